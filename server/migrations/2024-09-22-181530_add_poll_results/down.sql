@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS polls_open_idx;
+DROP TABLE IF EXISTS PollResults;
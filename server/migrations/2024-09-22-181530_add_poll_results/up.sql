@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS PollResults (
+    poll_id UUID PRIMARY KEY REFERENCES Polls (id) ON DELETE CASCADE,
+    result JSONB NOT NULL,
+    finalized_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS polls_open_idx ON Polls (close_after_time) WHERE closed_at IS NULL;
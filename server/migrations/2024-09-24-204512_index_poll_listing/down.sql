@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS ballots_user_idx;
+DROP INDEX IF EXISTS polls_title_trgm_idx;
+DROP INDEX IF EXISTS polls_owner_created_idx;
+DROP INDEX IF EXISTS polls_created_idx;
@@ -0,0 +1,8 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- newest first, with the ID as a tiebreaker for stable cursor pagination
+CREATE INDEX IF NOT EXISTS polls_created_idx ON Polls (created_at DESC, id DESC);
+CREATE INDEX IF NOT EXISTS polls_owner_created_idx ON Polls (owner_id, created_at DESC, id DESC);
+CREATE INDEX IF NOT EXISTS polls_title_trgm_idx ON Polls USING GIN (title gin_trgm_ops);
+
+CREATE INDEX IF NOT EXISTS ballots_user_idx ON Ballots (user_id);
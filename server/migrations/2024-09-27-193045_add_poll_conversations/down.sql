@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS polls_unannounced_idx;
+
+ALTER TABLE Polls
+    DROP COLUMN IF EXISTS conversation_reference,
+    DROP COLUMN IF EXISTS announced_at,
+    DROP COLUMN IF EXISTS announcement_claimed_until;
@@ -0,0 +1,8 @@
+ALTER TABLE Polls
+    ADD COLUMN IF NOT EXISTS conversation_reference JSONB,
+    ADD COLUMN IF NOT EXISTS announced_at TIMESTAMP,
+    -- until when a server instance holds the poll's result announcement, so that only one posts it
+    ADD COLUMN IF NOT EXISTS announcement_claimed_until TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS polls_unannounced_idx ON Polls (closed_at)
+    WHERE conversation_reference IS NOT NULL AND announced_at IS NULL;
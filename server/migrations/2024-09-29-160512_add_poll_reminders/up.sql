@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS PollReminders (
+    poll_id UUID NOT NULL REFERENCES Polls (id) ON DELETE CASCADE,
+    hours_before INTEGER NOT NULL,
+    sent_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (poll_id, hours_before)
+);
@@ -0,0 +1,34 @@
+-- a webhook either follows a single poll, or every poll created in a Teams tenant
+CREATE TABLE IF NOT EXISTS Webhooks (
+    id UUID PRIMARY KEY DEFAULT GEN_RANDOM_UUID(),
+    owner_id UUID NOT NULL REFERENCES Users (id) ON DELETE CASCADE,
+    poll_id UUID REFERENCES Polls (id) ON DELETE CASCADE,
+    tenant_id VARCHAR(100),
+    url VARCHAR(2000) NOT NULL,
+    secret VARCHAR(100) NOT NULL,
+    events TEXT[] NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK ((poll_id IS NULL) <> (tenant_id IS NULL))
+);
+
+CREATE INDEX IF NOT EXISTS webhooks_poll_idx ON Webhooks (poll_id) WHERE poll_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS webhooks_tenant_idx ON Webhooks (tenant_id) WHERE tenant_id IS NOT NULL;
+
+-- every event sent to a webhook, pending until it is delivered or retries run out
+CREATE TABLE IF NOT EXISTS WebhookDeliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id UUID NOT NULL REFERENCES Webhooks (id) ON DELETE CASCADE,
+    event VARCHAR(50) NOT NULL,
+    payload JSONB NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_status INTEGER,
+    -- only the kind of failure, since webhook owners can read it
+    last_error TEXT,
+    delivered_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS webhookdeliveries_pending_idx ON WebhookDeliveries (next_attempt_at)
+    WHERE next_attempt_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS webhookdeliveries_webhook_idx ON WebhookDeliveries (webhook_id, created_at DESC);
@@ -0,0 +1,2 @@
+ALTER TABLE Polls
+    DROP COLUMN IF EXISTS seed_commitment;
@@ -0,0 +1,8 @@
+-- the commitment published for the poll's seed, stored so a reopened poll can commit to a new seed
+ALTER TABLE Polls
+    ADD COLUMN IF NOT EXISTS seed_commitment VARCHAR(64);
+
+UPDATE Polls SET seed_commitment = encode(sha256(rng_seed), 'hex') WHERE seed_commitment IS NULL;
+
+ALTER TABLE Polls
+    ALTER COLUMN seed_commitment SET NOT NULL;
@@ -0,0 +1,6 @@
+ALTER TABLE PollResults
+    DROP COLUMN IF EXISTS merkle_root;
+
+ALTER TABLE Ballots
+    DROP COLUMN IF EXISTS nonce,
+    DROP COLUMN IF EXISTS receipt;
@@ -0,0 +1,7 @@
+-- ballots cast before receipts existed have none, and are left out of the poll's Merkle tree
+ALTER TABLE Ballots
+    ADD COLUMN IF NOT EXISTS nonce BYTEA,
+    ADD COLUMN IF NOT EXISTS receipt VARCHAR(64);
+
+ALTER TABLE PollResults
+    ADD COLUMN IF NOT EXISTS merkle_root VARCHAR(64);
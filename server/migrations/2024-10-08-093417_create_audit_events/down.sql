@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS AuditEvents;
+DROP FUNCTION IF EXISTS auditevents_append_only();
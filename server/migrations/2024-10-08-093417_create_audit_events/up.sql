@@ -0,0 +1,22 @@
+-- events outlive the polls they are about, so that deleting a poll doesn't erase its history
+CREATE TABLE IF NOT EXISTS AuditEvents (
+    id BIGSERIAL PRIMARY KEY,
+    poll_id UUID NOT NULL,
+    -- who made the change, or null for the server itself, like when a poll closes on time
+    actor_id UUID,
+    action VARCHAR(50) NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS auditevents_poll_idx ON AuditEvents (poll_id, id);
+
+CREATE OR REPLACE FUNCTION auditevents_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit events cannot be changed or deleted';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS auditevents_append_only ON AuditEvents;
+CREATE TRIGGER auditevents_append_only BEFORE UPDATE OR DELETE ON AuditEvents
+    FOR EACH ROW EXECUTE FUNCTION auditevents_append_only();
@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS BallotRevisions;
+
+ALTER TABLE Polls
+    DROP COLUMN IF EXISTS ballot_changes_allowed,
+    DROP COLUMN IF EXISTS ballot_withdrawals_allowed;
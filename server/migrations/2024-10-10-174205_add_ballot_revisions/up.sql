@@ -0,0 +1,23 @@
+-- polls that need ballots to be final once cast allow neither
+ALTER TABLE Polls
+    ADD COLUMN IF NOT EXISTS ballot_changes_allowed BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN IF NOT EXISTS ballot_withdrawals_allowed BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- every ranking a voter has given, oldest first, which outlives the voter's ballot if it is withdrawn
+CREATE TABLE IF NOT EXISTS BallotRevisions (
+    poll_id UUID NOT NULL REFERENCES Polls (id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES Users (id) ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    -- option ids in order of preference, or null when the ballot was withdrawn
+    ranked_preferences INTEGER[],
+    receipt VARCHAR(64),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (poll_id, user_id, revision)
+);
+
+-- the ballots cast so far are their own first revision, since what they replaced is gone
+INSERT INTO BallotRevisions (poll_id, user_id, revision, ranked_preferences, receipt, created_at)
+    SELECT b.poll_id, b.user_id, 1, array_agg(v.option ORDER BY v.preference), b.receipt, b.created_at
+    FROM Ballots b JOIN Votes v ON v.ballot_id = b.id
+    GROUP BY b.id
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,2 @@
+ALTER TABLE Ballots
+    DROP COLUMN IF EXISTS revision;
@@ -0,0 +1,7 @@
+-- the ballot's latest revision, which clients send back to replace or withdraw exactly what they saw
+ALTER TABLE Ballots
+    ADD COLUMN IF NOT EXISTS revision INTEGER NOT NULL DEFAULT 1;
+
+UPDATE Ballots b SET revision = r.revision
+    FROM (SELECT poll_id, user_id, max(revision) AS revision FROM BallotRevisions GROUP BY poll_id, user_id) r
+    WHERE r.poll_id = b.poll_id AND r.user_id = b.user_id;
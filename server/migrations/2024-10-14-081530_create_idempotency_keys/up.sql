@@ -0,0 +1,17 @@
+-- responses kept so clients can retry requests without repeating them, for a limited time
+CREATE TABLE IF NOT EXISTS IdempotencyKeys (
+    user_id UUID NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    -- the method and path the key was first used with, and the hash of that request's body
+    request VARCHAR(200) NOT NULL,
+    request_hash VARCHAR(64) NOT NULL,
+    -- the response, which is null while the first request is still being handled
+    status INTEGER,
+    body JSONB,
+    headers JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_id, key)
+);
+
+CREATE INDEX IF NOT EXISTS idempotencykeys_expiry_idx ON IdempotencyKeys (expires_at);
@@ -0,0 +1,124 @@
+//! Maintenance of the service's database, from a shell where `DATABASE_URL` is set
+
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use uuid::Uuid;
+
+use server::web::admin::{self, AdminResult, PollState};
+
+#[derive(Parser)]
+#[command(name = "admin")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the migrations the database hasn't had yet
+    Migrate,
+    /// List the newest polls
+    Polls {
+        #[arg(long, value_enum, default_value = "all")]
+        state: State,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Close a poll now and store its result
+    Close { poll_id: Uuid },
+    /// Open a closed poll again, dropping its result
+    Reopen { poll_id: Uuid },
+    /// Delete polls closed more than some days ago, and users left with nothing
+    Purge {
+        #[arg(long)]
+        older_than_days: u32,
+        /// List the polls that would be deleted, without deleting them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Count a closed poll again and replace its stored result
+    Recount { poll_id: Uuid },
+    /// Replace a user's name everywhere it is stored
+    Anonymize { user_id: Uuid },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum State {
+    Open,
+    Closed,
+    All,
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse().command) {
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        },
+        Ok(()) => ExitCode::SUCCESS,
+    }
+}
+
+fn run(command: Command) -> AdminResult<()> {
+    match command {
+        Command::Migrate => {
+            let versions = admin::migrate()?;
+            if versions.is_empty() {
+                println!("No pending migrations");
+            }
+            for version in versions {
+                println!("Ran migration {version}");
+            }
+        },
+        Command::Polls { state, limit } => {
+            let state = match state {
+                State::Open => PollState::Open,
+                State::Closed => PollState::Closed,
+                State::All => PollState::All,
+            };
+            for poll in admin::list_polls(state, limit)? {
+                let closed_at = poll.closed_at.map_or_else(|| String::from("open"), |t| format!("closed {t}"));
+                println!(
+                    "{}\t{}\tcreated {}\t{closed_at}\t{} ballots\towner {}",
+                    poll.id, poll.title, poll.created_at, poll.ballot_count, poll.owner_id,
+                );
+            }
+        },
+        Command::Close { poll_id } => {
+            if admin::close(&poll_id)? {
+                println!("Closed poll {poll_id}");
+            }
+            else {
+                println!("Poll {poll_id} was closed already");
+            }
+        },
+        Command::Reopen { poll_id } => {
+            admin::reopen(&poll_id)?;
+            println!("Reopened poll {poll_id}");
+        },
+        Command::Purge { older_than_days, dry_run } => {
+            let (poll_ids, user_count) = admin::purge(older_than_days, dry_run)?;
+            for poll_id in poll_ids.iter() {
+                println!("{poll_id}");
+            }
+            if dry_run {
+                println!("Would delete {} polls", poll_ids.len());
+            }
+            else {
+                println!("Deleted {} polls and {user_count} users", poll_ids.len());
+            }
+        },
+        Command::Recount { poll_id } => {
+            admin::recount(&poll_id)?;
+            println!("Recounted poll {poll_id}");
+        },
+        Command::Anonymize { user_id } => {
+            if !admin::anonymize(&user_id)? {
+                return Err(format!("user {user_id} not found").into());
+            }
+            println!("Anonymized user {user_id}");
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Count an election from a ballot file just like the server does, without the server or a
+//! database, to recount and cross-check results.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Parser, ValueEnum};
+use sha2::{Digest, Sha256};
+
+use server::voting::{self, PollResult, ResultReport, TieBreak};
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Blt,
+    Csv,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TieBreakRule {
+    Popularity,
+    Backwards,
+    Random,
+}
+
+impl From<TieBreakRule> for TieBreak {
+    fn from(rule: TieBreakRule) -> Self {
+        match rule {
+            TieBreakRule::Popularity => TieBreak::Popularity,
+            TieBreakRule::Backwards => TieBreak::Backwards,
+            TieBreakRule::Random => TieBreak::Random,
+        }
+    }
+}
+
+/// Count the ballots of an election with single transferable vote
+#[derive(Parser)]
+#[command(name = "stv")]
+struct Args {
+    /// A BLT file, a ranked CSV file with the candidates as its header, or JSON with the title,
+    /// options, winner_count and ballots as lists of option numbers
+    file: PathBuf,
+
+    /// The format of the file, by its extension if left out
+    #[arg(short, long)]
+    format: Option<Format>,
+
+    /// The number of winners, replacing the one in the file. CSV files elect one otherwise.
+    #[arg(short, long)]
+    seats: Option<i32>,
+
+    /// The seed ballots are shuffled with: 64 hex digits, or any text to be hashed into a seed
+    #[arg(long)]
+    seed: Option<String>,
+
+    /// How to choose who to eliminate when several options have the fewest votes
+    #[arg(long, value_enum, default_value = "popularity")]
+    tie_break: TieBreakRule,
+
+    /// How to print the result
+    #[arg(short, long, value_enum, default_value = "text")]
+    output: Output,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let text = match fs::read_to_string(&args.file) {
+        Err(err) => {
+            eprintln!("Can't read {}: {err}", args.file.display());
+            return ExitCode::FAILURE;
+        },
+        Ok(t) => t,
+    };
+
+    let format = args.format.or_else(|| {
+        match args.file.extension().and_then(|e| e.to_str()).map(str::to_lowercase).as_deref() {
+            Some("blt") => Some(Format::Blt),
+            Some("csv") => Some(Format::Csv),
+            Some("json") => Some(Format::Json),
+            _ => None,
+        }
+    });
+    let parsed = match format {
+        None => {
+            eprintln!("Can't tell the format of {}, pass --format", args.file.display());
+            return ExitCode::FAILURE;
+        },
+        Some(Format::Blt) => voting::parse_blt(&text),
+        Some(Format::Csv) => voting::parse_ranked_csv(&text),
+        Some(Format::Json) => voting::parse_json_import(&text),
+    };
+
+    let validated = parsed.and_then(|mut import| {
+        if let Some(seats) = args.seats {
+            import.settings.winner_count = seats;
+        }
+        import.validate()
+    });
+    let (poll, ballots) = match validated {
+        Err(errors) => {
+            for err in errors.iter() {
+                eprintln!("{}", serde_json::to_string(err).unwrap_or_else(|_| err.to_string()));
+            }
+            return ExitCode::FAILURE;
+        },
+        Ok(p) => p,
+    };
+
+    let seed = args.seed.as_deref().map(parse_seed).unwrap_or_default();
+
+    // count as many rounds as the server does, enough to eliminate all but the winners
+    let max_rounds = (poll.option_ids.len() as u32).saturating_sub(poll.winner_count as u32);
+    let result = PollResult::evaluate_with(&poll, &ballots, max_rounds, &seed, args.tie_break.into());
+    let report = ResultReport::new(&poll, &result, ballots.len());
+
+    match args.output {
+        Output::Text => print!("{}", to_text(&report)),
+        Output::Json => println!("{}", serde_json::to_string_pretty(&report).expect("Reports are always serializable")),
+        Output::Csv => print!("{}", report.to_csv()),
+    }
+    ExitCode::SUCCESS
+}
+
+fn parse_seed(seed: &str) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    if hex::decode_to_slice(seed, &mut bytes).is_err() {
+        bytes.copy_from_slice(&Sha256::digest(seed.as_bytes()));
+    }
+    bytes
+}
+
+/// The count of every round as a table, then the winners
+fn to_text(report: &ResultReport) -> String {
+    let width = report.options.iter().map(|o| o.description.chars().count()).max().unwrap_or(0).max(11);
+
+    let mut text = format!(
+        "{}\n{} ballots, {} seats, {} votes to win\n",
+        report.title, report.ballot_count, report.winner_count, report.threshold,
+    );
+    if report.rounds.is_empty() {
+        text.push_str("\nNot enough ballots to elect anyone\n");
+    }
+    for round in report.rounds.iter() {
+        text.push_str(&format!("\nRound {}\n", round.round));
+        for count in round.counts.iter() {
+            let status = match (round.elected.iter().any(|o| o.id == count.id), &round.eliminated) {
+                (true, _) => "elected",
+                (_, Some(o)) if o.id == count.id => "eliminated",
+                _ => "",
+            };
+            if count.status == voting::OptionStatus::Eliminated && status.is_empty() {
+                continue;
+            }
+            let line = format!("  {:width$}  {:>6}  {status}", count.description, count.votes);
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text.push_str(&format!("  {:width$}  {:>6}\n", "(exhausted)", round.exhausted));
+    }
+
+    if report.winners.is_empty() {
+        text.push_str("\nElected: no one\n");
+    }
+    else {
+        text.push_str("\nElected:\n");
+        for winner in report.winners.iter() {
+            text.push_str(&format!("  {}\n", winner.description));
+        }
+    }
+    text
+}
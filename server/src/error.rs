@@ -1,15 +1,226 @@
+use std::convert::Infallible;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
 use std::ops::RangeInclusive;
+use std::slice;
 use std::convert::From;
 
 use chrono::{DateTime, Utc};
 use diesel::result::{DatabaseErrorKind, Error as DbError};
+use serde::{Serialize, Serializer};
 use uuid::Uuid;
+use warp::filters::body::BodyDeserializeError;
+use warp::http::header::{self, HeaderValue};
 use warp::http::StatusCode;
+use warp::reject::{self, Rejection};
 use warp::reply::{self, Reply};
 
-#[derive(Debug)]
+/// Every error the API can report. Serializes as a stable machine-readable `code` and the `details`
+/// a client needs to build its own (localized) message.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code", content = "details")]
+pub enum ErrorKind {
+    #[serde(rename = "poll.title_invalid_size")]
+    PollTitleInvalidSize { min: usize, max: usize, actual: usize },
+    #[serde(rename = "poll.option_limit_exceeded")]
+    PollOptionLimitExceeded { min: usize, max: usize, actual: usize },
+    #[serde(rename = "poll.option_empty")]
+    PollOptionEmpty,
+    #[serde(rename = "poll.option_invalid_size")]
+    PollOptionInvalidSize { min: usize, max: usize, actual: usize },
+    #[serde(rename = "poll.option_duplicate")]
+    PollOptionDuplicate { option_indices: (usize, usize) },
+    #[serde(rename = "poll.winners_limit_exceeded")]
+    PollWinnersLimitExceeded { min: i32, max: i32, actual: i32 },
+    #[serde(rename = "poll.duration_invalid")]
+    PollDurationInvalid { min_minutes: i32, ends: DateTime<Utc> },
+    #[serde(rename = "poll.votes_limit_exceeded")]
+    PollVotesLimitExceeded { min: i64, max: i64, actual: i64 },
+    #[serde(rename = "poll.no_changes")]
+    PollNoChanges,
+    #[serde(rename = "poll.not_closed")]
+    PollNotClosed,
+    #[serde(rename = "poll.closed")]
+    PollClosed,
+    #[serde(rename = "poll.ballot_policy_locked")]
+    PollBallotPolicyLocked,
+
+    #[serde(rename = "list.limit_invalid")]
+    ListLimitInvalid { min: i64, max: i64, actual: i64 },
+    #[serde(rename = "list.cursor_invalid")]
+    ListCursorInvalid,
+
+    #[serde(rename = "ballot.empty")]
+    BallotEmpty,
+    #[serde(rename = "ballot.too_long")]
+    BallotTooLong { max: usize, actual: usize },
+    #[serde(rename = "ballot.incomplete_selection")]
+    BallotIncompleteSelection { missing_index: usize },
+    #[serde(rename = "ballot.invalid_selection")]
+    BallotInvalidSelection { preference_index: usize, option_id: u32 },
+    #[serde(rename = "ballot.duplicate_selection")]
+    BallotDuplicateSelection { option_id: u32, preference_indices: (usize, usize) },
+    #[serde(rename = "ballot.changes_not_allowed")]
+    BallotChangesNotAllowed,
+    #[serde(rename = "ballot.withdrawals_not_allowed")]
+    BallotWithdrawalsNotAllowed,
+    #[serde(rename = "ballot.revision_mismatch")]
+    BallotRevisionMismatch { expected: i32, actual: i32 },
+
+    #[serde(rename = "import.malformed")]
+    ImportMalformed { line: usize, reason: String },
+    #[serde(rename = "import.too_many_ballots")]
+    ImportTooManyBallots { max: usize },
+
+    #[serde(rename = "idempotency.key_reused")]
+    IdempotencyKeyReused,
+
+    #[serde(rename = "webhook.url_invalid")]
+    WebhookUrlInvalid,
+    #[serde(rename = "webhook.url_forbidden")]
+    WebhookUrlForbidden,
+
+    #[serde(rename = "resource.not_found")]
+    NotFound { resource: &'static str },
+    #[serde(rename = "resource.forbidden")]
+    Forbidden { resource: &'static str },
+    #[serde(rename = "resource.already_exists")]
+    AlreadyExists { resource: &'static str },
+    #[serde(rename = "resource.missing_fields")]
+    MissingFields { resource: &'static str },
+    #[serde(rename = "resource.bad_reference")]
+    BadReference { resource: &'static str },
+
+    #[serde(rename = "request.validation_failed")]
+    ValidationFailed { errors: Vec<ValidationError> },
+    #[serde(rename = "request.route_not_found")]
+    RouteNotFound,
+    #[serde(rename = "request.method_not_allowed")]
+    MethodNotAllowed,
+    #[serde(rename = "request.missing_header")]
+    MissingHeader { header: String },
+    #[serde(rename = "request.invalid_header")]
+    InvalidHeader { header: String },
+    #[serde(rename = "request.invalid_query")]
+    InvalidQuery,
+    #[serde(rename = "request.malformed_body")]
+    MalformedBody { reason: String },
+    #[serde(rename = "request.unsupported_media_type")]
+    UnsupportedMediaType,
+    #[serde(rename = "request.length_required")]
+    LengthRequired,
+    #[serde(rename = "request.payload_too_large")]
+    PayloadTooLarge,
+    #[serde(rename = "request.unauthorized")]
+    Unauthorized,
+    #[serde(rename = "request.rate_limited")]
+    RateLimited { retry_after: u64 },
+
+    #[serde(rename = "server.internal_error")]
+    Internal,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::PollTitleInvalidSize { min, max, actual } =>
+                write!(f, "poll's title must be between {min} and {max} characters, got {actual}"),
+            ErrorKind::PollOptionLimitExceeded { min, max, actual } =>
+                write!(f, "poll must have between {min} and {max} options, got {actual}"),
+            ErrorKind::PollOptionEmpty =>
+                write!(f, "poll option is empty"),
+            ErrorKind::PollOptionInvalidSize { min, max, actual } =>
+                write!(f, "poll option must be between {min} and {max} characters, got {actual}"),
+            ErrorKind::PollOptionDuplicate { option_indices } =>
+                write!(f, "poll options at indices {option_indices:?} are the same"),
+            ErrorKind::PollWinnersLimitExceeded { min, max, actual } =>
+                write!(f, "poll must have between {min} and {max} winners, got {actual}"),
+            ErrorKind::PollDurationInvalid { min_minutes, ends } =>
+                write!(f, "poll cannot end less than {min_minutes} minutes from now, ends {ends}"),
+            ErrorKind::PollVotesLimitExceeded { min, max, actual } =>
+                write!(f, "poll cannot end without between {min} and {max} votes, set to end after {actual}"),
+            ErrorKind::PollNoChanges =>
+                write!(f, "poll cannot be updated without new values"),
+            ErrorKind::PollNotClosed =>
+                write!(f, "poll is still open"),
+            ErrorKind::PollClosed =>
+                write!(f, "poll is closed"),
+            ErrorKind::PollBallotPolicyLocked =>
+                write!(f, "whether ballots can be changed or withdrawn cannot change once ballots are cast"),
+            ErrorKind::ListLimitInvalid { min, max, actual } =>
+                write!(f, "list limit must be between {min} and {max}, got {actual}"),
+            ErrorKind::ListCursorInvalid =>
+                write!(f, "list cursor is not valid"),
+            ErrorKind::BallotEmpty =>
+                write!(f, "ballot is empty"),
+            ErrorKind::BallotTooLong { max, actual } =>
+                write!(f, "ballot cannot rank more than {max} options, got {actual}"),
+            ErrorKind::BallotIncompleteSelection { missing_index } =>
+                write!(f, "ballot preferences have gap at index {missing_index}"),
+            ErrorKind::BallotInvalidSelection { preference_index, option_id } =>
+                write!(f, "ballot preference {preference_index} is for invalid poll option {option_id}"),
+            ErrorKind::BallotDuplicateSelection { option_id, preference_indices } =>
+                write!(f, "ballot poll option {option_id} has multiple votes at indices {preference_indices:?}"),
+            ErrorKind::BallotChangesNotAllowed =>
+                write!(f, "ballot cannot be changed once cast in this poll"),
+            ErrorKind::BallotWithdrawalsNotAllowed =>
+                write!(f, "ballot cannot be withdrawn once cast in this poll"),
+            ErrorKind::BallotRevisionMismatch { expected, actual } =>
+                write!(f, "ballot is at revision {actual}, not {expected}"),
+            ErrorKind::ImportMalformed { line, reason } =>
+                write!(f, "import is malformed at line {line}: {reason}"),
+            ErrorKind::ImportTooManyBallots { max } =>
+                write!(f, "import cannot have more than {max} ballots"),
+            ErrorKind::IdempotencyKeyReused =>
+                write!(f, "idempotency key was already used for a different request"),
+            ErrorKind::WebhookUrlInvalid =>
+                write!(f, "webhook URL must be an absolute https URL of at most 2000 characters"),
+            ErrorKind::WebhookUrlForbidden =>
+                write!(f, "webhook URL must only resolve to public addresses"),
+            ErrorKind::NotFound { resource } =>
+                write!(f, "{resource} not found"),
+            ErrorKind::Forbidden { resource } =>
+                write!(f, "{resource} cannot be modified by this user"),
+            ErrorKind::AlreadyExists { resource } =>
+                write!(f, "{resource} with that ID already exists"),
+            ErrorKind::MissingFields { resource } =>
+                write!(f, "{resource} is missing fields"),
+            ErrorKind::BadReference { resource } =>
+                write!(f, "{resource} has bad references"),
+            ErrorKind::ValidationFailed { errors } if errors.len() == 1 =>
+                write!(f, "request has 1 validation error"),
+            ErrorKind::ValidationFailed { errors } =>
+                write!(f, "request has {} validation errors", errors.len()),
+            ErrorKind::RouteNotFound =>
+                write!(f, "no API route matches the request"),
+            ErrorKind::MethodNotAllowed =>
+                write!(f, "method not allowed on this route"),
+            ErrorKind::MissingHeader { header } =>
+                write!(f, "missing request header '{header}'"),
+            ErrorKind::InvalidHeader { header } =>
+                write!(f, "invalid request header '{header}'"),
+            ErrorKind::InvalidQuery =>
+                write!(f, "invalid query string"),
+            ErrorKind::MalformedBody { reason } =>
+                write!(f, "malformed request body: {reason}"),
+            ErrorKind::UnsupportedMediaType =>
+                write!(f, "request body must be JSON"),
+            ErrorKind::LengthRequired =>
+                write!(f, "request must have a content length"),
+            ErrorKind::PayloadTooLarge =>
+                write!(f, "request body is too large"),
+            ErrorKind::Unauthorized =>
+                write!(f, "request is not authorized"),
+            ErrorKind::RateLimited { retry_after } =>
+                write!(f, "too many requests, retry after {retry_after} seconds"),
+            ErrorKind::Internal =>
+                write!(f, "internal server error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
 pub enum ContextId {
     Uuid(Uuid),
     I32(i32),
@@ -23,184 +234,501 @@ impl Display for ContextId {
     }
 }
 
-#[derive(Debug)]
-struct Context(&'static str, ContextId);
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Context {
+    #[serde(rename = "type")]
+    obj_type: &'static str,
+    id: ContextId,
+}
 impl Context {
     pub fn new(obj_type: &'static str, id: ContextId) -> Context {
-        Context(obj_type, id)
+        Context { obj_type, id }
     }
 }
 impl Display for Context {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.0, self.1)
+        write!(f, "{} {}", self.obj_type, self.id)
     }
 }
 
-#[derive(Debug)]
-pub struct ValidationError {
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: &'a Option<String>,
+    #[serde(flatten)]
+    kind: &'a ErrorKind,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: &'a Option<Context>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    kind: ErrorKind,
+    field: Option<String>,
     context: Option<Context>,
 }
 
 impl ValidationError {
+    fn new(kind: ErrorKind) -> Self {
+        Self { kind, field: None, context: None }
+    }
+
+    /// Attribute this error to a field of the request, e.g. `title` or `options[2]`
+    pub fn at(mut self, field: impl Into<String>) -> Self {
+        self.field = Some(field.into());
+        self
+    }
+
     pub fn with_context(mut self, obj_type: &'static str, id: ContextId) -> Self {
         self.context = Some(Context::new(obj_type, id));
         self
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
 }
 
 impl Display for ValidationError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Validation error")?;
         if let Some(context) = &self.context {
-            write!(f, "Validation error for {}: {}", context, self.message)
+            write!(f, " for {}", context)?;
         }
-        else {
-            write!(f, "Validation error: {}", self.message)
+        if let Some(field) = &self.field {
+            write!(f, " at {}", field)?;
         }
+        write!(f, ": {}", self.kind)
     }
 }
 
 impl Error for ValidationError {}
 
-pub fn poll_title_invalid_size(limits: RangeInclusive<usize>, len: usize) -> ValidationError {
-    ValidationError {
-        message: format!("poll's title must be between {} and {}, got {len}", limits.start(), limits.end()),
-        context: None,
+impl Serialize for ValidationError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ErrorBody {
+            field: &self.field,
+            kind: &self.kind,
+            message: self.kind.to_string(),
+            context: &self.context,
+        }.serialize(serializer)
     }
 }
 
-pub fn poll_option_limit_exceeded(limits: RangeInclusive<usize>, count: usize) -> ValidationError {
-    ValidationError {
-        message: format!("poll must have between {} and {} options, got {count}", limits.start(), limits.end()),
-        context: None,
+/// Every rule a request failed, so they can all be reported at once
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ValidationErrors(Vec<ValidationError>);
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self(vec![])
+    }
+
+    pub fn push(&mut self, err: ValidationError) {
+        self.0.push(err);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, ValidationError> {
+        self.0.iter()
+    }
+
+    pub fn with_context(self, obj_type: &'static str, id: ContextId) -> Self {
+        Self(self.0.into_iter().map(|err| err.with_context(obj_type, id.clone())).collect())
+    }
+
+    /// `Ok(value)` if no errors have been recorded, otherwise all of the errors
+    pub fn into_result<T>(self, value: T) -> Result<T, Self> {
+        if self.is_empty() {
+            Ok(value)
+        }
+        else {
+            Err(self)
+        }
     }
 }
 
-pub fn poll_winners_limit_exceeded(limits: RangeInclusive<i32>, count: i32) -> ValidationError {
-    ValidationError {
-        message: format!("poll must have between {} and {} winners, got {count}", limits.start(), limits.end()),
-        context: None,
+impl From<ValidationError> for ValidationErrors {
+    fn from(value: ValidationError) -> Self {
+        Self(vec![value])
     }
 }
 
-pub fn poll_duration_invalid(min_minutes: i32, ends: &DateTime<Utc>) -> ValidationError {
-    ValidationError {
-        message: format!("poll cannot end less than {min_minutes} minutes from now, ends {ends}"),
-        context: None,
+impl Display for ValidationErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, err) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{err}")?;
+        }
+        Ok(())
     }
 }
 
+impl Error for ValidationErrors {}
+
+pub fn poll_title_invalid_size(limits: RangeInclusive<usize>, len: usize) -> ValidationError {
+    ValidationError::new(ErrorKind::PollTitleInvalidSize { min: *limits.start(), max: *limits.end(), actual: len })
+}
+
+pub fn poll_option_limit_exceeded(limits: RangeInclusive<usize>, count: usize) -> ValidationError {
+    ValidationError::new(ErrorKind::PollOptionLimitExceeded { min: *limits.start(), max: *limits.end(), actual: count })
+}
+
+pub fn poll_option_empty() -> ValidationError {
+    ValidationError::new(ErrorKind::PollOptionEmpty)
+}
+
+pub fn poll_option_invalid_size(limits: RangeInclusive<usize>, len: usize) -> ValidationError {
+    ValidationError::new(ErrorKind::PollOptionInvalidSize { min: *limits.start(), max: *limits.end(), actual: len })
+}
+
+pub fn poll_option_duplicate(option_indices: (usize, usize)) -> ValidationError {
+    ValidationError::new(ErrorKind::PollOptionDuplicate { option_indices })
+}
+
+pub fn poll_winners_limit_exceeded(limits: RangeInclusive<i32>, count: i32) -> ValidationError {
+    ValidationError::new(ErrorKind::PollWinnersLimitExceeded { min: *limits.start(), max: *limits.end(), actual: count })
+}
+
+pub fn poll_duration_invalid(min_minutes: i32, ends: &DateTime<Utc>) -> ValidationError {
+    ValidationError::new(ErrorKind::PollDurationInvalid { min_minutes, ends: *ends })
+}
+
 pub fn poll_votes_limit_exceeded(limits: RangeInclusive<i64>, count: i64) -> ValidationError {
-    ValidationError {
-        message: format!("poll cannot end without between {} and {} votes, set to end after {count}",
-            limits.start(), limits.end()
-        ),
-        context: None,
-    }
+    ValidationError::new(ErrorKind::PollVotesLimitExceeded { min: *limits.start(), max: *limits.end(), actual: count })
+}
+
+pub fn poll_no_changes() -> ValidationError {
+    ValidationError::new(ErrorKind::PollNoChanges)
+}
+
+pub fn list_limit_invalid(limits: RangeInclusive<i64>, limit: i64) -> ValidationError {
+    ValidationError::new(ErrorKind::ListLimitInvalid { min: *limits.start(), max: *limits.end(), actual: limit })
+}
+
+pub fn list_cursor_invalid() -> ValidationError {
+    ValidationError::new(ErrorKind::ListCursorInvalid)
 }
 
 pub fn ballot_empty() -> ValidationError {
-    ValidationError {
-        message: format!("ballot is empty"),
-        context: None,
-    }
+    ValidationError::new(ErrorKind::BallotEmpty)
+}
+
+pub fn ballot_too_long(max: usize, len: usize) -> ValidationError {
+    ValidationError::new(ErrorKind::BallotTooLong { max, actual: len })
 }
 
 pub fn ballot_incomplete_selection(missing_index: usize) -> ValidationError {
-    ValidationError {
-        message: format!("ballot preferences have gap at index {missing_index}"),
-        context: None,
-    }
+    ValidationError::new(ErrorKind::BallotIncompleteSelection { missing_index })
 }
 
 pub fn ballot_invalid_selection(preference_index: usize, option_id: u32) -> ValidationError {
-    ValidationError {
-        message: format!("ballot preference {preference_index} is for invalid poll option {option_id}"),
-        context: None,
-    }
+    ValidationError::new(ErrorKind::BallotInvalidSelection { preference_index, option_id })
 }
 
 pub fn ballot_duplicate_selection(option_id: u32, pref_indices: (usize, usize)) -> ValidationError {
-    ValidationError {
-        message: format!("ballot poll option {option_id} has multiple votes at indices {pref_indices:?}"),
-        context: None,
-    }
+    ValidationError::new(ErrorKind::BallotDuplicateSelection { option_id, preference_indices: pref_indices })
 }
 
+pub fn import_malformed(line: usize, reason: impl Display) -> ValidationError {
+    ValidationError::new(ErrorKind::ImportMalformed { line, reason: reason.to_string() })
+}
 
+pub fn import_too_many_ballots(max: usize) -> ValidationError {
+    ValidationError::new(ErrorKind::ImportTooManyBallots { max })
+}
+
+pub fn webhook_url_invalid() -> ValidationError {
+    ValidationError::new(ErrorKind::WebhookUrlInvalid)
+}
+
+pub fn webhook_url_forbidden() -> ValidationError {
+    ValidationError::new(ErrorKind::WebhookUrlForbidden)
+}
+
+
+/// An error that can be sent to the client as a response, serialized as JSON
 #[derive(Debug)]
-pub struct HttpGetError {
-    pub code: StatusCode,
-    message: String,
+pub struct HttpError {
+    pub status: StatusCode,
+    kind: ErrorKind,
+    context: Option<Context>,
     source: Option<DbError>,
 }
 
-impl HttpGetError {
-    pub fn into_response(self) -> reply::Response {
-        if self.code == StatusCode::NOT_FOUND {
-            reply::with_status(reply::reply(), self.code).into_response()
+impl HttpError {
+    fn new(status: StatusCode, kind: ErrorKind, source: Option<DbError>) -> Self {
+        Self { status, kind, context: None, source }
+    }
+
+    /// The message shown to the client, without any server-side details
+    pub fn message(&self) -> String {
+        self.kind.to_string()
+    }
+
+    pub fn to_response(&self) -> reply::Response {
+        // the details of server errors are only for the logs, never the client
+        if self.status.is_server_error() {
+            println!("{self}");
         }
-        else {
-            reply::with_status(self.to_string(), self.code).into_response()
+
+        let body = ErrorBody {
+            field: &None,
+            kind: &self.kind,
+            message: self.kind.to_string(),
+            context: &self.context,
+        };
+        let mut response = reply::with_status(reply::json(&body), self.status).into_response();
+        if let ErrorKind::RateLimited { retry_after } = self.kind {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
         }
+        response
+    }
+
+    pub fn into_response(self) -> reply::Response {
+        self.to_response()
     }
 }
 
-impl Display for HttpGetError {
+impl Display for HttpError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: {:?}", self.message, self.source)
+        write!(f, "{}", self.kind)?;
+        if let Some(context) = &self.context {
+            write!(f, " ({context})")?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, ": {source:?}")?;
+        }
+        Ok(())
     }
 }
 
-impl Error for HttpGetError { }
+impl Error for HttpError { }
+
+impl reject::Reject for HttpError { }
 
-impl From<ValidationError> for HttpGetError {
+impl From<ValidationErrors> for HttpError {
+    fn from(value: ValidationErrors) -> Self {
+        HttpError::new(StatusCode::UNPROCESSABLE_ENTITY, ErrorKind::ValidationFailed { errors: value.0 }, None)
+    }
+}
+
+impl From<ValidationError> for HttpError {
     fn from(value: ValidationError) -> Self {
-        HttpGetError {
-            message: value.to_string(),
-            code: StatusCode::BAD_REQUEST,
-            source: None,
-        }
+        HttpError::from(ValidationErrors::from(value))
     }
 }
 
-pub fn db_get(source: DbError, code: StatusCode, subject: &str, object: Option<&str>) -> HttpGetError {
-    let message = match object {
-        Some(object) => format!("Failed to retrieve {object} of {subject}"),
-        None => format!("Failed to retrieve {subject}"),
-    };
-    HttpGetError { message, code, source: Some(source) }
+pub fn not_found(resource: &'static str) -> HttpError {
+    HttpError::new(StatusCode::NOT_FOUND, ErrorKind::NotFound { resource }, None)
+}
+
+pub fn poll_not_closed() -> HttpError {
+    HttpError::new(StatusCode::CONFLICT, ErrorKind::PollNotClosed, None)
+}
+
+pub fn poll_closed() -> HttpError {
+    HttpError::new(StatusCode::CONFLICT, ErrorKind::PollClosed, None)
+}
+
+pub fn poll_ballot_policy_locked() -> HttpError {
+    HttpError::new(StatusCode::CONFLICT, ErrorKind::PollBallotPolicyLocked, None)
+}
+
+pub fn ballot_changes_not_allowed() -> HttpError {
+    HttpError::new(StatusCode::FORBIDDEN, ErrorKind::BallotChangesNotAllowed, None)
+}
+
+pub fn ballot_withdrawals_not_allowed() -> HttpError {
+    HttpError::new(StatusCode::FORBIDDEN, ErrorKind::BallotWithdrawalsNotAllowed, None)
+}
+
+pub fn ballot_revision_mismatch(expected: i32, actual: i32) -> HttpError {
+    HttpError::new(StatusCode::PRECONDITION_FAILED, ErrorKind::BallotRevisionMismatch { expected, actual }, None)
+}
+
+pub fn idempotency_key_reused() -> HttpError {
+    HttpError::new(StatusCode::UNPROCESSABLE_ENTITY, ErrorKind::IdempotencyKeyReused, None)
+}
+
+pub fn forbidden(resource: &'static str) -> HttpError {
+    HttpError::new(StatusCode::FORBIDDEN, ErrorKind::Forbidden { resource }, None)
+}
+
+pub fn rate_limited(retry_after: u64) -> HttpError {
+    HttpError::new(StatusCode::TOO_MANY_REQUESTS, ErrorKind::RateLimited { retry_after }, None)
+}
+
+/// The reason is only logged, so as not to help anyone forge credentials
+pub fn unauthorized(reason: impl Display) -> HttpError {
+    println!("Unauthorized request: {reason}");
+    HttpError::new(StatusCode::UNAUTHORIZED, ErrorKind::Unauthorized, None)
+}
+
+pub fn malformed_body(reason: impl Display) -> HttpError {
+    HttpError::new(StatusCode::BAD_REQUEST, ErrorKind::MalformedBody { reason: reason.to_string() }, None)
+}
+
+pub fn missing_header(header: &str) -> HttpError {
+    HttpError::new(StatusCode::BAD_REQUEST, ErrorKind::MissingHeader { header: header.to_string() }, None)
+}
+
+pub fn internal(source: DbError) -> HttpError {
+    HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorKind::Internal, Some(source))
 }
 
-pub fn db_insert(source: DbError, subject: &str) -> HttpGetError {
+/// Data read back from the database isn't valid, which is the server's fault rather than the
+/// client's. The reason is only logged.
+pub fn stored_data_invalid(reason: impl Display) -> HttpError {
+    println!("Invalid stored data: {reason}");
+    HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorKind::Internal, None)
+}
+
+pub fn db_get(source: DbError, subject: &'static str) -> HttpError {
     match source {
+        DbError::NotFound => HttpError::new(StatusCode::NOT_FOUND, ErrorKind::NotFound { resource: subject }, None),
+        _ => internal(source),
+    }
+}
+
+pub fn db_insert(source: DbError, subject: &'static str) -> HttpError {
+    let (status, kind) = match source {
         DbError::DatabaseError(DatabaseErrorKind::UniqueViolation, ..) => {
-            HttpGetError {
-                message: format!("{subject} with that ID already exists"),
-                code: StatusCode::CONFLICT,
-                source: Some(source),
-            }
+            (StatusCode::CONFLICT, ErrorKind::AlreadyExists { resource: subject })
         },
         DbError::DatabaseError(DatabaseErrorKind::NotNullViolation, ..) => {
-            HttpGetError {
-                message: format!("{subject} is missing fields"),
-                code: StatusCode::BAD_REQUEST,
-                source: Some(source),
-            }
+            (StatusCode::BAD_REQUEST, ErrorKind::MissingFields { resource: subject })
         },
         DbError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, ..) => {
-            HttpGetError {
-                message: format!("{subject} has bad references"),
-                code: StatusCode::BAD_REQUEST,
-                source: Some(source),
-            }
+            (StatusCode::BAD_REQUEST, ErrorKind::BadReference { resource: subject })
         }
         _ => {
-            HttpGetError {
-                message: format!("Error inserting {subject}"),
-                code: StatusCode::INTERNAL_SERVER_ERROR,
-                source: Some(source),
-            }
+            (StatusCode::INTERNAL_SERVER_ERROR, ErrorKind::Internal)
         }
+    };
+    HttpError::new(status, kind, Some(source))
+}
+
+/// Convert any request that was rejected before reaching a handler into a JSON error response
+pub async fn recover(rejection: Rejection) -> Result<reply::Response, Infallible> {
+    let err = if rejection.is_not_found() {
+        HttpError::new(StatusCode::NOT_FOUND, ErrorKind::RouteNotFound, None)
+    }
+    else if let Some(err) = rejection.find::<HttpError>() {
+        return Ok(err.to_response());
+    }
+    else if let Some(err) = rejection.find::<reject::MissingHeader>() {
+        HttpError::new(StatusCode::BAD_REQUEST, ErrorKind::MissingHeader { header: err.name().to_string() }, None)
+    }
+    else if let Some(err) = rejection.find::<reject::InvalidHeader>() {
+        HttpError::new(StatusCode::BAD_REQUEST, ErrorKind::InvalidHeader { header: err.name().to_string() }, None)
+    }
+    else if rejection.find::<reject::InvalidQuery>().is_some() {
+        HttpError::new(StatusCode::BAD_REQUEST, ErrorKind::InvalidQuery, None)
+    }
+    else if let Some(err) = rejection.find::<BodyDeserializeError>() {
+        let reason = err.source().map_or_else(|| err.to_string(), |e| e.to_string());
+        HttpError::new(StatusCode::BAD_REQUEST, ErrorKind::MalformedBody { reason }, None)
+    }
+    else if rejection.find::<reject::UnsupportedMediaType>().is_some() {
+        HttpError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, ErrorKind::UnsupportedMediaType, None)
+    }
+    else if rejection.find::<reject::LengthRequired>().is_some() {
+        HttpError::new(StatusCode::LENGTH_REQUIRED, ErrorKind::LengthRequired, None)
+    }
+    else if rejection.find::<reject::PayloadTooLarge>().is_some() {
+        HttpError::new(StatusCode::PAYLOAD_TOO_LARGE, ErrorKind::PayloadTooLarge, None)
+    }
+    else if rejection.find::<reject::MethodNotAllowed>().is_some() {
+        HttpError::new(StatusCode::METHOD_NOT_ALLOWED, ErrorKind::MethodNotAllowed, None)
+    }
+    else {
+        println!("Unhandled rejection: {rejection:?}");
+        HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorKind::Internal, None)
+    };
+
+    Ok(err.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn body(err: &HttpError) -> serde_json::Value {
+        serde_json::to_value(ErrorBody {
+            field: &None,
+            kind: &err.kind,
+            message: err.kind.to_string(),
+            context: &err.context,
+        }).unwrap()
+    }
+
+    #[test]
+    fn validation_error_code_and_details() {
+        let err = HttpError::from(ballot_duplicate_selection(2, (0, 3)).at("ranked_preferences[3]"));
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        assert_eq!(body(&err), json!({
+            "code": "request.validation_failed",
+            "message": "request has 1 validation error",
+            "details": { "errors": [{
+                "field": "ranked_preferences[3]",
+                "code": "ballot.duplicate_selection",
+                "message": "ballot poll option 2 has multiple votes at indices (0, 3)",
+                "details": { "option_id": 2, "preference_indices": [0, 3] },
+            }] },
+        }));
+    }
+
+    #[test]
+    fn unit_error_has_no_details() {
+        let mut errors = ValidationErrors::new();
+        errors.push(ballot_empty());
+        errors.push(poll_no_changes());
+        let err = HttpError::from(errors.with_context("ballot", ContextId::I32(7)));
+
+        assert_eq!(body(&err), json!({
+            "code": "request.validation_failed",
+            "message": "request has 2 validation errors",
+            "details": { "errors": [
+                {
+                    "code": "ballot.empty",
+                    "message": "ballot is empty",
+                    "context": { "type": "ballot", "id": 7 },
+                },
+                {
+                    "code": "poll.no_changes",
+                    "message": "poll cannot be updated without new values",
+                    "context": { "type": "ballot", "id": 7 },
+                },
+            ] },
+        }));
+    }
+
+    #[test]
+    fn server_errors_hide_source() {
+        let err = db_insert(DbError::RollbackTransaction, "poll");
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.kind, ErrorKind::Internal);
+        assert_eq!(body(&err), json!({
+            "code": "server.internal_error",
+            "message": "internal server error",
+        }));
     }
 }
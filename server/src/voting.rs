@@ -1,11 +1,23 @@
 mod ballot;
+mod blt;
 mod id;
+mod import;
+mod limits;
 mod poll;
 mod poll_result;
+mod ranked_csv;
+mod receipt;
+mod report;
 mod user;
 
 pub use ballot::*;
+pub use blt::*;
 pub use id::*;
+pub use import::*;
+pub use limits::*;
 pub use poll::*;
 pub use poll_result::*;
+pub use ranked_csv::*;
+pub use receipt::*;
+pub use report::*;
 pub use user::*;
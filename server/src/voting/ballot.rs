@@ -6,6 +6,7 @@ use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 
 use super::id::WeakId;
+use super::limits::limits;
 use super::poll::Poll;
 use super::user::{User, PossibleUser};
 use crate::error;
@@ -17,6 +18,8 @@ pub struct Ballot {
     pub voter: Option<User>,
     pub ranked_preferences: Vec<WeakId>,
     pub created_at: DateTime<Utc>,
+    /// Counts every time the voter casts, changes or withdraws their ballot in the poll
+    pub revision: u32,
 }
 
 impl Ballot {
@@ -26,6 +29,7 @@ impl Ballot {
             voter: Some(voter),
             ranked_preferences,
             created_at: Utc::now(),
+            revision: 0,
         }
     }
 }
@@ -43,6 +47,7 @@ impl Default for Ballot {
             voter: None,
             ranked_preferences: vec![],
             created_at: Utc::now(),
+            revision: 0,
         }
     }
 }
@@ -72,7 +77,7 @@ impl Default for CreateBallot {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct UnvalidatedCreateBallot {
     pub ranked_preferences: Vec<WeakId>,
 }
@@ -84,24 +89,73 @@ impl UnvalidatedCreateBallot {
         }
     }
 
-    pub fn validate(self, poll: Poll) -> Result<CreateBallot, error::ValidationError> {
+    pub fn validate(self, poll: Poll) -> Result<CreateBallot, error::ValidationErrors> {
         let Self { ranked_preferences, .. } = self;
+        let mut errors = error::ValidationErrors::new();
+
         if ranked_preferences.is_empty() {
-            return Err(error::ballot_empty());
+            errors.push(error::ballot_empty().at("ranked_preferences"));
+        }
+        // duplicates are found in quadratic time, so a long ballot isn't checked any further
+        if ranked_preferences.len() > limits().ballot_length {
+            errors.push(error::ballot_too_long(limits().ballot_length, ranked_preferences.len()).at("ranked_preferences"));
+            return Err(errors);
         }
 
         for (i, pref) in ranked_preferences.iter().enumerate() {
-            if !poll.option_ids.contains(&pref) {
-                return Err(error::ballot_invalid_selection(i, pref.0));
+            let field = format!("ranked_preferences[{i}]");
+            if !poll.option_ids.contains(pref) {
+                errors.push(error::ballot_invalid_selection(i, pref.0).at(field));
             }
-            if let Some((old_idx, _)) = ranked_preferences[0..i].iter().enumerate().find(|(_, p)| *p == pref) {
-                return Err(error::ballot_duplicate_selection(pref.0, (old_idx, i)))
+            else if let Some((old_idx, _)) = ranked_preferences[0..i].iter().enumerate().find(|(_, p)| *p == pref) {
+                errors.push(error::ballot_duplicate_selection(pref.0, (old_idx, i)).at(field));
             }
         }
 
-        Ok(CreateBallot {
+        errors.into_result(CreateBallot {
             poll: Some(poll),
             ranked_preferences,
         })
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::CreatePollSettings;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn validate_reports_every_error() {
+        let poll = Poll::from(CreatePollSettings {
+            options: vec![String::from("A"), String::from("B"), String::from("C")],
+            ..CreatePollSettings::default()
+        });
+        let ballot = UnvalidatedCreateBallot {
+            ranked_preferences: vec![WeakId(1), WeakId(7), WeakId(1), WeakId(2), WeakId(2)],
+        };
+
+        let errors = ballot.validate(poll).err().unwrap();
+        let reported: Vec<_> = errors.iter().map(|e| (e.field().unwrap(), e.kind().clone())).collect();
+        assert_eq!(reported, [
+            ("ranked_preferences[1]", ErrorKind::BallotInvalidSelection { preference_index: 1, option_id: 7 }),
+            ("ranked_preferences[2]", ErrorKind::BallotDuplicateSelection { option_id: 1, preference_indices: (0, 2) }),
+            ("ranked_preferences[4]", ErrorKind::BallotDuplicateSelection { option_id: 2, preference_indices: (3, 4) }),
+        ]);
+    }
+
+    #[test]
+    fn validate_stops_at_long_ballots() {
+        let poll = Poll::from(CreatePollSettings {
+            options: vec![String::from("A"), String::from("B")],
+            ..CreatePollSettings::default()
+        });
+        let max = limits().ballot_length;
+        let ballot = UnvalidatedCreateBallot { ranked_preferences: vec![WeakId(9); max + 1] };
+
+        let errors = ballot.validate(poll).err().unwrap();
+        let reported: Vec<_> = errors.iter().map(|e| (e.field().unwrap(), e.kind().clone())).collect();
+        assert_eq!(reported, [("ranked_preferences", ErrorKind::BallotTooLong { max, actual: max + 1 })]);
+    }
+}
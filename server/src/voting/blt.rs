@@ -0,0 +1,220 @@
+use std::fmt::Write;
+
+use super::ballot::{Ballot, UnvalidatedCreateBallot};
+use super::id::WeakId;
+use super::import::{UnvalidatedImport, DEFAULT_IMPORT_TITLE, MAX_IMPORTED_BALLOTS};
+use super::poll::{Poll, UnvalidatedCreatePollSettings};
+use crate::error;
+
+/// Write the poll and its ballots in the BLT format read by OpenSTV, OpaVote and most other STV
+/// counters: the candidate and seat counts, one line per ballot of its weight and 1-based candidate
+/// numbers ending in 0, a 0 line, then the quoted candidate names and the election title.
+pub fn to_blt(poll: &Poll, ballots: &[Ballot]) -> String {
+    let mut blt = String::new();
+    writeln!(blt, "{} {}", poll.option_ids.len(), poll.winner_count).unwrap();
+
+    for ballot in ballots {
+        blt.push('1');
+        for preference in ballot.ranked_preferences.iter() {
+            // ballots were validated against the poll, so every preference is one of its options
+            if let Some(index) = poll.option_ids.iter().position(|id| id == preference) {
+                write!(blt, " {}", index + 1).unwrap();
+            }
+        }
+        blt.push_str(" 0\n");
+    }
+    blt.push_str("0\n");
+
+    let options = poll.options.as_deref().unwrap_or_default();
+    for id in poll.option_ids.iter() {
+        let description = options.iter()
+            .find(|o| o.id == *id)
+            .map_or_else(|| format!("Option {}", id.0), |o| o.description.clone());
+        writeln!(blt, "{}", quote(&description)).unwrap();
+    }
+    writeln!(blt, "{}", quote(&poll.title)).unwrap();
+
+    blt
+}
+
+/// Read an election in the BLT format. Ballots with a weight are repeated that many times, and
+/// empty ballots are left out. Withdrawn candidates, equal rankings and fractional weights aren't
+/// supported, since polls have none of those.
+pub fn parse_blt(blt: &str) -> Result<UnvalidatedImport, error::ValidationErrors> {
+    let mut lines = blt.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty());
+
+    let (line, header) = lines.next()
+        .ok_or_else(|| error::import_malformed(1, "file is empty"))?;
+    let counts: Vec<usize> = header.split_whitespace()
+        .map(|count| count.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| error::import_malformed(line, "expected the number of candidates and seats"))?;
+    let [candidate_count, seats] = counts[..] else {
+        return Err(error::import_malformed(line, "expected the number of candidates and seats").into());
+    };
+
+    let mut ballots = vec![];
+    loop {
+        let Some((line, text)) = lines.next() else {
+            return Err(error::import_malformed(line, "ballots must end with a line of 0").into());
+        };
+        if text == "0" {
+            break;
+        }
+        if text.starts_with('-') {
+            return Err(error::import_malformed(line, "withdrawn candidates are not supported").into());
+        }
+
+        let mut tokens: Vec<&str> = text.split_whitespace().collect();
+        if tokens.pop() != Some("0") {
+            return Err(error::import_malformed(line, "ballot must end with 0").into());
+        }
+        let (weight, preferences) = tokens.split_first()
+            .ok_or_else(|| error::import_malformed(line, "ballot has no weight"))?;
+        let weight: usize = weight.parse()
+            .map_err(|_| error::import_malformed(line, format!("ballot weight must be a whole number, got {weight}")))?;
+
+        let mut ranked_preferences = vec![];
+        for preference in preferences {
+            match preference.parse::<usize>() {
+                Ok(candidate) if (1..=candidate_count).contains(&candidate) => {
+                    ranked_preferences.push(WeakId(candidate as u32 - 1));
+                },
+                _ => return Err(error::import_malformed(
+                    line, format!("expected a candidate number from 1 to {candidate_count}, got {preference}")
+                ).into()),
+            }
+        }
+
+        if ranked_preferences.is_empty() {
+            continue;
+        }
+        // ballots never outnumber the limit, and weights can be large enough to overflow an addition
+        if weight > MAX_IMPORTED_BALLOTS - ballots.len() {
+            return Err(error::import_too_many_ballots(MAX_IMPORTED_BALLOTS).into());
+        }
+        for _ in 0..weight {
+            ballots.push(UnvalidatedCreateBallot { ranked_preferences: ranked_preferences.clone() });
+        }
+    }
+
+    // the rest is quoted strings, usually one per line: every candidate's name, then the title
+    let mut strings = vec![];
+    for (line, text) in lines {
+        let mut rest = text;
+        while let Some(start) = rest.find('"') {
+            let end = rest[start + 1..].find('"')
+                .ok_or_else(|| error::import_malformed(line, "string is missing its closing quote"))?;
+            strings.push(rest[start + 1..start + 1 + end].to_string());
+            rest = &rest[start + end + 2..];
+        }
+    }
+    if strings.len() < candidate_count {
+        return Err(error::import_malformed(
+            blt.lines().count(), format!("expected {candidate_count} candidate names, got {}", strings.len())
+        ).into());
+    }
+    let title = strings.get(candidate_count).cloned().unwrap_or_else(|| String::from(DEFAULT_IMPORT_TITLE));
+    strings.truncate(candidate_count);
+
+    Ok(UnvalidatedImport {
+        settings: UnvalidatedCreatePollSettings {
+            title,
+            options: strings,
+            winner_count: seats.try_into().unwrap_or(i32::MAX),
+            ..UnvalidatedCreatePollSettings::default()
+        },
+        ballots,
+    })
+}
+
+/// BLT strings can't contain quotes or line breaks, so those are replaced
+fn quote(text: &str) -> String {
+    let text: String = text.chars()
+        .map(|c| match c {
+            '"' => '\'',
+            '\r' | '\n' => ' ',
+            c => c,
+        })
+        .collect();
+    format!("\"{text}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn blt_lists_ballots_then_names() {
+        let poll = Poll::from(CreatePollSettings {
+            id: None,
+            title: String::from("Best \"fruit\""),
+            options: vec![String::from("Apple"), String::from("Banana"), String::from("Cherry")],
+            winner_count: 2,
+            write_ins_allowed: false,
+            ballot_changes_allowed: true,
+            ballot_withdrawals_allowed: true,
+            close_after_time: None,
+            close_after_votes: None,
+        });
+        let ballots: Vec<Ballot> = [vec![2, 0], vec![1], vec![0, 1, 2]].into_iter()
+            .map(|prefs| Ballot {
+                ranked_preferences: prefs.into_iter().map(WeakId).collect(),
+                ..Ballot::default()
+            })
+            .collect();
+
+        assert_eq!(to_blt(&poll, &ballots), "\
+3 2
+1 3 1 0
+1 2 0
+1 1 2 3 0
+0
+\"Apple\"
+\"Banana\"
+\"Cherry\"
+\"Best 'fruit'\"
+");
+    }
+
+    #[test]
+    fn blt_round_trips() {
+        let blt = "3 1\n2 1 3 0\n1 2 0\n1 0\n0\n\"Apple\" \"Banana\"\n\"Cherry\"\n\"Fruit\"\n";
+        let (poll, ballots) = import_blt(blt).unwrap();
+
+        assert_eq!(poll.title, "Fruit");
+        assert_eq!(poll.winner_count, 1);
+        let options: Vec<_> = poll.options.as_ref().unwrap().iter().map(|o| o.description.as_str()).collect();
+        assert_eq!(options, ["Apple", "Banana", "Cherry"]);
+        let preferences: Vec<_> = ballots.iter().map(|b| b.ranked_preferences.clone()).collect();
+        assert_eq!(preferences, [
+            vec![WeakId(0), WeakId(2)],
+            vec![WeakId(0), WeakId(2)],
+            vec![WeakId(1)],
+        ], "Weights repeat ballots, and empty ballots are left out");
+
+        assert_eq!(to_blt(&poll, &ballots), "3 1\n1 1 3 0\n1 1 3 0\n1 2 0\n0\n\"Apple\"\n\"Banana\"\n\"Cherry\"\n\"Fruit\"\n");
+    }
+
+    #[test]
+    fn blt_reports_where_it_is_malformed() {
+        let malformed = |blt: &str| {
+            let errors = parse_blt(blt).err().expect("BLT should be rejected");
+            errors.iter().next().unwrap().kind().clone()
+        };
+
+        assert!(matches!(malformed("3\n"), ErrorKind::ImportMalformed { line: 1, .. }));
+        assert!(matches!(malformed("3 1\n1 4 0\n0\n"), ErrorKind::ImportMalformed { line: 2, .. }));
+        assert!(matches!(malformed("3 1\n-2\n1 1 0\n0\n"), ErrorKind::ImportMalformed { line: 2, .. }));
+        assert!(matches!(malformed("3 1\n1 1 0\n0\n\"Apple\"\n"), ErrorKind::ImportMalformed { .. }));
+        let huge = format!("3 1\n1 1 0\n{} 2 0\n0\n", usize::MAX);
+        assert!(matches!(malformed(&huge), ErrorKind::ImportTooManyBallots { .. }), "Weights don't overflow");
+
+        let errors = import_blt("2 1\n1 1 1 0\n0\n\"Apple\"\n\"Banana\"\n\"Fruit\"\n").err().unwrap();
+        assert_eq!(errors.iter().next().unwrap().field(), Some("ranked_preferences[1]"), "Ballots are validated");
+    }
+}
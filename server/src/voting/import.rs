@@ -0,0 +1,95 @@
+use chrono::Utc;
+use serde::Deserialize;
+
+use super::ballot::{Ballot, UnvalidatedCreateBallot};
+use super::id::WeakId;
+use super::poll::{CreatePollSettings, Poll, UnvalidatedCreatePollSettings};
+use crate::error;
+
+/// The most ballots one import may hold, after ballots with a weight are repeated
+pub const MAX_IMPORTED_BALLOTS: usize = 100_000;
+
+/// The title of imported elections that don't have one
+pub const DEFAULT_IMPORT_TITLE: &str = "Imported election";
+
+/// An election counted outside of Teams, read from a BLT or ranked CSV file but not checked yet
+pub struct UnvalidatedImport {
+    pub settings: UnvalidatedCreatePollSettings,
+    pub ballots: Vec<UnvalidatedCreateBallot>,
+}
+
+/// An election as JSON: the settings a poll is created with, and each ballot's option ids in order
+/// of preference
+#[derive(Deserialize)]
+struct JsonImport {
+    #[serde(flatten)]
+    settings: UnvalidatedCreatePollSettings,
+    ballots: Vec<Vec<WeakId>>,
+}
+
+impl UnvalidatedImport {
+    /// Check the poll and every ballot just as if they had been created through the API. The
+    /// ballots are anonymous, and errors in them have the ballot's index as context.
+    pub fn validate(self) -> Result<(Poll, Vec<Ballot>), error::ValidationErrors> {
+        let Self { settings, ballots } = self;
+
+        let poll = Poll::from(CreatePollSettings::try_from(settings)?);
+
+        let mut errors = error::ValidationErrors::new();
+        if ballots.len() > MAX_IMPORTED_BALLOTS {
+            errors.push(error::import_too_many_ballots(MAX_IMPORTED_BALLOTS).at("ballots"));
+            return Err(errors);
+        }
+
+        let mut valid = vec![];
+        for (i, ballot) in ballots.into_iter().enumerate() {
+            match ballot.validate(poll.clone()) {
+                Err(err) => {
+                    for err in err.with_context("ballot", error::ContextId::I32(i as i32)).iter() {
+                        errors.push(err.clone());
+                    }
+                },
+                Ok(ballot) => valid.push(Ballot {
+                    poll: None,
+                    voter: None,
+                    ranked_preferences: ballot.ranked_preferences,
+                    created_at: Utc::now(),
+                    revision: 0,
+                }),
+            }
+        }
+
+        errors.into_result((poll, valid))
+    }
+}
+
+/// Read an election from JSON like `{"title": "Fruit", "options": ["Apple", "Banana"], "winner_count":
+/// 1, "ballots": [[1, 0], [0]]}`, where options are numbered from 0 in the order they are listed
+pub fn parse_json_import(text: &str) -> Result<UnvalidatedImport, error::ValidationErrors> {
+    let JsonImport { mut settings, ballots } = serde_json::from_str(text)
+        .map_err(|err| error::import_malformed(err.line(), err))?;
+
+    if settings.title.is_empty() {
+        settings.title = String::from(DEFAULT_IMPORT_TITLE);
+    }
+    let ballots: Vec<UnvalidatedCreateBallot> = ballots.into_iter()
+        .filter(|preferences| !preferences.is_empty())
+        .map(|ranked_preferences| UnvalidatedCreateBallot { ranked_preferences })
+        .collect();
+
+    Ok(UnvalidatedImport { settings, ballots })
+}
+
+/// Read and validate an election in the BLT format
+pub fn import_blt(blt: &str) -> Result<(Poll, Vec<Ballot>), error::ValidationErrors> {
+    super::parse_blt(blt)?.validate()
+}
+
+/// Read and validate an election from a ranked CSV file. CSV files have no title or seat count, so
+/// those must be given.
+pub fn import_ranked_csv(text: &str, title: String, winner_count: i32) -> Result<(Poll, Vec<Ballot>), error::ValidationErrors> {
+    let mut import = super::parse_ranked_csv(text)?;
+    import.settings.title = title;
+    import.settings.winner_count = winner_count;
+    import.validate()
+}
@@ -0,0 +1,53 @@
+use std::env;
+use std::sync::OnceLock;
+
+/// Options are stored as VARCHAR(300), so they can't be any longer whatever the configuration
+const OPTION_LENGTH_CAP: usize = 300;
+
+/// Hard caps on the size of polls and ballots, checked before anything is stored so that one request
+/// can't insert or count an unbounded number of rows
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// The most options a poll can have
+    pub options: usize,
+    /// The most characters an option can have
+    pub option_length: usize,
+    /// The most options a ballot can rank
+    pub ballot_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            options: 100,
+            option_length: OPTION_LENGTH_CAP,
+            ballot_length: 100,
+        }
+    }
+}
+
+impl Limits {
+    /// Read the caps from `MAX_POLL_OPTIONS`, `MAX_OPTION_LENGTH` and `MAX_BALLOT_LENGTH`, using the
+    /// defaults for any that are missing or invalid
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name: &str, default: usize| {
+            env::var(name).ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .unwrap_or(default)
+        };
+
+        Self {
+            options: var("MAX_POLL_OPTIONS", defaults.options).max(2),
+            option_length: var("MAX_OPTION_LENGTH", defaults.option_length).min(OPTION_LENGTH_CAP),
+            ballot_length: var("MAX_BALLOT_LENGTH", defaults.ballot_length),
+        }
+    }
+}
+
+/// The caps for this process, read from the environment the first time they're needed
+pub fn limits() -> &'static Limits {
+    static LIMITS: OnceLock<Limits> = OnceLock::new();
+    LIMITS.get_or_init(Limits::from_env)
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::default::Default;
 use std::convert::{From, TryFrom};
 use std::ops::RangeInclusive;
@@ -5,9 +7,11 @@ use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 use serde::{self, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 use super::id::{Id, WeakId};
+use super::limits::limits;
 use super::user::User;
 use crate::error;
 
@@ -20,6 +24,10 @@ pub struct Poll {
 
     pub winner_count: u8,
     pub write_ins_allowed: bool,
+    /// Whether voters may change their ballot until the poll closes
+    pub ballot_changes_allowed: bool,
+    /// Whether voters may withdraw their ballot until the poll closes
+    pub ballot_withdrawals_allowed: bool,
     pub close_after_time: Option<DateTime<Utc>>,
     pub close_after_votes: Option<u32>,
 
@@ -30,6 +38,13 @@ pub struct Poll {
 
     #[serde(skip)]
     pub rng_seed: [u8; 32],
+    /// The SHA-256 hash of the seed, published when the poll is created so the seed revealed once
+    /// it closes can be checked
+    #[serde(default)]
+    pub seed_commitment: String,
+    /// The seed in hex, only once the poll is closed and no ballot can be cast knowing it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revealed_seed: Option<String>,
 }
 
 impl Poll {
@@ -40,8 +55,19 @@ impl Poll {
         poll.owner_id = owner.id.clone();
         poll.owner = Some(owner);
         poll.rng_seed.copy_from_slice(&rng_seed);
+        poll.seed_commitment = commit_seed(&poll.rng_seed);
         poll
     }
+
+    /// Publish the seed ballots were shuffled with, which must only be done once the poll is closed
+    pub fn reveal_seed(&mut self) {
+        self.revealed_seed = Some(hex::encode(self.rng_seed));
+    }
+}
+
+/// The commitment to a poll's seed, the hex SHA-256 hash of its bytes
+pub fn commit_seed(rng_seed: &[u8; 32]) -> String {
+    hex::encode(Sha256::digest(rng_seed))
 }
 
 impl From<CreatePollSettings> for Poll {
@@ -51,6 +77,8 @@ impl From<CreatePollSettings> for Poll {
         options,
         winner_count,
         write_ins_allowed,
+        ballot_changes_allowed,
+        ballot_withdrawals_allowed,
         close_after_time,
         close_after_votes: close_after_num_votes
     }: CreatePollSettings) -> Poll {
@@ -71,6 +99,8 @@ impl From<CreatePollSettings> for Poll {
             options: Some(options),
             winner_count,
             write_ins_allowed,
+            ballot_changes_allowed,
+            ballot_withdrawals_allowed,
             close_after_time,
             close_after_votes: close_after_num_votes,
 
@@ -79,6 +109,8 @@ impl From<CreatePollSettings> for Poll {
             created_at: Utc::now(),
             closed_at: None,
             rng_seed: [0u8; 32],
+            seed_commitment: commit_seed(&[0u8; 32]),
+            revealed_seed: None,
         }
     }
 }
@@ -91,7 +123,7 @@ pub struct PollOption {
 }
 
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(try_from = "UnvalidatedCreatePollSettings")]
 pub struct CreatePollSettings {
     pub id: Option<Uuid>,
@@ -100,6 +132,8 @@ pub struct CreatePollSettings {
 
     pub winner_count: u8,
     pub write_ins_allowed: bool,
+    pub ballot_changes_allowed: bool,
+    pub ballot_withdrawals_allowed: bool,
     pub close_after_time: Option<DateTime<Utc>>,
     pub close_after_votes: Option<u32>,
 }
@@ -113,6 +147,8 @@ impl Default for CreatePollSettings {
             options: vec![],
             winner_count: unvalidated_default.winner_count as u8,
             write_ins_allowed: unvalidated_default.write_ins_allowed,
+            ballot_changes_allowed: unvalidated_default.ballot_changes_allowed,
+            ballot_withdrawals_allowed: unvalidated_default.ballot_withdrawals_allowed,
             close_after_time: unvalidated_default.close_after_time,
             close_after_votes: unvalidated_default.close_after_votes.map(|v| v as u32),
         }
@@ -135,6 +171,14 @@ impl CreatePollSettings {
             self.write_ins_allowed = *write_ins_allowed;
         }
 
+        if let Some(ballot_changes_allowed) = &patch.ballot_changes_allowed {
+            self.ballot_changes_allowed = *ballot_changes_allowed;
+        }
+
+        if let Some(ballot_withdrawals_allowed) = &patch.ballot_withdrawals_allowed {
+            self.ballot_withdrawals_allowed = *ballot_withdrawals_allowed;
+        }
+
         if let Some(close_after_time) = &patch.close_after_time {
             self.close_after_time = *close_after_time;
         }
@@ -145,48 +189,97 @@ impl CreatePollSettings {
     }
 }
 
-const TITLE_LENGTH_BOUNDS: RangeInclusive<usize> = 3usize ..= i32::MAX as usize;
-const OPTIONS_LENGTH_BOUNDS: RangeInclusive<usize> = 2usize ..= i32::MAX as usize;
+// text lengths are in characters, matching the VARCHAR limits of the database schema
+const TITLE_LENGTH_BOUNDS: RangeInclusive<usize> = 3usize ..= 300;
 const WINNERS_BOUNDS: RangeInclusive<i32> = 1 ..= u8::MAX as i32;
 const VOTES_BOUNDS: RangeInclusive<i64> = 2i64 ..= i32::MAX as i64;
 
+fn validate_title(title: String, errors: &mut error::ValidationErrors) -> String {
+    let title = title.trim().to_string();
+    let len = title.chars().count();
+    if !TITLE_LENGTH_BOUNDS.contains(&len) {
+        errors.push(error::poll_title_invalid_size(TITLE_LENGTH_BOUNDS, len).at("title"));
+    }
+    title
+}
+
+fn validate_options(options: Vec<String>, errors: &mut error::ValidationErrors) -> Vec<String> {
+    let options_length_bounds = 2 ..= limits().options;
+    let option_text_length_bounds = 1 ..= limits().option_length;
+    if !options_length_bounds.contains(&options.len()) {
+        errors.push(error::poll_option_limit_exceeded(options_length_bounds, options.len()).at("options"));
+        // checking each of too many options could take as long as storing them
+        if options.len() > limits().options {
+            return options;
+        }
+    }
+
+    let options: Vec<String> = options.iter().map(|o| o.trim().to_string()).collect();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (i, option) in options.iter().enumerate() {
+        let field = format!("options[{i}]");
+        let len = option.chars().count();
+        if len == 0 {
+            errors.push(error::poll_option_empty().at(field));
+        }
+        else if !option_text_length_bounds.contains(&len) {
+            errors.push(error::poll_option_invalid_size(option_text_length_bounds.clone(), len).at(field));
+        }
+        else {
+            match seen.entry(option_key(option)) {
+                Entry::Occupied(first) => errors.push(error::poll_option_duplicate((*first.get(), i)).at(field)),
+                Entry::Vacant(slot) => { slot.insert(i); },
+            }
+        }
+    }
+
+    options
+}
+
+/// Options are the same if they only differ by case or whitespace
+fn option_key(option: &str) -> String {
+    option.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
 impl TryFrom<UnvalidatedCreatePollSettings> for CreatePollSettings {
-    type Error = error::ValidationError;
+    type Error = error::ValidationErrors;
 
     fn try_from(UnvalidatedCreatePollSettings {
         title,
         options,
         winner_count,
         write_ins_allowed,
+        ballot_changes_allowed,
+        ballot_withdrawals_allowed,
         close_after_time,
         close_after_votes,
     }: UnvalidatedCreatePollSettings) -> Result<Self, Self::Error> {
-        if !TITLE_LENGTH_BOUNDS.contains(&title.len()) {
-            return Err(error::poll_title_invalid_size(TITLE_LENGTH_BOUNDS, title.len()));
-        }
-        if !OPTIONS_LENGTH_BOUNDS.contains(&options.len()) {
-            return Err(error::poll_option_limit_exceeded(OPTIONS_LENGTH_BOUNDS, options.len()));
-        }
+        let mut errors = error::ValidationErrors::new();
+
+        let title = validate_title(title, &mut errors);
+        let options = validate_options(options, &mut errors);
         if !WINNERS_BOUNDS.contains(&winner_count) {
-            return Err(error::poll_winners_limit_exceeded(WINNERS_BOUNDS, winner_count));
+            errors.push(error::poll_winners_limit_exceeded(WINNERS_BOUNDS, winner_count).at("winner_count"));
         }
         if let Some(time) = close_after_time {
             if time < Utc::now() + Duration::from_secs(60) {
-                return Err(error::poll_duration_invalid(1, &time))
+                errors.push(error::poll_duration_invalid(1, &time).at("close_after_time"));
             }
         }
         if let Some(votes) = close_after_votes {
             if !VOTES_BOUNDS.contains(&(votes as i64)) {
-                return Err(error::poll_votes_limit_exceeded(VOTES_BOUNDS, votes as i64));
+                errors.push(error::poll_votes_limit_exceeded(VOTES_BOUNDS, votes as i64).at("close_after_votes"));
             }
         }
 
-        Ok(CreatePollSettings {
+        errors.into_result(CreatePollSettings {
             id: None,
             title,
             options,
             winner_count: winner_count as u8,
             write_ins_allowed,
+            ballot_changes_allowed,
+            ballot_withdrawals_allowed,
             close_after_time,
             close_after_votes: close_after_votes.map(|v| v as u32),
         })
@@ -202,6 +295,8 @@ pub struct UnvalidatedCreatePollSettings {
 
     pub winner_count: i32,
     pub write_ins_allowed: bool,
+    pub ballot_changes_allowed: bool,
+    pub ballot_withdrawals_allowed: bool,
     pub close_after_time: Option<DateTime<Utc>>,
     pub close_after_votes: Option<i32>,
 }
@@ -213,6 +308,8 @@ impl Default for UnvalidatedCreatePollSettings {
             options: vec![],
             winner_count: 1,
             write_ins_allowed: false,
+            ballot_changes_allowed: true,
+            ballot_withdrawals_allowed: true,
             close_after_time: None,
             close_after_votes: None,
         }
@@ -226,6 +323,8 @@ impl From<CreatePollSettings> for UnvalidatedCreatePollSettings {
         options,
         winner_count,
         write_ins_allowed,
+        ballot_changes_allowed,
+        ballot_withdrawals_allowed,
         close_after_time,
         close_after_votes,
     }: CreatePollSettings) -> Self {
@@ -234,6 +333,8 @@ impl From<CreatePollSettings> for UnvalidatedCreatePollSettings {
             options,
             winner_count: winner_count as i32,
             write_ins_allowed,
+            ballot_changes_allowed,
+            ballot_withdrawals_allowed,
             close_after_time,
             close_after_votes: close_after_votes.map(|v| v as i32),
         }
@@ -241,13 +342,22 @@ impl From<CreatePollSettings> for UnvalidatedCreatePollSettings {
 }
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(try_from = "UnvalidatedUpdatePollSettings")]
 pub struct UpdatePollSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub winner_count: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub write_ins_allowed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ballot_changes_allowed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ballot_withdrawals_allowed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub close_after_time: Option<Option<DateTime<Utc>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub close_after_votes: Option<Option<u32>>,
 }
 
@@ -257,6 +367,8 @@ impl Default for UpdatePollSettings {
             title: None,
             winner_count: None,
             write_ins_allowed: None,
+            ballot_changes_allowed: None,
+            ballot_withdrawals_allowed: None,
             close_after_time: None,
             close_after_votes: None,
         }
@@ -264,44 +376,42 @@ impl Default for UpdatePollSettings {
 }
 
 impl TryFrom<UnvalidatedUpdatePollSettings> for UpdatePollSettings {
-    type Error = error::ValidationError;
+    type Error = error::ValidationErrors;
 
     fn try_from(UnvalidatedUpdatePollSettings {
         title,
         winner_count,
         write_ins_allowed,
+        ballot_changes_allowed,
+        ballot_withdrawals_allowed,
         close_after_time,
         close_after_votes,
     }: UnvalidatedUpdatePollSettings) -> Result<Self, Self::Error> {
-        if let Some(title) = &title {
-            if !TITLE_LENGTH_BOUNDS.contains(&title.len()) {
-                return Err(error::poll_title_invalid_size(TITLE_LENGTH_BOUNDS, title.len()));
-            }
-        }
+        let mut errors = error::ValidationErrors::new();
+
+        let title = title.map(|title| validate_title(title, &mut errors));
         if let Some(winner_count) = winner_count {
             if !WINNERS_BOUNDS.contains(&(winner_count as i32)) {
-                return Err(error::poll_winners_limit_exceeded(WINNERS_BOUNDS, winner_count as i32));
+                errors.push(error::poll_winners_limit_exceeded(WINNERS_BOUNDS, winner_count as i32).at("winner_count"));
             }
         }
-        if let Some(otime) = close_after_time {
-            if let Some(time) = otime {
-                if time < Utc::now() + Duration::from_secs(60) {
-                    return Err(error::poll_duration_invalid(1, &time))
-                }
+        if let Some(Some(time)) = close_after_time {
+            if time < Utc::now() + Duration::from_secs(60) {
+                errors.push(error::poll_duration_invalid(1, &time).at("close_after_time"));
             }
         }
-        if let Some(ovotes) = close_after_votes {
-            if let Some(votes) = ovotes {
-                if !VOTES_BOUNDS.contains(&(votes as i64)) {
-                    return Err(error::poll_votes_limit_exceeded(VOTES_BOUNDS, votes as i64));
-                }
+        if let Some(Some(votes)) = close_after_votes {
+            if !VOTES_BOUNDS.contains(&(votes as i64)) {
+                errors.push(error::poll_votes_limit_exceeded(VOTES_BOUNDS, votes as i64).at("close_after_votes"));
             }
         }
 
-        Ok(UpdatePollSettings {
+        errors.into_result(UpdatePollSettings {
             title,
             winner_count,
             write_ins_allowed,
+            ballot_changes_allowed,
+            ballot_withdrawals_allowed,
             close_after_time,
             close_after_votes,
         })
@@ -315,6 +425,8 @@ pub struct UnvalidatedUpdatePollSettings {
     pub title: Option<String>,
     pub winner_count: Option<u8>,
     pub write_ins_allowed: Option<bool>,
+    pub ballot_changes_allowed: Option<bool>,
+    pub ballot_withdrawals_allowed: Option<bool>,
 
     // absent fields deserialized as None, explicit null values deserialized as Some(None)
     #[serde(deserialize_with = "deserialize_nested_time")]
@@ -329,6 +441,8 @@ impl Default for UnvalidatedUpdatePollSettings {
             title: None,
             winner_count: None,
             write_ins_allowed: None,
+            ballot_changes_allowed: None,
+            ballot_withdrawals_allowed: None,
             close_after_time: None,
             close_after_votes: None,
         }
@@ -352,3 +466,108 @@ where D: serde::Deserializer<'de> {
         Some(_) => Ok(normal),
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn seed_is_committed_then_revealed() {
+        let owner = User::new(Id::new(), String::from("Owner"));
+        let mut poll = Poll::new(CreatePollSettings::default(), vec![], owner, vec![7; 32]);
+        let json = serde_json::to_value(&poll).unwrap();
+        assert_eq!(json["seed_commitment"], hex::encode(Sha256::digest([7; 32])));
+        assert!(json.get("revealed_seed").is_none(), "Seeds of open polls are secret");
+
+        poll.reveal_seed();
+        let revealed = hex::decode(poll.revealed_seed.as_ref().unwrap()).unwrap();
+        assert_eq!(commit_seed(&revealed.try_into().unwrap()), poll.seed_commitment);
+    }
+
+    #[test]
+    fn create_reports_every_error() {
+        let settings = UnvalidatedCreatePollSettings {
+            title: String::from("Hi"),
+            options: vec![String::from("Only one")],
+            winner_count: 0,
+            close_after_time: Some(Utc::now() - TimeDelta::minutes(5)),
+            close_after_votes: Some(1),
+            ..UnvalidatedCreatePollSettings::default()
+        };
+
+        let errors = CreatePollSettings::try_from(settings).unwrap_err();
+        let fields: Vec<_> = errors.iter().map(|e| e.field().unwrap()).collect();
+        assert_eq!(fields, ["title", "options", "winner_count", "close_after_time", "close_after_votes"]);
+        assert_eq!(errors.iter().next().unwrap().kind(), &ErrorKind::PollTitleInvalidSize {
+            min: 3, max: 300, actual: 2,
+        });
+    }
+
+    #[test]
+    fn create_normalizes_text() {
+        let settings = UnvalidatedCreatePollSettings {
+            title: String::from("  🍕🍣🍉  "),
+            options: vec![String::from(" 🍕 Pizza"), String::from("🍣 Sushi\t")],
+            ..UnvalidatedCreatePollSettings::default()
+        };
+
+        let settings = CreatePollSettings::try_from(settings).unwrap();
+        assert_eq!(settings.title, "🍕🍣🍉", "Title is trimmed and measured in characters");
+        assert_eq!(settings.options, ["🍕 Pizza", "🍣 Sushi"], "Options are trimmed");
+    }
+
+    #[test]
+    fn create_rejects_bad_options() {
+        let settings = UnvalidatedCreatePollSettings {
+            title: String::from("Lunch"),
+            options: vec![
+                String::from("Fried rice"),
+                String::from("   "),
+                "x".repeat(301),
+                String::from(" fried   RICE "),
+                "🍕".repeat(300),
+            ],
+            ..UnvalidatedCreatePollSettings::default()
+        };
+
+        let errors = CreatePollSettings::try_from(settings).unwrap_err();
+        let reported: Vec<_> = errors.iter().map(|e| (e.field().unwrap(), e.kind().clone())).collect();
+        assert_eq!(reported, [
+            ("options[1]", ErrorKind::PollOptionEmpty),
+            ("options[2]", ErrorKind::PollOptionInvalidSize { min: 1, max: 300, actual: 301 }),
+            ("options[3]", ErrorKind::PollOptionDuplicate { option_indices: (0, 3) }),
+        ]);
+    }
+
+    #[test]
+    fn create_stops_at_too_many_options() {
+        let max = limits().options;
+        let settings = UnvalidatedCreatePollSettings {
+            title: String::from("Lunch"),
+            options: vec![String::new(); max + 1],
+            ..UnvalidatedCreatePollSettings::default()
+        };
+
+        let errors = CreatePollSettings::try_from(settings).unwrap_err();
+        let reported: Vec<_> = errors.iter().map(|e| (e.field().unwrap(), e.kind().clone())).collect();
+        assert_eq!(reported, [("options", ErrorKind::PollOptionLimitExceeded { min: 2, max, actual: max + 1 })]);
+    }
+
+    #[test]
+    fn update_reports_every_error() {
+        let settings = UnvalidatedUpdatePollSettings {
+            title: Some(String::new()),
+            winner_count: Some(0),
+            close_after_votes: Some(Some(0)),
+            ..UnvalidatedUpdatePollSettings::default()
+        };
+
+        let errors = UpdatePollSettings::try_from(settings).unwrap_err();
+        let fields: Vec<_> = errors.iter().map(|e| e.field().unwrap()).collect();
+        assert_eq!(fields, ["title", "winner_count", "close_after_votes"]);
+    }
+}
@@ -2,8 +2,8 @@ use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
 
 use chrono::{DateTime, Utc};
-use rand::{self, SeedableRng, rngs::StdRng, prelude::SliceRandom};
-use serde::Serialize;
+use rand::{self, Rng, SeedableRng, rngs::StdRng, prelude::SliceRandom};
+use serde::{Deserialize, Serialize};
 
 use super::ballot::Ballot;
 use super::id::{Id, WeakId};
@@ -28,7 +28,7 @@ impl<'a> Display for Tally<'a> {
     }
 }
 
-#[derive(Debug, Serialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TallyItem {
     option_id: WeakId,
     vote_count: u32,
@@ -41,6 +41,14 @@ impl TallyItem {
             vote_count: count,
         }
     }
+
+    pub fn option_id(&self) -> WeakId {
+        self.option_id
+    }
+
+    pub fn vote_count(&self) -> u32 {
+        self.vote_count
+    }
 }
 
 impl Display for TallyItem {
@@ -74,7 +82,37 @@ impl<'a> std::fmt::Display for BallotList<'a> {
     }
 }
 
-#[derive(Serialize)]
+/// How to choose which option to eliminate when several have the fewest votes. Options that are
+/// still tied after the rule is applied are eliminated lowest id first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TieBreak {
+    /// Eliminate the option ranked least highly over all ballots, where a first preference counts
+    /// as much as two second preferences, or four third preferences
+    #[default]
+    Popularity,
+    /// Eliminate the option with the fewest votes in the latest round where the tied options
+    /// differed, then by popularity
+    Backwards,
+    /// Eliminate one of the tied options at random, drawn from the seed
+    Random,
+}
+
+/// The count after each round of transfers, and what was decided from it
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Round {
+    pub round: u32,
+    /// Votes of every option still counted in this round, most first
+    pub tally: Vec<TallyItem>,
+    /// Options that reached the threshold this round
+    pub elected: Vec<WeakId>,
+    /// The option eliminated at the end of this round, if any
+    pub eliminated: Option<WeakId>,
+    /// Ballots with no continuing option left, in this round and all before it
+    pub exhausted: u32,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct PollResult {
     pub poll_id: Id,
     pub poll: Option<Poll>,
@@ -84,11 +122,23 @@ pub struct PollResult {
     pub tally: Vec<TallyItem>,
     pub winners: Vec<WeakId>,
     pub eliminated: Vec<WeakId>,
+
+    // results stored before rounds were recorded don't have them
+    #[serde(default)]
+    pub exhausted: u32,
+    #[serde(default)]
+    pub rounds: Vec<Round>,
 }
 
 impl PollResult {
     pub fn evaluate(poll: &Poll, ballots: &[Ballot], max_rounds: u32, rng_seed: &[u8; 32]) -> PollResult {
-        println!("{}", BallotList(&ballots));
+        Self::evaluate_with(poll, ballots, max_rounds, rng_seed, TieBreak::default())
+    }
+
+    pub fn evaluate_with(
+        poll: &Poll, ballots: &[Ballot], max_rounds: u32, rng_seed: &[u8; 32], tie_break: TieBreak
+    ) -> PollResult {
+        eprintln!("{}", BallotList(&ballots));
 
         let mut result = PollResult {
             poll_id: poll.id.clone(),
@@ -98,6 +148,8 @@ impl PollResult {
             tally: vec![],
             winners: vec![],
             eliminated: vec![],
+            exhausted: 0,
+            rounds: vec![],
         };
 
         // abort tallying if there are not enough votes to determine a winner
@@ -107,7 +159,8 @@ impl PollResult {
 
         // clone the list of ballots so we can shuffle and throw out invalid/settled/exhausted ballots
         let mut ballots = Vec::from_iter(ballots.iter());
-        ballots.shuffle(&mut StdRng::from_seed(*rng_seed));
+        let mut rng = StdRng::from_seed(*rng_seed);
+        ballots.shuffle(&mut rng);
 
         let vecs = poll.option_ids.iter().map(|_| vec![]);
         let mut tally = poll.option_ids.iter().zip(vecs).collect::<HashMap<&WeakId, Vec<&Ballot>>>();
@@ -123,12 +176,14 @@ impl PollResult {
         }
 
         for round in 1..=max_rounds {
+            let winner_count = result.winners.len();
+
             // count the votes for each option
             while let Some(ballot) = ballots.pop() {
                 // find the vote from this ballot
                 let selection = ballot.ranked_preferences.iter()
                     .find(|id| !result.eliminated.contains(id) && !result.winners.contains(id));
-                println!("User {:?} votes for {selection:?}", ballot.voter);
+                eprintln!("User {:?} votes for {selection:?}", ballot.voter);
 
                 // drop ballot if exhausted
                 if let Some(id) = selection {
@@ -139,32 +194,69 @@ impl PollResult {
                         result.winners.push(id.clone());
                     }
                 }
+                else {
+                    result.exhausted += 1;
+                }
             }
 
-            println!("{}", Tally(&tally));
+            eprintln!("{}", Tally(&tally));
+
+            let mut round_tally: Vec<TallyItem> = tally.iter()
+                .map(|(id, votes)| TallyItem { option_id: **id, vote_count: votes.len() as u32 })
+                .collect();
+            round_tally.sort();
+            result.rounds.push(Round {
+                round,
+                tally: round_tally,
+                elected: result.winners[winner_count..].to_vec(),
+                eliminated: None,
+                exhausted: result.exhausted,
+            });
 
             if result.winners.len() > poll.winner_count as usize {
                 panic!("How did we get too many winners?");
             }
             else if result.winners.len() == poll.winner_count as usize {
-                println!("Winners: {:?}", result.winners);
+                eprintln!("Winners: {:?}", result.winners);
                 break;
             }
-            // find the option with the fewest votes, breaking ties by popularity
+            // find the option with the fewest votes, breaking ties by the chosen rule
             else if let Some(min_votes) = tally.iter().map(|(_, votes)| votes.len()).min() {
-                let loser = tally.iter()
+                let mut tied: Vec<&&WeakId> = tally.iter()
                     .filter(|(_, votes)| votes.len() == min_votes)
-                    .min_by(|(a, _), (b, _)| {
-                        let a_pop = *popularity.get(*a).unwrap_or(&0f64);
-                        let b_pop = *popularity.get(*b).unwrap_or(&0f64);
-                        a_pop.partial_cmp(&b_pop).unwrap() // panics on NaN
-                    }).unwrap().0;
-                println!("No winner after round {round}, eliminating {loser}");
+                    .map(|(id, _)| id)
+                    .collect();
+                tied.sort();
+
+                let by_popularity = |a: &&&WeakId, b: &&&WeakId| {
+                    let a_pop = *popularity.get(**a).unwrap_or(&0f64);
+                    let b_pop = *popularity.get(**b).unwrap_or(&0f64);
+                    a_pop.partial_cmp(&b_pop).unwrap() // panics on NaN
+                };
+                let loser = match tie_break {
+                    TieBreak::Popularity => tied.into_iter().min_by(by_popularity).unwrap(),
+                    TieBreak::Backwards => tied.into_iter()
+                        .min_by(|a, b| {
+                            let votes = |round: &Round, id: &WeakId| round.tally.iter()
+                                .find(|item| item.option_id == *id)
+                                .map_or(0, |item| item.vote_count);
+                            result.rounds.iter().rev().skip(1)
+                                .map(|round| votes(round, a).cmp(&votes(round, b)))
+                                .find(|order| order.is_ne())
+                                .unwrap_or_else(|| by_popularity(a, b))
+                        })
+                        .unwrap(),
+                    TieBreak::Random => tied[rng.gen_range(0..tied.len())],
+                };
+                eprintln!("No winner after round {round}, eliminating {loser}");
                 result.eliminated.push((*loser).clone());
+                if let Some(round) = result.rounds.last_mut() {
+                    round.eliminated = Some(**loser);
+                }
                 ballots = tally.remove(*loser).unwrap();
             }
             else {
-                println!("No ballots remaining, inconclusive");
+                eprintln!("No ballots remaining, inconclusive");
                 break;
             }
         }
@@ -219,6 +311,8 @@ mod tests {
             options,
             winner_count,
             write_ins_allowed: false,
+            ballot_changes_allowed: true,
+            ballot_withdrawals_allowed: true,
             close_after_time: None,
             close_after_votes: None,
         });
@@ -262,6 +356,8 @@ mod tests {
             options: vec![],
             winner_count: 1,
             write_ins_allowed: false,
+            ballot_changes_allowed: true,
+            ballot_withdrawals_allowed: true,
             close_after_time: None,
             close_after_votes: None,
         });
@@ -309,6 +405,41 @@ mod tests {
             TallyItem::new(1, 2),
             TallyItem::new(2, 0)],
             "Check tally");
+        assert_eq!(result.rounds, &[
+            Round {
+                round: 1,
+                tally: vec![TallyItem::new(0, 2), TallyItem::new(1, 2), TallyItem::new(2, 1)],
+                elected: vec![],
+                eliminated: Some(WeakId(2)),
+                exhausted: 0,
+            },
+            Round {
+                round: 2,
+                tally: vec![TallyItem::new(0, 3), TallyItem::new(1, 2)],
+                elected: vec![WeakId(0)],
+                eliminated: None,
+                exhausted: 0,
+            },
+        ], "Check rounds");
+    }
+
+    #[test]
+    fn exhausted_ballots_are_counted() {
+        let (poll, ballots) = generate_poll(1, vec![
+            // 5 votes, 1 seat = 3 votes to win
+            vec![0],
+            vec![0],
+            vec![1],
+            vec![1],
+            vec![2],
+        ]);
+
+        let result = PollResult::evaluate(&poll, ballots.as_ref(), 3, &RNG_SEED);
+        assert!(result.winners.is_empty(), "Exhausted ballots leave no one with enough votes");
+        assert_eq!(result.eliminated[0], 2, "Check eliminated");
+        assert_eq!(result.exhausted, 3, "Check exhausted");
+        let exhausted: Vec<u32> = result.rounds.iter().map(|r| r.exhausted).collect();
+        assert_eq!(exhausted, [0, 1, 3], "Exhausted ballots accumulate over the rounds");
     }
 
     #[test]
@@ -330,6 +461,24 @@ mod tests {
             "Check tally");
     }
 
+    #[test]
+    fn tie_break_rules() {
+        let (poll, ballots) = generate_poll(1, vec![
+            // 1 is tied with 0 after round 1, but ranked more often
+            vec![0, 1],
+            vec![0, 1],
+            vec![0, 1],
+            vec![1],
+            vec![1],
+            vec![2, 1],
+        ]);
+
+        let result = PollResult::evaluate_with(&poll, ballots.as_ref(), 2, &RNG_SEED, TieBreak::Popularity);
+        assert_eq!(result.eliminated, &[2, 0], "Popularity eliminates the option ranked least");
+        let result = PollResult::evaluate_with(&poll, ballots.as_ref(), 2, &RNG_SEED, TieBreak::Backwards);
+        assert_eq!(result.eliminated, &[2, 1], "Backwards eliminates the option behind in the round before");
+    }
+
     #[test]
     fn two_winners_simple() {
         let (poll, ballots) = generate_poll(2, vec![
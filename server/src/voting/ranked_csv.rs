@@ -0,0 +1,104 @@
+use super::ballot::UnvalidatedCreateBallot;
+use super::id::WeakId;
+use super::import::{UnvalidatedImport, DEFAULT_IMPORT_TITLE, MAX_IMPORTED_BALLOTS};
+use super::poll::UnvalidatedCreatePollSettings;
+use crate::error;
+
+/// Read an election from a ranked CSV file, as exported by most form and survey tools: a header row
+/// of the candidates' names, then a row per ballot with the rank given to each candidate, 1 being the
+/// first choice. Unranked candidates are left blank, gaps in the ranks are closed, and empty ballots
+/// are left out. Equal rankings aren't supported, since polls have none.
+pub fn parse_ranked_csv(text: &str) -> Result<UnvalidatedImport, error::ValidationErrors> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+
+    let options: Vec<String> = match reader.headers() {
+        Err(err) => return Err(error::import_malformed(1, err).into()),
+        Ok(headers) => headers.iter().map(String::from).collect(),
+    };
+
+    let mut ballots = vec![];
+    for record in reader.records() {
+        let record = match record {
+            Err(err) => {
+                let line = err.position().map_or(0, |p| p.line() as usize);
+                return Err(error::import_malformed(line, err).into());
+            },
+            Ok(r) => r,
+        };
+        let line = record.position().map_or(0, |p| p.line() as usize);
+        if record.len() > options.len() {
+            return Err(error::import_malformed(
+                line, format!("expected at most {} ranks, got {}", options.len(), record.len())
+            ).into());
+        }
+
+        let mut ranks = vec![];
+        for (option, rank) in record.iter().enumerate() {
+            if rank.is_empty() {
+                continue;
+            }
+            match rank.parse::<u32>() {
+                Ok(rank) if rank > 0 => ranks.push((rank, WeakId(option as u32))),
+                _ => return Err(error::import_malformed(
+                    line, format!("rank of {} must be a whole number from 1, got {rank}", options[option])
+                ).into()),
+            }
+        }
+        ranks.sort_by_key(|(rank, _)| *rank);
+        if let Some(pair) = ranks.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(error::import_malformed(
+                line, format!("more than one candidate is ranked {}", pair[0].0)
+            ).into());
+        }
+
+        if ranks.is_empty() {
+            continue;
+        }
+        if ballots.len() == MAX_IMPORTED_BALLOTS {
+            return Err(error::import_too_many_ballots(MAX_IMPORTED_BALLOTS).into());
+        }
+        ballots.push(UnvalidatedCreateBallot {
+            ranked_preferences: ranks.into_iter().map(|(_, option)| option).collect(),
+        });
+    }
+
+    Ok(UnvalidatedImport {
+        settings: UnvalidatedCreatePollSettings {
+            title: String::from(DEFAULT_IMPORT_TITLE),
+            options,
+            ..UnvalidatedCreatePollSettings::default()
+        },
+        ballots,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn csv_ranks_become_preferences() {
+        let csv = "Apple, Banana ,\"Cherry, sour\"\n2,,1\n,1,\n,,\n3,1,2\n";
+        let (poll, ballots) = import_ranked_csv(csv, String::from("Fruit"), 2).unwrap();
+
+        assert_eq!(poll.winner_count, 2);
+        let options: Vec<_> = poll.options.as_ref().unwrap().iter().map(|o| o.description.as_str()).collect();
+        assert_eq!(options, ["Apple", "Banana", "Cherry, sour"]);
+        let preferences: Vec<_> = ballots.iter().map(|b| b.ranked_preferences.clone()).collect();
+        assert_eq!(preferences, [
+            vec![WeakId(2), WeakId(0)],
+            vec![WeakId(1)],
+            vec![WeakId(1), WeakId(2), WeakId(0)],
+        ]);
+    }
+
+    #[test]
+    fn csv_rejects_equal_ranks() {
+        let errors = parse_ranked_csv("Apple,Banana\n1,2\n1,1\n").err().unwrap();
+        assert!(matches!(errors.iter().next().unwrap().kind(), ErrorKind::ImportMalformed { line: 3, .. }));
+    }
+}
@@ -0,0 +1,188 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::id::WeakId;
+
+/// Proof for a voter that their ballot was stored as cast. The receipt is the hex SHA-256 hash of
+/// `{poll id}:{option ids in order of preference, comma separated}:{nonce}`, which only the voter
+/// can recompute, since the nonce is only given to them.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Receipt {
+    pub receipt: String,
+    pub nonce: String,
+}
+
+impl Receipt {
+    /// A receipt for the ballot with a new random nonce
+    pub fn new(poll_id: &Uuid, ranked_preferences: &[WeakId]) -> Self {
+        let mut nonce = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        Self::with_nonce(poll_id, ranked_preferences, &nonce)
+    }
+
+    pub fn with_nonce(poll_id: &Uuid, ranked_preferences: &[WeakId], nonce: &[u8]) -> Self {
+        let nonce = hex::encode(nonce);
+        let preferences: Vec<String> = ranked_preferences.iter().map(|id| id.to_string()).collect();
+        let contents = format!("{poll_id}:{}:{nonce}", preferences.join(","));
+        Self { receipt: hex::encode(Sha256::digest(contents)), nonce }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// A hash to combine with on the way up to the root, and which side it goes on
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ProofStep {
+    pub hash: String,
+    pub side: Side,
+}
+
+/// A counted ballot as published once its poll closes: its rankings and its receipt, but not its
+/// voter. Ballots cast before receipts existed have none, and aren't in the Merkle tree.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CountedBallot {
+    pub receipt: Option<String>,
+    pub ranked_preferences: Vec<WeakId>,
+}
+
+// leaves and parents are hashed with different prefixes, so that one can't pass for the other
+const LEAF_PREFIX: u8 = 0;
+const PARENT_PREFIX: u8 = 1;
+
+/// The root of the Merkle tree over every counted ballot of a poll, which ties each receipt to the
+/// rankings that were counted for it. Each leaf is the SHA-256 hash of a 0 byte, the receipt's bytes
+/// and each option id as 4 big-endian bytes, in order of preference, and the leaves are sorted. Each
+/// parent is the SHA-256 hash of a 1 byte and its children's bytes, and a last node without a
+/// sibling moves up a level as it is. A poll without receipts has the hash of nothing as its root.
+pub fn merkle_root(ballots: &[CountedBallot]) -> String {
+    let mut level = leaves(ballots);
+    if level.is_empty() {
+        return hex::encode(Sha256::digest([]));
+    }
+    while level.len() > 1 {
+        level = parents(&level);
+    }
+    hex::encode(level[0])
+}
+
+/// The path from the ballot with the receipt up to the Merkle root, if it is among them
+pub fn merkle_proof(ballots: &[CountedBallot], receipt: &str) -> Option<Vec<ProofStep>> {
+    let ballot = ballots.iter().find(|b| b.receipt.as_deref() == Some(receipt))?;
+    let leaf = leaf(ballot)?;
+    let mut level = leaves(ballots);
+    let mut index = level.iter().position(|l| *l == leaf)?;
+
+    let mut proof = vec![];
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            let side = if sibling < index { Side::Left } else { Side::Right };
+            proof.push(ProofStep { hash: hex::encode(hash), side });
+        }
+        level = parents(&level);
+        index /= 2;
+    }
+    Some(proof)
+}
+
+/// Whether the proof leads from the ballot to the root, so that the ballot was counted with exactly
+/// these rankings
+pub fn verify_merkle_proof(ballot: &CountedBallot, proof: &[ProofStep], root: &str) -> bool {
+    let Some(mut hash) = leaf(ballot) else {
+        return false;
+    };
+    for step in proof {
+        let Some(sibling) = hex::decode(&step.hash).ok().and_then(|s| <[u8; 32]>::try_from(s).ok()) else {
+            return false;
+        };
+        hash = match step.side {
+            Side::Left => parent(&sibling, &hash),
+            Side::Right => parent(&hash, &sibling),
+        };
+    }
+    hex::encode(hash) == root
+}
+
+fn leaf(ballot: &CountedBallot) -> Option<[u8; 32]> {
+    let receipt = hex::decode(ballot.receipt.as_ref()?).ok()?;
+    let mut hasher = Sha256::new().chain_update([LEAF_PREFIX]).chain_update(receipt);
+    for option in ballot.ranked_preferences.iter() {
+        hasher.update(option.0.to_be_bytes());
+    }
+    Some(hasher.finalize().into())
+}
+
+fn leaves(ballots: &[CountedBallot]) -> Vec<[u8; 32]> {
+    let mut leaves: Vec<[u8; 32]> = ballots.iter().filter_map(leaf).collect();
+    leaves.sort();
+    leaves
+}
+
+fn parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new().chain_update([PARENT_PREFIX]).chain_update(left).chain_update(right).finalize().into()
+}
+
+fn parents(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level.chunks(2)
+        .map(|pair| match pair {
+            [left, right] => parent(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::leaves;
+
+    #[test]
+    fn receipts_are_proven_in_the_tree() {
+        let poll_id = uuid::Uuid::new_v4();
+        let ballots: Vec<CountedBallot> = (0..5u32)
+            .map(|i| {
+                let ranked_preferences = vec![WeakId(i), WeakId(0)];
+                let receipt = Receipt::new(&poll_id, &ranked_preferences).receipt;
+                CountedBallot { receipt: Some(receipt), ranked_preferences }
+            })
+            .collect();
+        let root = merkle_root(&ballots);
+
+        for ballot in ballots.iter() {
+            let proof = merkle_proof(&ballots, ballot.receipt.as_ref().unwrap()).unwrap();
+            assert!(verify_merkle_proof(ballot, &proof, &root));
+            assert!(!verify_merkle_proof(ballot, &proof, &merkle_root(&ballots[1..])), "Proofs are for one tree");
+
+            let altered = CountedBallot { ranked_preferences: vec![WeakId(4)], ..ballot.clone() };
+            assert!(!verify_merkle_proof(&altered, &proof, &root), "Proofs are for the rankings counted");
+        }
+
+        let receipt = Receipt::new(&poll_id, &[WeakId(1)]);
+        assert_eq!(Receipt::with_nonce(&poll_id, &[WeakId(1)], &hex::decode(&receipt.nonce).unwrap()), receipt);
+        assert_ne!(Receipt::with_nonce(&poll_id, &[WeakId(2)], &hex::decode(&receipt.nonce).unwrap()), receipt);
+        assert_eq!(merkle_proof(&ballots, &receipt.receipt), None, "Other receipts aren't in the tree");
+    }
+
+    #[test]
+    fn parents_cant_pass_for_leaves() {
+        let ballots: Vec<CountedBallot> = (0..2u32)
+            .map(|i| CountedBallot { receipt: Some(hex::encode([i as u8; 32])), ranked_preferences: vec![WeakId(i)] })
+            .collect();
+        let root = merkle_root(&ballots);
+
+        // a two leaf tree's root is the hash of 64 bytes, which mustn't verify as a leaf of them
+        let mut leaves = leaves(&ballots);
+        leaves.sort();
+        let forged = CountedBallot { receipt: Some(hex::encode(leaves[0])), ranked_preferences: vec![] };
+        let proof = [ProofStep { hash: hex::encode(leaves[1]), side: Side::Right }];
+        assert!(!verify_merkle_proof(&forged, &proof, &root));
+    }
+}
@@ -0,0 +1,228 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::id::{Id, WeakId};
+use super::poll::Poll;
+use super::poll_result::PollResult;
+
+/// What had happened to an option by the end of a round, or of the count
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptionStatus {
+    Continuing,
+    Elected,
+    Eliminated,
+}
+impl OptionStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OptionStatus::Continuing => "continuing",
+            OptionStatus::Elected => "elected",
+            OptionStatus::Eliminated => "eliminated",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OptionRef {
+    pub id: WeakId,
+    pub description: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OptionReport {
+    pub id: WeakId,
+    pub description: String,
+    /// Votes in the last round the option was counted in
+    pub votes: u32,
+    pub status: OptionStatus,
+    pub elected_round: Option<u32>,
+    pub eliminated_round: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OptionCount {
+    pub id: WeakId,
+    pub description: String,
+    pub votes: u32,
+    pub status: OptionStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoundReport {
+    pub round: u32,
+    /// Every option of the poll, in the poll's order, with no votes once eliminated
+    pub counts: Vec<OptionCount>,
+    pub exhausted: u32,
+    pub elected: Vec<OptionRef>,
+    pub eliminated: Option<OptionRef>,
+}
+
+/// A poll result with the options described, for people rather than clients to read. Options are
+/// always listed in the poll's order, so exports from different polls line up.
+#[derive(Debug, Serialize)]
+pub struct ResultReport {
+    pub poll_id: Id,
+    pub title: String,
+    pub winner_count: u8,
+    pub ballot_count: usize,
+    pub threshold: usize,
+    pub exhausted: u32,
+    pub evaluated_at: DateTime<Utc>,
+    pub options: Vec<OptionReport>,
+    pub winners: Vec<OptionRef>,
+    pub eliminated: Vec<OptionRef>,
+    pub rounds: Vec<RoundReport>,
+}
+
+impl ResultReport {
+    pub fn new(poll: &Poll, result: &PollResult, ballot_count: usize) -> ResultReport {
+        let option_ref = |id: &WeakId| OptionRef { id: *id, description: describe(poll, id) };
+        let elected_round = |id: &WeakId| result.rounds.iter().find(|r| r.elected.contains(id)).map(|r| r.round);
+        let eliminated_round = |id: &WeakId| result.rounds.iter().find(|r| r.eliminated == Some(*id)).map(|r| r.round);
+
+        let options = poll.option_ids.iter()
+            .map(|id| {
+                let votes = result.tally.iter()
+                    .find(|item| item.option_id() == *id)
+                    .map_or(0, |item| item.vote_count());
+                let status = if result.winners.contains(id) {
+                    OptionStatus::Elected
+                }
+                else if result.eliminated.contains(id) {
+                    OptionStatus::Eliminated
+                }
+                else {
+                    OptionStatus::Continuing
+                };
+                OptionReport {
+                    id: *id,
+                    description: describe(poll, id),
+                    votes,
+                    status,
+                    elected_round: elected_round(id),
+                    eliminated_round: eliminated_round(id),
+                }
+            })
+            .collect();
+
+        let rounds = result.rounds.iter()
+            .map(|round| RoundReport {
+                round: round.round,
+                counts: poll.option_ids.iter()
+                    .map(|id| {
+                        let status = match (elected_round(id), eliminated_round(id)) {
+                            (Some(r), _) if r <= round.round => OptionStatus::Elected,
+                            (_, Some(r)) if r <= round.round => OptionStatus::Eliminated,
+                            _ => OptionStatus::Continuing,
+                        };
+                        OptionCount {
+                            id: *id,
+                            description: describe(poll, id),
+                            votes: round.tally.iter()
+                                .find(|item| item.option_id() == *id)
+                                .map_or(0, |item| item.vote_count()),
+                            status,
+                        }
+                    })
+                    .collect(),
+                exhausted: round.exhausted,
+                elected: round.elected.iter().map(option_ref).collect(),
+                eliminated: round.eliminated.as_ref().map(option_ref),
+            })
+            .collect();
+
+        ResultReport {
+            poll_id: poll.id.clone(),
+            title: poll.title.clone(),
+            winner_count: poll.winner_count,
+            ballot_count,
+            threshold: result.threshold,
+            exhausted: result.exhausted,
+            evaluated_at: result.evaluated_at,
+            options,
+            winners: result.winners.iter().map(option_ref).collect(),
+            eliminated: result.eliminated.iter().map(option_ref).collect(),
+            rounds,
+        }
+    }
+
+    /// The rounds as CSV, with the columns `round,option_id,option,votes,status`: a row for every
+    /// option in every round, then a row of the ballots exhausted so far, with an empty option id
+    pub fn to_csv(&self) -> String {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.write_record(["round", "option_id", "option", "votes", "status"])
+            .expect("Writing CSV to memory never fails");
+        for round in self.rounds.iter() {
+            for count in round.counts.iter() {
+                writer.write_record([
+                    round.round.to_string(),
+                    count.id.to_string(),
+                    count.description.clone(),
+                    count.votes.to_string(),
+                    count.status.name().to_string(),
+                ]).expect("Writing CSV to memory never fails");
+            }
+            writer.write_record([
+                round.round.to_string(),
+                String::new(),
+                String::from("(exhausted)"),
+                round.exhausted.to_string(),
+                String::new(),
+            ]).expect("Writing CSV to memory never fails");
+        }
+
+        let csv = writer.into_inner().expect("Writing CSV to memory never fails");
+        String::from_utf8(csv).expect("CSV of strings is valid UTF-8")
+    }
+}
+
+fn describe(poll: &Poll, id: &WeakId) -> String {
+    poll.options.as_deref().unwrap_or_default().iter()
+        .find(|o| o.id == *id)
+        .map_or_else(|| format!("Option {}", id.0), |o| o.description.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    #[test]
+    fn report_describes_every_round() {
+        let poll = Poll::from(CreatePollSettings {
+            id: None,
+            title: String::from("Fruit"),
+            options: vec![String::from("Apple"), String::from("Banana"), String::from("Cherry, sour")],
+            winner_count: 1,
+            write_ins_allowed: false,
+            ballot_changes_allowed: true,
+            ballot_withdrawals_allowed: true,
+            close_after_time: None,
+            close_after_votes: None,
+        });
+        let ballots: Vec<Ballot> = [vec![0], vec![0], vec![1], vec![1], vec![2, 0]].into_iter()
+            .map(|prefs| Ballot {
+                ranked_preferences: prefs.into_iter().map(WeakId).collect(),
+                ..Ballot::default()
+            })
+            .collect();
+
+        let result = PollResult::evaluate(&poll, &ballots, 2, &[0; 32]);
+        let report = ResultReport::new(&poll, &result, ballots.len());
+
+        assert_eq!(report.winners.iter().map(|w| w.description.as_str()).collect::<Vec<_>>(), ["Apple"]);
+        assert_eq!(report.options[2].eliminated_round, Some(1));
+        assert_eq!(report.options[0].elected_round, Some(2));
+        assert_eq!(report.to_csv(), "\
+round,option_id,option,votes,status
+1,0,Apple,2,continuing
+1,1,Banana,2,continuing
+1,2,\"Cherry, sour\",1,eliminated
+1,,(exhausted),0,
+2,0,Apple,3,elected
+2,1,Banana,2,continuing
+2,2,\"Cherry, sour\",0,eliminated
+2,,(exhausted),0,
+");
+    }
+}
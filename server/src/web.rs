@@ -1,75 +1,254 @@
+pub mod admin;
+mod audit;
+mod audit_api;
+mod bot;
+mod card_api;
+mod cards;
 mod db;
+mod events;
+mod idempotency;
+mod import_api;
+mod jobs;
+mod notifications;
 mod poll_api;
+mod rate_limit;
+mod reminders;
 mod ballot_api;
 mod result_api;
+#[cfg(test)]
+mod testing;
+mod webhook_api;
+mod webhooks;
 
 use std::env;
+use std::sync::Arc;
+use serde::de::DeserializeOwned;
+use tokio::signal;
+use tokio::sync::watch;
 use uuid::Uuid;
-use warp::Filter;
+use warp::{Filter, Rejection};
 
-use crate::voting::{UnvalidatedCreateBallot, CreatePollSettings, UpdatePollSettings};
+use crate::error;
+use idempotency::IdempotencyKey;
+use rate_limit::Bucket;
+use crate::voting::{
+    UnvalidatedCreateBallot,
+    CreatePollSettings, UnvalidatedCreatePollSettings,
+    UpdatePollSettings, UnvalidatedUpdatePollSettings,
+};
+
+/// The largest JSON body accepted by the API unless `MAX_BODY_BYTES` is set, which is plenty for a
+/// poll with the most and longest options
+const DEFAULT_BODY_LIMIT: u64 = 256 * 1024;
 
 pub async fn setup() {
+    // every background task and open event stream stops once shutdown is signalled
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let hub = Arc::new(events::Hub::default());
+    let limiter = Arc::new(rate_limit::RateLimiter::from_env());
+    let body_limit = env::var("MAX_BODY_BYTES").ok()
+        .and_then(|b| b.parse().ok())
+        .unwrap_or(DEFAULT_BODY_LIMIT);
+
     // define the poll API
 
-    let new_poll = warp::post()
-        .and(warp::path!("api" / "poll"))
+    let new_poll = warp::path!("api" / "poll")
+        .and(warp::post())
         .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::PollCreation))
         .and(warp::header::<Uuid>("user-id"))
-        .and(warp::body::json::<CreatePollSettings>())
+        .and(warp::header::optional::<IdempotencyKey>("idempotency-key"))
+        .and(validated_json::<UnvalidatedCreatePollSettings, CreatePollSettings>(body_limit))
         .map(poll_api::new);
 
-    let get_poll = warp::get()
-        .and(warp::path!("api" / "poll" / Uuid))
+    let import_poll = warp::path!("api" / "polls" / "import")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::PollCreation))
+        .and(warp::header::<Uuid>("user-id"))
+        .and(warp::query::<import_api::ImportQuery>())
+        .and(warp::body::content_length_limit(import_api::IMPORT_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .map(import_api::import);
+
+    let get_poll = warp::path!("api" / "poll" / Uuid)
+        .and(warp::get())
         .and(warp::path::end())
         .map(poll_api::get);
 
-    let update_poll = warp::patch()
-        .and(warp::path!("api" / "poll" / Uuid))
+    let list_polls = warp::path!("api" / "polls")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::header::optional::<Uuid>("user-id"))
+        .and(warp::query::<poll_api::ListQuery>())
+        .map(poll_api::list);
+
+    let update_poll = warp::path!("api" / "poll" / Uuid)
+        .and(warp::patch())
         .and(warp::path::end())
         .and(warp::header::<Uuid>("user-id"))
-        .and(warp::body::json::<UpdatePollSettings>())
+        .and(validated_json::<UnvalidatedUpdatePollSettings, UpdatePollSettings>(body_limit))
         .map(poll_api::update);
 
-    let delete_poll = warp::delete()
-        .and(warp::path!("api" / "poll" / Uuid))
+    let delete_poll = warp::path!("api" / "poll" / Uuid)
+        .and(warp::delete())
         .and(warp::path::end())
         .and(warp::header::<Uuid>("user-id"))
         .map(poll_api::delete);
 
     // define the ballot API
 
-    let new_ballot = warp::post()
-        .and(warp::path!("api" / "poll" / Uuid / "my_ballot"))
+    let new_ballot = warp::path!("api" / "poll" / Uuid / "my_ballot")
+        .and(warp::post())
         .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::BallotSubmission))
         .and(warp::header::<Uuid>("user-id"))
-        .and(warp::body::json::<UnvalidatedCreateBallot>())
+        .and(warp::header::optional::<IdempotencyKey>("idempotency-key"))
+        .and(json::<UnvalidatedCreateBallot>(body_limit))
         .map(ballot_api::new);
 
-    let get_ballot = warp::get()
-        .and(warp::path!("api" / "poll" / Uuid / "my_ballot"))
+    let get_ballot = warp::path!("api" / "poll" / Uuid / "my_ballot")
+        .and(warp::get())
         .and(warp::path::end())
         .and(warp::header::<Uuid>("user-id"))
         .map(ballot_api::get);
 
-    let update_ballot = warp::patch()
-        .and(warp::path!("api" / "poll" / Uuid / "my_ballot"))
+    let update_ballot = warp::path!("api" / "poll" / Uuid / "my_ballot")
+        .and(warp::patch())
         .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::BallotSubmission))
         .and(warp::header::<Uuid>("user-id"))
-        .and(warp::body::json::<UnvalidatedCreateBallot>())
+        .and(warp::header::optional::<ballot_api::IfMatch>("if-match"))
+        .and(json::<UnvalidatedCreateBallot>(body_limit))
         .map(ballot_api::update);
 
-    let delete_ballot = warp::delete()
-        .and(warp::path!("api" / "poll" / Uuid / "my_ballot"))
+    let delete_ballot = warp::path!("api" / "poll" / Uuid / "my_ballot")
+        .and(warp::delete())
         .and(warp::path::end())
         .and(warp::header::<Uuid>("user-id"))
+        .and(warp::header::optional::<ballot_api::IfMatch>("if-match"))
         .map(ballot_api::delete);
 
-    let get_result = warp::get()
-        .and(warp::path!("api" / "poll" / Uuid / "result"))
+    let ballot_receipt = warp::path!("api" / "poll" / Uuid / "my_ballot" / "receipt")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .map(ballot_api::receipt);
+
+    let ballot_revisions = warp::path!("api" / "poll" / Uuid / "my_ballot" / "revisions")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .map(ballot_api::revisions);
+
+    let get_result = warp::path!("api" / "poll" / Uuid / "result")
+        .and(warp::get())
         .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::ResultRead))
         .map(result_api::get_result);
 
+    let verify_result = warp::path!("api" / "poll" / Uuid / "result" / "verify")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::ResultRead))
+        .map(result_api::verify);
+
+    let poll_receipts = warp::path!("api" / "poll" / Uuid / "receipts")
+        .and(warp::get())
+        .and(warp::path::end())
+        .map(result_api::receipts);
+
+    let poll_audit = warp::path!("api" / "poll" / Uuid / "audit")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .and(warp::query::<audit_api::AuditQuery>())
+        .map(audit_api::list);
+
+    let export_result_csv = warp::path!("api" / "poll" / Uuid / "result.csv")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::ResultRead))
+        .map(result_api::export_csv);
+
+    let export_result_json = warp::path!("api" / "poll" / Uuid / "result" / "detailed")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::ResultRead))
+        .map(result_api::export_json);
+
+    let export_ballots = warp::path!("api" / "poll" / Uuid / "ballots.blt")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .map(result_api::export_blt);
+
+    let get_card = warp::path!("api" / "poll" / Uuid / "card")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(rate_limit::limit(limiter.clone(), Bucket::ResultRead))
+        .and(warp::header::optional::<Uuid>("user-id"))
+        .and(warp::query::<card_api::CardQuery>())
+        .map(card_api::get_card);
+
+    let poll_events = warp::path!("api" / "poll" / Uuid / "events")
+        .and(warp::get())
+        .and(warp::path::end())
+        .map({
+            let hub = hub.clone();
+            let shutdown = shutdown_rx.clone();
+            move |poll_id| events::subscribe(poll_id, hub.clone(), shutdown.clone())
+        });
+
+    // define the webhook API
+
+    let new_poll_webhook = warp::path!("api" / "poll" / Uuid / "webhooks")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .and(validated_json::<webhook_api::UnvalidatedCreateWebhook, webhook_api::CreateWebhook>(body_limit))
+        .map(webhook_api::new_for_poll);
+
+    let list_poll_webhooks = warp::path!("api" / "poll" / Uuid / "webhooks")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .map(webhook_api::list_for_poll);
+
+    let new_tenant_webhook = warp::path!("api" / "tenant" / String / "webhooks")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .and(warp::header::optional::<String>("admin-key"))
+        .and(validated_json::<webhook_api::UnvalidatedCreateWebhook, webhook_api::CreateWebhook>(body_limit))
+        .map(webhook_api::new_for_tenant);
+
+    let webhook_deliveries = warp::path!("api" / "webhook" / Uuid / "deliveries")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .map(webhook_api::deliveries);
+
+    let delete_webhook = warp::path!("api" / "webhook" / Uuid)
+        .and(warp::delete())
+        .and(warp::path::end())
+        .and(warp::header::<Uuid>("user-id"))
+        .map(webhook_api::delete);
+
+    // define the Teams bot endpoint
+
+    let bot = Arc::new(bot::Bot::from_env());
+    let announcer = bot.clone();
+    let bot_limiter = limiter.clone();
+    let bot_messages = warp::path!("api" / "messages")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(json::<bot::Activity>(body_limit))
+        .and(warp::any().map(move || bot.clone()))
+        .and(warp::any().map(move || bot_limiter.clone()))
+        .and_then(bot::messages);
+
     // Define the static files route
     let cwd = env::current_exe().expect("Could not get current executable path");
     let static_path = cwd.parent().unwrap()
@@ -80,11 +259,77 @@ pub async fn setup() {
     let static_files = warp::path("static")
         .and(warp::fs::dir(static_path));
 
+    // Start the background jobs
+    let mut jobs = jobs::Jobs::new(shutdown_rx);
+    jobs.every("close_polls", jobs::CLOSE_POLLS_PERIOD, jobs::close_polls);
+    jobs.every("expire_idempotency_keys", jobs::EXPIRE_IDEMPOTENCY_KEYS_PERIOD, jobs::expire_idempotency_keys);
+    jobs.every("prune_rate_limits", rate_limit::PRUNE_PERIOD, move || limiter.prune());
+    jobs.run("poll_events", |shutdown| events::listen(hub, shutdown));
+    jobs.run("announce_results", |shutdown| notifications::announce_results(announcer.clone(), shutdown));
+    jobs.run("send_reminders", |shutdown| reminders::Reminders::from_env(announcer).run(shutdown));
+    jobs.run("deliver_webhooks", webhooks::deliver);
+
     // Start the server
     let routes =
-        new_poll.or(get_poll).or(update_poll).or(delete_poll)
-        .or(new_ballot).or(get_ballot).or(update_ballot).or(delete_ballot)
-        .or(get_result)
-        .or(static_files);
-    warp::serve(routes).run(([0, 0, 0, 0], 3000)).await;
+        new_poll.or(import_poll).or(get_poll).or(list_polls).or(update_poll).or(delete_poll)
+        .or(new_ballot).or(get_ballot).or(update_ballot).or(delete_ballot).or(ballot_receipt).or(ballot_revisions)
+        .or(get_result).or(verify_result).or(poll_receipts).or(poll_audit).or(export_result_csv).or(export_result_json).or(export_ballots).or(get_card).or(poll_events)
+        .or(new_poll_webhook).or(list_poll_webhooks).or(new_tenant_webhook).or(webhook_deliveries).or(delete_webhook)
+        .or(bot_messages)
+        .or(static_files)
+        .recover(error::recover);
+    let (_, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown(([0, 0, 0, 0], 3000), async move {
+            shutdown_signal().await;
+            let _ = shutdown.send(true);
+        });
+
+    // runs until a shutdown signal is received and all in-flight requests have completed
+    server.await;
+
+    println!("Stopping background jobs");
+    jobs.join().await;
+    println!("Shutdown complete");
+}
+
+/// Deserialize a JSON request body of at most `limit` bytes, rejecting larger bodies before they
+/// are read
+fn json<T: DeserializeOwned + Send>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(limit).and(warp::body::json::<T>())
+}
+
+/// Deserialize a JSON request body of at most `limit` bytes as `U`, then validate it into a `T`,
+/// rejecting with a structured error if it fails
+fn validated_json<U, T>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    U: DeserializeOwned + Send,
+    T: TryFrom<U, Error = error::ValidationErrors> + Send,
+{
+    json::<U>(limit).and_then(|body: U| async move {
+        T::try_from(body).map_err(|err| warp::reject::custom(error::HttpError::from(err)))
+    })
+}
+
+/// Resolves when the process receives SIGINT (Ctrl+C), or SIGTERM on Unix platforms
+async fn shutdown_signal() {
+    let interrupt = async {
+        signal::ctrl_c().await.expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+
+    println!("Shutdown requested, draining in-flight requests");
 }
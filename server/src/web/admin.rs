@@ -0,0 +1,277 @@
+use std::error::Error;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::dsl::{count, exists, not};
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use diesel::sql_types::{Text, Uuid as SqlUuid};
+use diesel_migrations::MigrationHarness;
+use uuid::Uuid;
+
+use super::db::{establish_connection, models, schema, MIGRATIONS};
+use super::{audit, events, result_api, webhooks};
+
+/// The name anonymized users are given, the same as users who never gave one
+pub const ANONYMOUS: &str = "Anonymous";
+
+pub type AdminResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PollState {
+    Open,
+    Closed,
+    All,
+}
+
+#[derive(Debug, Queryable)]
+pub struct PollSummary {
+    pub id: Uuid,
+    pub title: String,
+    pub owner_id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub closed_at: Option<NaiveDateTime>,
+    pub ballot_count: i64,
+}
+
+/// Run every migration the database hasn't had yet, returning their versions
+pub fn migrate() -> AdminResult<Vec<String>> {
+    let connection = &mut establish_connection();
+    let versions = connection.run_pending_migrations(MIGRATIONS)?;
+    Ok(versions.into_iter().map(|v| v.to_string()).collect())
+}
+
+/// The newest polls, with how many ballots each has
+pub fn list_polls(state: PollState, limit: i64) -> AdminResult<Vec<PollSummary>> {
+    let connection = &mut establish_connection();
+    let mut query = schema::polls::table
+        .left_join(schema::ballots::table)
+        .group_by(schema::polls::id)
+        .select((
+            schema::polls::id,
+            schema::polls::title,
+            schema::polls::owner_id,
+            schema::polls::created_at,
+            schema::polls::closed_at,
+            count(schema::ballots::id.nullable()),
+        ))
+        .order(schema::polls::created_at.desc())
+        .limit(limit)
+        .into_boxed();
+    query = match state {
+        PollState::Open => query.filter(schema::polls::closed_at.is_null()),
+        PollState::Closed => query.filter(schema::polls::closed_at.is_not_null()),
+        PollState::All => query,
+    };
+
+    Ok(query.load(connection)?)
+}
+
+/// Close a poll now, whatever its closing conditions, and store its result. Returns false if the
+/// poll was closed already.
+pub fn close(poll_id: &Uuid) -> AdminResult<bool> {
+    let connection = &mut establish_connection();
+    let closed = connection.transaction::<usize, DbError, _>(|connection| {
+        let closed = diesel::update(
+            schema::polls::table.filter(schema::polls::id.eq(poll_id).and(schema::polls::closed_at.is_null()))
+        )
+            .set(schema::polls::closed_at.eq(Utc::now().naive_utc()))
+            .execute(connection)?;
+        if closed > 0 {
+            audit::record(connection, poll_id, None, audit::Action::PollClosed, serde_json::json!({ "reason": "admin" }))?;
+        }
+        Ok(closed)
+    })?;
+    if closed == 0 {
+        ensure_exists(connection, poll_id)?;
+        return Ok(false);
+    }
+
+    webhooks::enqueue(connection, poll_id, webhooks::Event::PollClosed);
+    result_api::finalize(connection, poll_id)?;
+    Ok(true)
+}
+
+/// Open a closed poll for voting again, dropping its stored result. Closing conditions that are
+/// already met are cleared, or the poll would just be closed again. The seed was revealed when the
+/// poll closed, so ballots are shuffled with a new one, committed to in the audit log.
+pub fn reopen(poll_id: &Uuid) -> AdminResult<()> {
+    let connection = &mut establish_connection();
+    let now = Utc::now().naive_utc();
+
+    connection.transaction::<(), DbError, _>(|connection| {
+        let (close_after_time, close_after_votes): (Option<NaiveDateTime>, Option<i32>) = schema::polls::table
+            .find(poll_id)
+            .select((schema::polls::close_after_time, schema::polls::close_after_votes))
+            .for_update()
+            .first(connection)?;
+        let ballot_count: i64 = schema::ballots::table
+            .filter(schema::ballots::poll_id.eq(poll_id))
+            .count()
+            .get_result(connection)?;
+        let (rng_seed, seed_commitment) = models::new_seed();
+
+        diesel::update(schema::polls::table.find(poll_id))
+            .set((
+                schema::polls::rng_seed.eq(rng_seed),
+                schema::polls::seed_commitment.eq(&seed_commitment),
+                schema::polls::closed_at.eq(None::<NaiveDateTime>),
+                schema::polls::announced_at.eq(None::<NaiveDateTime>),
+                schema::polls::close_after_time.eq(close_after_time.filter(|time| *time > now)),
+                schema::polls::close_after_votes.eq(close_after_votes.filter(|votes| ballot_count < *votes as i64)),
+            ))
+            .execute(connection)?;
+        diesel::delete(schema::pollresults::table.find(poll_id)).execute(connection)?;
+        audit::record(connection, poll_id, None, audit::Action::PollReopened, serde_json::json!({ "seed_commitment": seed_commitment }))?;
+        Ok(())
+    })?;
+
+    events::notify(connection, poll_id, events::Change::PollUpdated);
+    Ok(())
+}
+
+/// Delete every poll closed more than `days` days ago with everything in it, then the users left
+/// with no polls, ballots or webhooks. Open polls are kept however old they are. Returns the deleted
+/// polls and how many users were deleted.
+pub fn purge(days: u32, dry_run: bool) -> AdminResult<(Vec<Uuid>, usize)> {
+    let connection = &mut establish_connection();
+    let cutoff = Utc::now().naive_utc() - Duration::days(days.into());
+
+    if dry_run {
+        let poll_ids = schema::polls::table
+            .filter(schema::polls::closed_at.lt(cutoff))
+            .select(schema::polls::id)
+            .load(connection)?;
+        return Ok((poll_ids, 0));
+    }
+
+    let (poll_ids, user_count) = connection.transaction::<_, DbError, _>(|connection| {
+        let poll_ids: Vec<Uuid> = diesel::delete(schema::polls::table.filter(schema::polls::closed_at.lt(cutoff)))
+            .returning(schema::polls::id)
+            .get_results(connection)?;
+        for poll_id in poll_ids.iter() {
+            audit::record(connection, poll_id, None, audit::Action::PollDeleted, serde_json::json!({ "reason": "purge" }))?;
+        }
+
+        let user_count = diesel::delete(
+            schema::users::table
+                .filter(not(exists(schema::polls::table.filter(schema::polls::owner_id.eq(schema::users::id)))))
+                .filter(not(exists(schema::ballots::table.filter(schema::ballots::user_id.eq(schema::users::id)))))
+                .filter(not(exists(schema::ballotrevisions::table.filter(schema::ballotrevisions::user_id.eq(schema::users::id)))))
+                .filter(not(exists(schema::webhooks::table.filter(schema::webhooks::owner_id.eq(schema::users::id)))))
+        ).execute(connection)?;
+
+        Ok((poll_ids, user_count))
+    })?;
+
+    for poll_id in poll_ids.iter() {
+        events::notify(connection, poll_id, events::Change::PollDeleted);
+    }
+    Ok((poll_ids, user_count))
+}
+
+/// Count a closed poll's ballots again and replace its stored result
+pub fn recount(poll_id: &Uuid) -> AdminResult<()> {
+    let connection = &mut establish_connection();
+    let closed_at: Option<NaiveDateTime> = schema::polls::table
+        .find(poll_id)
+        .select(schema::polls::closed_at)
+        .first(connection)
+        .optional()?
+        .ok_or("poll not found")?;
+    if closed_at.is_none() {
+        return Err("poll is still open, its result is counted whenever it is shown".into());
+    }
+
+    connection.transaction::<_, Box<dyn Error + Send + Sync>, _>(|connection| {
+        diesel::delete(schema::pollresults::table.find(poll_id)).execute(connection)?;
+        result_api::finalize(connection, poll_id)?;
+        Ok(())
+    })
+}
+
+/// Replace a user's name wherever it is stored, including in stored results and webhook payloads,
+/// which embed the poll and its owner. Returns whether the user was found.
+pub fn anonymize(user_id: &Uuid) -> AdminResult<bool> {
+    let connection = &mut establish_connection();
+
+    let found = connection.transaction::<_, DbError, _>(|connection| {
+        let found = diesel::update(schema::users::table.find(user_id))
+            .set(schema::users::display_name.eq(ANONYMOUS))
+            .execute(connection)?;
+
+        // the JSON paths of the owner, up to its last key
+        let owners = [
+            ("PollResults", "result", "{poll,owner"),
+            ("WebhookDeliveries", "payload", "{data,poll,owner"),
+            ("WebhookDeliveries", "payload", "{data,result,poll,owner"),
+        ];
+        for (table, column, owner) in owners {
+            diesel::sql_query(format!(
+                "UPDATE {table} SET {column} = jsonb_set({column}, '{owner},display_name}}', to_jsonb($1::text)) \
+                WHERE {column} #>> '{owner},id}}' = $2::text"
+            ))
+                .bind::<Text, _>(ANONYMOUS)
+                .bind::<SqlUuid, _>(user_id)
+                .execute(connection)?;
+        }
+
+        Ok(found > 0)
+    })?;
+
+    Ok(found)
+}
+
+fn ensure_exists(connection: &mut PgConnection, poll_id: &Uuid) -> AdminResult<()> {
+    let found: bool = diesel::select(exists(schema::polls::table.find(poll_id))).get_result(connection)?;
+    if found { Ok(()) } else { Err("poll not found".into()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voting;
+    use super::super::testing::TestPoll;
+
+    #[test]
+    fn polls_close_and_reopen() -> AdminResult<()> {
+        let connection = &mut establish_connection();
+        let test_poll = TestPoll::create(voting::CreatePollSettings {
+            title: String::from("Admin test"),
+            close_after_time: Some(Utc::now() - Duration::hours(1)),
+            ..voting::CreatePollSettings::default()
+        });
+        let (poll, poll_id, owner_id) = (&test_poll.poll, test_poll.id(), test_poll.owner_id);
+        let stored_result = |connection: &mut PgConnection| -> QueryResult<bool> {
+            diesel::select(exists(schema::pollresults::table.find(poll_id))).get_result(connection)
+        };
+
+        assert!(close(&poll_id)?, "Open polls are closed");
+        assert!(!close(&poll_id)?, "Closed polls are left alone");
+        assert!(stored_result(connection)?, "Closing stores the result");
+        recount(&poll_id)?;
+        assert!(stored_result(connection)?, "Recounting replaces the result");
+
+        reopen(&poll_id)?;
+        let (closed_at, close_after_time, rng_seed, seed_commitment):
+            (Option<NaiveDateTime>, Option<NaiveDateTime>, Vec<u8>, String) = schema::polls::table
+            .find(poll_id)
+            .select((
+                schema::polls::closed_at,
+                schema::polls::close_after_time,
+                schema::polls::rng_seed,
+                schema::polls::seed_commitment,
+            ))
+            .first(connection)?;
+        assert_eq!(closed_at, None);
+        assert_ne!(rng_seed, poll.rng_seed, "Revealed seeds aren't used again");
+        assert_eq!(voting::commit_seed(&rng_seed.try_into().unwrap()), seed_commitment);
+        assert_eq!(close_after_time, None, "A closing time that has passed is cleared");
+        assert!(!stored_result(connection)?, "Reopening drops the result");
+        assert!(recount(&poll_id).is_err(), "Open polls aren't recounted");
+
+        anonymize(&owner_id)?;
+        let name: String = schema::users::table.find(owner_id).select(schema::users::display_name).first(connection)?;
+        assert_eq!(name, ANONYMOUS);
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use uuid::Uuid;
+
+use super::db::{models, schema};
+
+/// A change to a poll or its ballots worth keeping a record of
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    PollCreated,
+    PollUpdated,
+    PollClosed,
+    PollReopened,
+    PollDeleted,
+    BallotCast,
+    BallotChanged,
+    BallotWithdrawn,
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::PollCreated => "poll.created",
+            Action::PollUpdated => "poll.updated",
+            Action::PollClosed => "poll.closed",
+            Action::PollReopened => "poll.reopened",
+            Action::PollDeleted => "poll.deleted",
+            Action::BallotCast => "ballot.cast",
+            Action::BallotChanged => "ballot.changed",
+            Action::BallotWithdrawn => "ballot.withdrawn",
+        }
+    }
+}
+
+/// Record a change to a poll. This must be called in the transaction making the change, so that
+/// the change can't happen without its record. `actor_id` is who made it, or none for the server.
+pub fn record(
+    connection: &mut PgConnection,
+    poll_id: &Uuid,
+    actor_id: Option<&Uuid>,
+    action: Action,
+    details: serde_json::Value,
+) -> Result<(), DbError> {
+    diesel::insert_into(schema::auditevents::table)
+        .values(models::CreateAuditEvent {
+            poll_id: *poll_id,
+            actor_id: actor_id.copied(),
+            action: action.name().to_string(),
+            details,
+        })
+        .execute(connection)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+
+    use super::*;
+    use crate::voting;
+    use super::super::db::establish_connection;
+    use super::super::audit_api;
+    use super::super::testing::TestPoll;
+
+    #[test]
+    fn events_are_recorded_and_kept() -> Result<(), Box<dyn std::error::Error>> {
+        let connection = &mut establish_connection();
+        let test_poll = TestPoll::create(voting::CreatePollSettings {
+            title: String::from("Audit test"),
+            ..voting::CreatePollSettings::default()
+        });
+        let (poll_id, owner_id) = (test_poll.id(), test_poll.owner_id);
+
+        let events: Vec<models::AuditEvent> = schema::auditevents::table
+            .filter(schema::auditevents::poll_id.eq(poll_id))
+            .select(models::AuditEvent::as_select())
+            .load(connection)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, Action::PollCreated.name());
+        assert_eq!(events[0].actor_id, Some(owner_id));
+
+        let update = diesel::update(schema::auditevents::table.find(events[0].id))
+            .set(schema::auditevents::action.eq(Action::PollDeleted.name()))
+            .execute(connection);
+        assert!(update.is_err(), "Events can't be changed");
+        let delete = diesel::delete(schema::auditevents::table.find(events[0].id)).execute(connection);
+        assert!(delete.is_err(), "Events can't be deleted");
+
+        diesel::delete(schema::polls::table.find(poll_id)).execute(connection)?;
+        let history = audit_api::list(poll_id, owner_id, audit_api::AuditQuery::default());
+        assert_eq!(history.status(), StatusCode::OK, "Owners can read the history of deleted polls");
+        let history = audit_api::list(poll_id, Uuid::new_v4(), audit_api::AuditQuery::default());
+        assert_eq!(history.status(), StatusCode::FORBIDDEN, "Only owners can");
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use std::ops::RangeInclusive;
+
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::reply::{self, Reply, Response};
+
+use crate::error;
+use super::audit::Action;
+use super::db::{establish_connection, models, schema};
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AuditQuery {
+    /// Where the previous page left off, from its `next_cursor`
+    pub cursor: Option<String>,
+    pub limit: i64,
+}
+
+impl Default for AuditQuery {
+    fn default() -> Self {
+        Self { cursor: None, limit: 100 }
+    }
+}
+
+const LIST_LIMIT_BOUNDS: RangeInclusive<i64> = 1 ..= 1000;
+
+#[derive(Serialize)]
+struct AuditEventList {
+    events: Vec<models::AuditEvent>,
+    next_cursor: Option<String>,
+}
+
+/// The history of a poll, oldest first, which only its owner may see, even once it is deleted
+pub fn list(poll_id: Uuid, user_id: Uuid, query: AuditQuery) -> Response {
+    let mut errors = error::ValidationErrors::new();
+    if !LIST_LIMIT_BOUNDS.contains(&query.limit) {
+        errors.push(error::list_limit_invalid(LIST_LIMIT_BOUNDS, query.limit).at("limit"));
+    }
+    // the cursor is the id of the last event on the previous page
+    let cursor = query.cursor.as_deref().map(|c| c.parse::<i64>().ok());
+    if let Some(None) = cursor {
+        errors.push(error::list_cursor_invalid().at("cursor"));
+    }
+    if !errors.is_empty() {
+        return error::HttpError::from(errors).into_response();
+    }
+
+    let connection = &mut establish_connection();
+    match owner(connection, &poll_id) {
+        Err(err) => return err.into_response(),
+        Ok(owner_id) if owner_id != Some(user_id) => return error::forbidden("poll").into_response(),
+        Ok(_) => {},
+    }
+
+    let mut db_query = schema::auditevents::table
+        .filter(schema::auditevents::poll_id.eq(poll_id))
+        .order(schema::auditevents::id)
+        .limit(query.limit + 1)
+        .select(models::AuditEvent::as_select())
+        .into_boxed();
+    if let Some(Some(after)) = cursor {
+        db_query = db_query.filter(schema::auditevents::id.gt(after));
+    }
+
+    let result: Result<Vec<models::AuditEvent>, DbError> = db_query.load(connection);
+    let mut events = match result {
+        Err(err) => return error::internal(err).into_response(),
+        Ok(e) => e,
+    };
+
+    let next_cursor = if events.len() as i64 > query.limit {
+        events.truncate(query.limit as usize);
+        events.last().map(|event| event.id.to_string())
+    }
+    else {
+        None
+    };
+
+    reply::json(&AuditEventList { events, next_cursor }).into_response()
+}
+
+/// Who created the poll, as its history records, which is kept after the poll is deleted
+fn owner(connection: &mut PgConnection, poll_id: &Uuid) -> Result<Option<Uuid>, error::HttpError> {
+    let result = schema::auditevents::table
+        .filter(schema::auditevents::poll_id.eq(poll_id).and(schema::auditevents::action.eq(Action::PollCreated.name())))
+        .select(schema::auditevents::actor_id)
+        .first::<Option<Uuid>>(connection)
+        .optional();
+    match result {
+        Err(err) => Err(error::internal(err)),
+        Ok(Some(owner_id)) => Ok(owner_id),
+        // polls created before their history was kept only have it while they exist
+        Ok(None) => match schema::polls::table.find(poll_id).select(schema::polls::owner_id).first(connection) {
+            Err(err) => Err(error::db_get(err, "poll")),
+            Ok(owner_id) => Ok(Some(owner_id)),
+        },
+    }
+}
@@ -1,6 +1,10 @@
-use chrono::{DateTime, Utc};
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
-use diesel::result::{DatabaseErrorKind, Error as DbError};
+use diesel::result::Error as DbError;
+use serde::Serialize;
 use uuid::Uuid;
 use warp::http::StatusCode;
 use warp::reply::{self, Reply, Response};
@@ -8,45 +12,86 @@ use warp::reply::{self, Reply, Response};
 use crate::error;
 use crate::voting;
 use super::db::{establish_connection, models, schema};
+use super::audit;
+use super::events;
+use super::idempotency::{self, IdempotencyKey, Replayable};
+use super::result_api;
+use super::webhooks;
 use super::poll_api::get_internal as get_poll;
 
-pub fn new(poll_id: Uuid, user_id: Uuid, ballot: voting::UnvalidatedCreateBallot) -> Response {
+/// A ballot with the receipt the voter can check it by, which is only shown when it is cast
+#[derive(Serialize)]
+struct ReceiptedBallot {
+    #[serde(flatten)]
+    ballot: voting::Ballot,
+    #[serde(flatten)]
+    receipt: voting::Receipt,
+}
+
+/// The voter's receipt, and once the poll is closed, the proof that it is in the poll's Merkle tree
+#[derive(Serialize)]
+struct ReceiptProof {
+    #[serde(flatten)]
+    receipt: voting::Receipt,
+    merkle_root: Option<String>,
+    proof: Option<Vec<voting::ProofStep>>,
+}
+
+pub fn new(
+    poll_id: Uuid, user_id: Uuid, idempotency_key: Option<IdempotencyKey>, ballot: voting::UnvalidatedCreateBallot
+) -> Response {
     let connection = &mut establish_connection();
 
-    // todo: get owner = session user
-    let owner = models::User { id: user_id, display_name: String::from("Anonymous") };
-    let owner_result = diesel::insert_into(schema::users::table)
-        .values(&owner)
+    let request = idempotency::Request::new(format!("POST /api/poll/{poll_id}/my_ballot"), &ballot);
+    idempotency::once(connection, &user_id, idempotency_key, request, |connection| {
+        // todo: get owner = session user
+        let owner = models::User { id: user_id, display_name: String::from("Anonymous") };
+        add_user(connection, &owner)?;
+        let owner: voting::User = owner.into();
+
+        // fetch poll from db, and validate ballot against it
+        let poll = get_poll(connection, &poll_id)?;
+        let ballot = ballot.validate(poll)?;
+
+        let (db_ballot, receipt) = insert(connection, &poll_id, &user_id, &ballot)?;
+
+        let mut ballot = voting::Ballot::new(owner, ballot);
+        ballot.created_at = db_ballot.created_at.and_utc();
+        ballot.revision = db_ballot.revision as u32;
+
+        let etag = etag(&ballot);
+        Ok(Replayable::new(StatusCode::CREATED, &ReceiptedBallot { ballot, receipt }).with_header("etag", etag))
+    })
+}
+
+/// Add the user if they aren't known yet
+pub fn add_user(connection: &mut PgConnection, user: &models::User) -> Result<(), error::HttpError> {
+    let result = diesel::insert_into(schema::users::table)
+        .values(user)
         .on_conflict_do_nothing()
         .execute(connection);
-    if let Err(err) = owner_result {
-        return reply::with_status(
-            format!("Error creating user: {err}"),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ).into_response();
-    }
-    let owner: voting::User = owner.into();
 
-    // fetch poll from db
-    let poll = match get_poll(connection, &poll_id) {
-        Err(err) => {
-            return reply::with_status(err.to_string(), StatusCode::BAD_REQUEST).into_response();
-        },
-        Ok(p) => p,
-    };
+    match result {
+        Err(err) => Err(error::db_insert(err, "user")),
+        Ok(_) => Ok(()),
+    }
+}
 
-    // validate ballot against poll
-    let ballot = match ballot.validate(poll) {
-        Err(err) => {
-            return reply::with_status(err.to_string(), StatusCode::BAD_REQUEST).into_response();
-        },
-        Ok(b) => b,
-    };
+/// Store the user's first ballot in the poll, returning it as stored and its receipt
+pub fn insert(
+    connection: &mut PgConnection, poll_id: &Uuid, user_id: &Uuid, ballot: &voting::CreateBallot
+) -> Result<(models::Ballot, voting::Receipt), error::HttpError> {
+    let receipt = voting::Receipt::new(poll_id, &ballot.ranked_preferences);
+    let insert_result = connection.transaction::<Result<models::Ballot, error::HttpError>, DbError, _>(|connection| {
+        if let Err(err) = lock_poll(connection, poll_id, BallotChange::Cast)? {
+            return Ok(Err(err));
+        }
+        // a ballot cast again after being withdrawn carries on from its last revision
+        let revision = next_revision(connection, poll_id, user_id)?;
 
-    let insert_result: Result<DateTime<Utc>, DbError> = connection.transaction(|connection| {
         // insert new ballot into the db
         let db_ballot: models::Ballot = diesel::insert_into(schema::ballots::table)
-            .values(models::CreateBallot::new(poll_id, user_id))
+            .values(models::CreateBallot::with_receipt(*poll_id, *user_id, revision, &receipt))
             .get_result(connection)?;
 
         // insert votes into db
@@ -60,36 +105,43 @@ pub fn new(poll_id: Uuid, user_id: Uuid, ballot: voting::UnvalidatedCreateBallot
             }).collect::<Vec<_>>())
             .execute(connection)?;
 
-        Ok(db_ballot.created_at.and_utc())
+        add_revision(connection, poll_id, user_id, revision, Some(&ballot.ranked_preferences), Some(&receipt.receipt))?;
+        let details = serde_json::json!({ "ballot_id": db_ballot.id, "revision": revision, "receipt": receipt.receipt });
+        audit::record(connection, poll_id, Some(user_id), audit::Action::BallotCast, details)?;
+
+        Ok(Ok(db_ballot))
     });
-    let created_at = match insert_result {
-        Err(DbError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
-            return reply::with_status(reply::reply(), StatusCode::CONFLICT).into_response();
-        },
+    let db_ballot = match insert_result {
         Err(err) => {
-            return reply::with_status(
-                format!("Failed to create ballot: {err}"),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ).into_response();
+            return Err(error::db_insert(err, "ballot"));
         },
-        Ok(dt) => dt,
+        Ok(Err(err)) => {
+            return Err(err);
+        },
+        Ok(Ok(b)) => b,
     };
 
-    let mut ballot = voting::Ballot::new(owner, ballot);
-    ballot.created_at = created_at;
-
-    reply::with_status(reply::json(&ballot), StatusCode::CREATED).into_response()
+    events::notify(connection, poll_id, events::Change::Ballot);
+    webhooks::enqueue(connection, poll_id, webhooks::Event::BallotCast);
+    Ok((db_ballot, receipt))
 }
 
 pub fn get(poll_id: Uuid, user_id: Uuid) -> Response {
     let connection = &mut establish_connection();
     match get_internal(connection, &poll_id, &user_id) {
         Err(err) => err.into_response(),
-        Ok(ballot) => reply::json(&ballot).into_response(),
+        Ok(ballot) => {
+            let etag = etag(&ballot);
+            reply::with_header(reply::json(&ballot), "etag", etag).into_response()
+        },
     }
 }
 
-pub fn update(poll_id: Uuid, user_id: Uuid, new_ballot: voting::UnvalidatedCreateBallot) -> Response {
+/// Replace the user's ballot, only if it is still at the revision they last saw when they say which
+/// that was, so that changes made meanwhile, like from another device, aren't lost
+pub fn update(
+    poll_id: Uuid, user_id: Uuid, if_match: Option<IfMatch>, new_ballot: voting::UnvalidatedCreateBallot
+) -> Response {
     let connection = &mut establish_connection();
 
     // fetch poll from db
@@ -103,98 +155,317 @@ pub fn update(poll_id: Uuid, user_id: Uuid, new_ballot: voting::UnvalidatedCreat
     // confirm success
     let new_ballot = match new_ballot.validate(poll) {
         Err(err) => {
-            return reply::with_status(err.to_string(), StatusCode::BAD_REQUEST).into_response();
+            return error::HttpError::from(err).into_response();
         },
         Ok(b) => b,
     };
 
-    // fetch ballot id from db
-    let result = schema::ballots::table
-        .filter(schema::ballots::poll_id.eq(poll_id).and(schema::ballots::user_id.eq(user_id)))
-        .select(schema::ballots::id)
-        .first(connection);
-
-    // confirm success
-    let ballot_id: i32 = match result {
-        Err(DbError::NotFound) => {
-            return reply::with_status(reply::reply(), StatusCode::NOT_FOUND).into_response();
-        },
+    let expected_revision = if_match.and_then(|m| m.0);
+    let receipt = match replace(connection, &poll_id, &user_id, &new_ballot, expected_revision) {
         Err(err) => {
-            return reply::with_status(
-                format!("Error updating ballot: {err}"),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ).into_response();
+            return err.into_response();
         },
-        Ok(id) => id,
+        Ok(r) => r,
     };
 
-    // update preferences
-    let result = diesel::insert_into(schema::votes::table)
-        .values(
-            new_ballot.ranked_preferences.iter().enumerate()
-            .map(|(idx, opt)| models::Vote { ballot_id, preference: idx as i32, option: opt.0 as i32, })
-            .collect::<Vec<models::Vote>>()
-        )
-        .on_conflict((schema::votes::ballot_id, schema::votes::preference))
-        .do_update()
-        .set(schema::votes::option.eq(diesel::upsert::excluded(schema::votes::option)))
-        .execute(connection);
-
-    // validate success
-    if let Err(err) = result {
-        return reply::with_status(
-            format!("Failed to update ballot: {err}"),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ).into_response();
+    match get_internal(connection, &poll_id, &user_id) {
+        Err(err) => err.into_response(),
+        Ok(ballot) => {
+            let etag = etag(&ballot);
+            let reply = reply::with_status(reply::json(&ReceiptedBallot { ballot, receipt }), StatusCode::OK);
+            reply::with_header(reply, "etag", etag).into_response()
+        },
     }
+}
 
-    // delete excesses
-    let result = diesel::delete(schema::votes::table)
-        .filter(
-            schema::votes::ballot_id.eq(ballot_id)
-            .and(schema::votes::preference.ge(new_ballot.ranked_preferences.len() as i32))
-        )
-        .execute(connection);
+/// Replace the rankings of the user's existing ballot in the poll, returning its new receipt. With
+/// an expected revision, the ballot is only replaced if it is still at that revision.
+pub fn replace(
+    connection: &mut PgConnection,
+    poll_id: &Uuid,
+    user_id: &Uuid,
+    new_ballot: &voting::CreateBallot,
+    expected_revision: Option<i32>,
+) -> Result<voting::Receipt, error::HttpError> {
+    // the old receipt is for rankings that are gone
+    let receipt = voting::Receipt::new(poll_id, &new_ballot.ranked_preferences);
+    let result = connection.transaction::<Result<(), error::HttpError>, DbError, _>(|connection| {
+        if let Err(err) = lock_poll(connection, poll_id, BallotChange::Replace)? {
+            return Ok(Err(err));
+        }
+        let ballot_id = match lock_ballot(connection, poll_id, user_id, expected_revision)? {
+            Err(err) => {
+                return Ok(Err(err));
+            },
+            Ok(id) => id,
+        };
+        let revision = next_revision(connection, poll_id, user_id)?;
+
+        // update preferences
+        diesel::insert_into(schema::votes::table)
+            .values(
+                new_ballot.ranked_preferences.iter().enumerate()
+                .map(|(idx, opt)| models::Vote { ballot_id, preference: idx as i32, option: opt.0 as i32, })
+                .collect::<Vec<models::Vote>>()
+            )
+            .on_conflict((schema::votes::ballot_id, schema::votes::preference))
+            .do_update()
+            .set(schema::votes::option.eq(diesel::upsert::excluded(schema::votes::option)))
+            .execute(connection)?;
+
+        // delete excesses
+        diesel::delete(schema::votes::table)
+            .filter(
+                schema::votes::ballot_id.eq(ballot_id)
+                .and(schema::votes::preference.ge(new_ballot.ranked_preferences.len() as i32))
+            )
+            .execute(connection)?;
+
+        diesel::update(schema::ballots::table.find(ballot_id))
+            .set((
+                schema::ballots::nonce.eq(hex::decode(&receipt.nonce).ok()),
+                schema::ballots::receipt.eq(&receipt.receipt),
+                schema::ballots::revision.eq(revision),
+            ))
+            .execute(connection)?;
+
+        add_revision(connection, poll_id, user_id, revision, Some(&new_ballot.ranked_preferences), Some(&receipt.receipt))?;
+        let details = serde_json::json!({ "ballot_id": ballot_id, "revision": revision, "receipt": receipt.receipt });
+        audit::record(connection, poll_id, Some(user_id), audit::Action::BallotChanged, details)?;
+        Ok(Ok(()))
+    });
 
     // confirm success
-    if let Err(err) = result {
-        return reply::with_status(
-            format!("Failed to update ballot: {err}"),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ).into_response();
+    match result {
+        Err(err) => return Err(error::internal(err)),
+        Ok(Err(err)) => return Err(err),
+        Ok(Ok(())) => {},
     }
 
-    match get_internal(connection, &poll_id, &user_id) {
-        Err(err) => err.into_response(),
-        Ok(ballot) => reply::with_status(reply::json(&ballot), StatusCode::OK).into_response(),
-    }
+    events::notify(connection, poll_id, events::Change::Ballot);
+    webhooks::enqueue(connection, poll_id, webhooks::Event::BallotCast);
+    Ok(receipt)
 }
 
-pub fn delete(poll_id: Uuid, user_id: Uuid) -> Response {
+/// Withdraw the user's ballot, only if it is still at the revision they last saw when they say which
+/// that was
+pub fn delete(poll_id: Uuid, user_id: Uuid, if_match: Option<IfMatch>) -> Response {
     let connection = &mut establish_connection();
-    let result = diesel::delete(schema::ballots::table.filter(
-        schema::ballots::poll_id.eq(poll_id).and(schema::ballots::user_id.eq(user_id))
-    )).execute(connection);
+
+    let expected_revision = if_match.and_then(|m| m.0);
+    let result = connection.transaction::<Result<(), error::HttpError>, DbError, _>(|connection| {
+        if let Err(err) = lock_poll(connection, &poll_id, BallotChange::Withdraw)? {
+            return Ok(Err(err));
+        }
+        let ballot_id = match lock_ballot(connection, &poll_id, &user_id, expected_revision)? {
+            Err(err) => {
+                return Ok(Err(err));
+            },
+            Ok(id) => id,
+        };
+        let revision = next_revision(connection, &poll_id, &user_id)?;
+
+        diesel::delete(schema::ballots::table.find(ballot_id)).execute(connection)?;
+        add_revision(connection, &poll_id, &user_id, revision, None, None)?;
+        let details = serde_json::json!({ "ballot_id": ballot_id, "revision": revision });
+        audit::record(connection, &poll_id, Some(&user_id), audit::Action::BallotWithdrawn, details)?;
+        Ok(Ok(()))
+    });
 
     match result {
         Err(err) => {
-            reply::with_status(
-                format!("Failed to delete ballot: {err}"),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ).into_response()
+            error::internal(err).into_response()
         },
-        Ok(affected) if affected == 0 => {
-            reply::with_status(reply::reply(), StatusCode::NOT_FOUND).into_response()
+        Ok(Err(err)) => {
+            err.into_response()
         },
-        Ok(_) => {
+        Ok(Ok(())) => {
+            events::notify(connection, &poll_id, events::Change::Ballot);
             reply::with_status(reply::reply(), StatusCode::NO_CONTENT).into_response()
         },
     }
 }
 
-fn get_internal(
+/// The receipt of the user's ballot, with the proof that it was counted once the poll is closed
+pub fn receipt(poll_id: Uuid, user_id: Uuid) -> Response {
+    let connection = &mut establish_connection();
+
+    let result = schema::ballots::table
+        .filter(schema::ballots::poll_id.eq(poll_id).and(schema::ballots::user_id.eq(user_id)))
+        .select((schema::ballots::receipt, schema::ballots::nonce))
+        .first::<(Option<String>, Option<Vec<u8>>)>(connection);
+    let receipt = match result {
+        Err(err) => {
+            return error::db_get(err, "ballot").into_response();
+        },
+        // ballots cast before receipts existed don't have one
+        Ok((Some(receipt), Some(nonce))) => voting::Receipt { receipt, nonce: hex::encode(nonce) },
+        Ok(_) => {
+            return error::not_found("receipt").into_response();
+        },
+    };
+
+    let result = schema::pollresults::table
+        .find(poll_id)
+        .select(schema::pollresults::merkle_root)
+        .first::<Option<String>>(connection)
+        .optional();
+    let merkle_root = match result {
+        Err(err) => {
+            return error::internal(err).into_response();
+        },
+        Ok(root) => root.flatten(),
+    };
+
+    let proof = match &merkle_root {
+        None => None,
+        Some(_) => match result_api::get_counted(connection, &poll_id) {
+            Err(err) => {
+                return err.into_response();
+            },
+            Ok(ballots) => voting::merkle_proof(&ballots, &receipt.receipt),
+        },
+    };
+
+    reply::json(&ReceiptProof { receipt, merkle_root, proof }).into_response()
+}
+
+/// Every ranking the user has given in the poll, oldest first, including withdrawals
+pub fn revisions(poll_id: Uuid, user_id: Uuid) -> Response {
+    let connection = &mut establish_connection();
+    let result: Result<Vec<models::BallotRevision>, DbError> = schema::ballotrevisions::table
+        .filter(schema::ballotrevisions::poll_id.eq(poll_id).and(schema::ballotrevisions::user_id.eq(user_id)))
+        .order(schema::ballotrevisions::revision)
+        .select(models::BallotRevision::as_select())
+        .load(connection);
+
+    match result {
+        Err(err) => error::internal(err).into_response(),
+        Ok(revisions) if revisions.is_empty() => error::not_found("ballot").into_response(),
+        Ok(revisions) => reply::json(&revisions).into_response(),
+    }
+}
+
+/// The revision of a ballot, as an entity tag
+fn etag(ballot: &voting::Ballot) -> String {
+    format!("\"{}\"", ballot.revision)
+}
+
+/// The revision of the ballot a request expects to change, from its `If-Match` header. Any
+/// revision matches `*`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IfMatch(Option<i32>);
+
+impl FromStr for IfMatch {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "*" {
+            return Ok(Self(None));
+        }
+        let tag = s.strip_prefix("W/").unwrap_or(s);
+        let tag = tag.strip_prefix('"').and_then(|t| t.strip_suffix('"')).unwrap_or(tag);
+        tag.parse().map(|revision| Self(Some(revision)))
+    }
+}
+
+/// What is being done to a voter's ballot, which the poll's settings may not allow
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BallotChange {
+    Cast,
+    Replace,
+    Withdraw,
+}
+
+/// Check that the poll is open and allows the change, keeping it from closing or changing its
+/// ballot policy until the transaction ends
+fn lock_poll(
+    connection: &mut PgConnection, poll_id: &Uuid, change: BallotChange
+) -> Result<Result<(), error::HttpError>, DbError> {
+    let poll: Option<(Option<NaiveDateTime>, bool, bool)> = schema::polls::table
+        .find(poll_id)
+        .select((
+            schema::polls::closed_at,
+            schema::polls::ballot_changes_allowed,
+            schema::polls::ballot_withdrawals_allowed,
+        ))
+        .for_share()
+        .first(connection)
+        .optional()?;
+
+    Ok(match (poll, change) {
+        (None, _) => Err(error::not_found("poll")),
+        (Some((Some(_), _, _)), _) => Err(error::poll_closed()),
+        (Some((_, false, _)), BallotChange::Replace) => Err(error::ballot_changes_not_allowed()),
+        (Some((_, _, false)), BallotChange::Withdraw) => Err(error::ballot_withdrawals_not_allowed()),
+        (Some(_), _) => Ok(()),
+    })
+}
+
+/// Lock the user's ballot in the poll until the end of the transaction, so that changes to it are
+/// made one after another, returning its id. It can't be changed if it isn't at the expected revision.
+fn lock_ballot(
+    connection: &mut PgConnection, poll_id: &Uuid, user_id: &Uuid, expected_revision: Option<i32>
+) -> Result<Result<i32, error::HttpError>, DbError> {
+    let ballot: Option<(i32, i32)> = schema::ballots::table
+        .filter(schema::ballots::poll_id.eq(poll_id).and(schema::ballots::user_id.eq(user_id)))
+        .select((schema::ballots::id, schema::ballots::revision))
+        .for_update()
+        .first(connection)
+        .optional()?;
+
+    Ok(match (ballot, expected_revision) {
+        (None, _) => Err(error::not_found("ballot")),
+        (Some((_, actual)), Some(expected)) if actual != expected => Err(error::ballot_revision_mismatch(expected, actual)),
+        (Some((ballot_id, _)), _) => Ok(ballot_id),
+    })
+}
+
+/// The number of the user's next revision in the poll, after all those of ballots they withdrew
+fn next_revision(connection: &mut PgConnection, poll_id: &Uuid, user_id: &Uuid) -> Result<i32, DbError> {
+    let last: Option<i32> = schema::ballotrevisions::table
+        .filter(schema::ballotrevisions::poll_id.eq(poll_id).and(schema::ballotrevisions::user_id.eq(user_id)))
+        .select(diesel::dsl::max(schema::ballotrevisions::revision))
+        .first(connection)?;
+    Ok(last.unwrap_or(0) + 1)
+}
+
+/// Keep a ranking the user has given as a revision, or none when they withdraw their ballot
+fn add_revision(
+    connection: &mut PgConnection,
+    poll_id: &Uuid,
+    user_id: &Uuid,
+    revision: i32,
+    ranked_preferences: Option<&[voting::WeakId]>,
+    receipt: Option<&str>,
+) -> Result<(), DbError> {
+    diesel::insert_into(schema::ballotrevisions::table)
+        .values(models::CreateBallotRevision {
+            poll_id: *poll_id,
+            user_id: *user_id,
+            revision,
+            ranked_preferences: ranked_preferences.map(|prefs| prefs.iter().map(|id| id.0 as i32).collect()),
+            receipt: receipt.map(String::from),
+        })
+        .execute(connection)?;
+    Ok(())
+}
+
+/// The user's ballot in the poll, if they have cast one
+pub fn find(
+    connection: &mut PgConnection, poll_id: &Uuid, user_id: &Uuid
+) -> Result<Option<voting::Ballot>, error::HttpError> {
+    match get_internal(connection, poll_id, user_id) {
+        Err(err) if err.status == StatusCode::NOT_FOUND => Ok(None),
+        Err(err) => Err(err),
+        Ok(b) => Ok(Some(b)),
+    }
+}
+
+pub fn get_internal(
     connection: &mut PgConnection, poll_id: &Uuid, user_id: &Uuid
-) -> Result<voting::Ballot, error::HttpGetError> {
+) -> Result<voting::Ballot, error::HttpError> {
     // fetch ballot from db
     let ballot_result: Result<(models::Ballot, models::User), DbError> =
         schema::ballots::table.filter(
@@ -207,11 +478,8 @@ fn get_internal(
         .first(connection);
 
     let (db_ballot, db_user) = match ballot_result {
-        Err(err @ DbError::NotFound) => {
-            return Err(error::db_get(err, StatusCode::NOT_FOUND, "ballot/voter", None));
-        }
         Err(err) => {
-            return Err(error::db_get(err, StatusCode::INTERNAL_SERVER_ERROR, "ballot/voter", None));
+            return Err(error::db_get(err, "ballot"));
         },
         Ok(r) => r,
     };
@@ -223,19 +491,72 @@ fn get_internal(
 
     let db_votes = match votes_result {
         Err(err) => {
-            return Err(error::db_get(err, StatusCode::INTERNAL_SERVER_ERROR, "vote", Some("ballot")));
+            return Err(error::db_get(err, "vote"));
         },
         Ok(v) => v,
     };
 
     let poll = get_poll(connection, poll_id)?;
 
+    // what was stored was validated, so a ballot that no longer is means the data is broken
     let ballot = match (db_ballot, db_votes, db_user, poll).try_into() {
         Err(err) => {
-            return Err(error::HttpGetError::from(err));
+            return Err(error::stored_data_invalid(err));
         },
         Ok(b) => b,
     };
 
     Ok(ballot)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::poll_api;
+    use super::super::testing::TestPoll;
+
+    #[test]
+    fn replacing_a_stale_revision_fails() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!("\"3\"".parse(), Ok(IfMatch(Some(3))));
+        assert_eq!("W/\"3\"".parse(), Ok(IfMatch(Some(3))));
+        assert_eq!("*".parse(), Ok(IfMatch(None)));
+        assert!("\"three\"".parse::<IfMatch>().is_err());
+
+        let connection = &mut establish_connection();
+        let test_poll = TestPoll::create(voting::CreatePollSettings {
+            title: String::from("Revision test"),
+            options: vec![String::from("A"), String::from("B")],
+            ..voting::CreatePollSettings::default()
+        });
+        let (poll_id, owner_id) = (test_poll.id(), test_poll.owner_id);
+        let ballot = |preferences: Vec<u32>| voting::UnvalidatedCreateBallot {
+            ranked_preferences: preferences.into_iter().map(voting::WeakId).collect(),
+        }.validate(test_poll.poll.clone());
+
+        let (db_ballot, _) = insert(connection, &poll_id, &owner_id, &ballot(vec![0, 1])?)?;
+        assert_eq!(db_ballot.revision, 1);
+        replace(connection, &poll_id, &owner_id, &ballot(vec![1, 0])?, Some(1))?;
+        let stale = replace(connection, &poll_id, &owner_id, &ballot(vec![1])?, Some(1)).unwrap_err();
+        assert_eq!(stale.status, StatusCode::PRECONDITION_FAILED, "Another device changed the ballot first");
+        replace(connection, &poll_id, &owner_id, &ballot(vec![1])?, None)?;
+
+        let stored = get_internal(connection, &poll_id, &owner_id)?;
+        assert_eq!(stored.revision, 3);
+        assert_eq!(stored.ranked_preferences, vec![voting::WeakId(1)], "Preferences left over are removed");
+
+        let locked = voting::UpdatePollSettings { ballot_changes_allowed: Some(false), ..Default::default() };
+        let res = poll_api::update(poll_id, owner_id, locked);
+        assert_eq!(res.status(), StatusCode::CONFLICT, "The ballot policy stays as voters were shown it");
+        let unchanged = voting::UpdatePollSettings { ballot_changes_allowed: Some(true), ..Default::default() };
+        assert_eq!(poll_api::update(poll_id, owner_id, unchanged).status(), StatusCode::OK);
+
+        diesel::update(schema::polls::table.find(poll_id))
+            .set(schema::polls::closed_at.eq(chrono::Utc::now().naive_utc()))
+            .execute(connection)?;
+        let closed = replace(connection, &poll_id, &owner_id, &ballot(vec![0])?, None).unwrap_err();
+        assert_eq!(closed.status, StatusCode::CONFLICT, "Ballots of closed polls are final");
+        let late = insert(connection, &poll_id, &Uuid::new_v4(), &ballot(vec![0])?).err().unwrap();
+        assert_eq!(late.status, StatusCode::CONFLICT, "Closed polls take no new ballots");
+        Ok(())
+    }
+}
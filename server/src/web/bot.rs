@@ -0,0 +1,503 @@
+mod activity;
+mod auth;
+mod connector;
+
+use std::convert::Infallible;
+use std::env;
+use std::sync::Arc;
+
+use diesel::PgConnection;
+use serde::Deserialize;
+use tokio::task;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reply::{self, Reply, Response};
+
+use crate::error;
+use crate::voting;
+use super::card_api::{self, CardView};
+use super::cards;
+use super::db::{establish_connection, models};
+use super::poll_api::{self, get_internal as get_poll};
+use super::ballot_api;
+use super::rate_limit::{Bucket, RateLimiter};
+pub use activity::{Activity, ChannelAccount, ConversationReference};
+use activity::{
+    AdaptiveCardAction, AdaptiveCardInvoke, AdaptiveCardInvokeResponse,
+    Attachment, MessagingExtensionAction, MessagingExtensionResponse, MessagingExtensionResult,
+};
+pub use connector::Credentials;
+
+const DEFAULT_OPENID_METADATA_URL: &str = "https://login.botframework.com/v1/.well-known/openidconfiguration";
+const DEFAULT_TOKEN_URL: &str = "https://login.microsoftonline.com/botframework.com/oauth2/v2.0/token";
+
+const CREATE_POLL_COMMAND: &str = "createPoll";
+
+/// The Teams bot, which talks to Teams through the Bot Framework
+pub struct Bot {
+    auth: auth::Authenticator,
+    connector: connector::Connector,
+}
+
+impl Bot {
+    pub fn new(
+        credentials: Option<Credentials>, openid_metadata_url: String, token_url: String, connector_url: Option<String>
+    ) -> Self {
+        Self {
+            auth: auth::Authenticator::new(credentials.as_ref().map(|c| c.app_id.clone()), openid_metadata_url),
+            connector: connector::Connector::new(credentials, token_url, connector_url),
+        }
+    }
+
+    /// Accept activities without a token when the bot has no credentials, as the Bot Framework
+    /// emulators expect. Only suitable for local testing.
+    pub fn allow_unauthenticated(mut self) -> Self {
+        self.auth.allow_unauthenticated = true;
+        self
+    }
+
+    /// Configure the bot from the `BOT_ID` and `BOT_PASSWORD` of its app registration. Without them
+    /// every activity is refused, unless `BOT_ALLOW_UNAUTHENTICATED` is `true` for local testing.
+    /// `BOT_CONNECTOR_URL` sends all of the bot's messages to that connector instead of the one Teams
+    /// asks for.
+    pub fn from_env() -> Self {
+        let credentials = match (env::var("BOT_ID"), env::var("BOT_PASSWORD")) {
+            (Ok(app_id), Ok(password)) if !app_id.is_empty() => Some(Credentials { app_id, password }),
+            _ => None,
+        };
+        let allow_unauthenticated = env::var("BOT_ALLOW_UNAUTHENTICATED").as_deref() == Ok("true");
+        match (&credentials, allow_unauthenticated) {
+            (Some(_), _) => {},
+            (None, true) => println!("BOT_ID and BOT_PASSWORD are not set, bot requests will not be authenticated"),
+            (None, false) => println!("BOT_ID and BOT_PASSWORD are not set, bot requests will be refused"),
+        }
+
+        let bot = Self::new(
+            credentials,
+            env::var("BOT_OPENID_METADATA_URL").unwrap_or_else(|_| String::from(DEFAULT_OPENID_METADATA_URL)),
+            env::var("BOT_TOKEN_URL").unwrap_or_else(|_| String::from(DEFAULT_TOKEN_URL)),
+            env::var("BOT_CONNECTOR_URL").ok().filter(|url| !url.is_empty()),
+        );
+        if allow_unauthenticated { bot.allow_unauthenticated() } else { bot }
+    }
+
+    /// Post a card to a conversation without being asked to
+    pub async fn send_card(&self, reference: &ConversationReference, card: serde_json::Value) -> Result<(), reqwest::Error> {
+        self.connector.send(reference, Activity::card(card)).await
+    }
+
+    /// Post a message to a conversation without being asked to
+    pub async fn send(&self, reference: &ConversationReference, activity: Activity) -> Result<(), reqwest::Error> {
+        self.connector.send(reference, activity).await
+    }
+
+    pub async fn conversation_members(&self, reference: &ConversationReference) -> Result<Vec<ChannelAccount>, reqwest::Error> {
+        self.connector.members(reference).await
+    }
+}
+
+/// The fields of the "create poll" messaging extension form. Options are entered one per line.
+#[derive(Debug, Deserialize)]
+struct CreatePollForm {
+    title: String,
+    options: String,
+    winner_count: Option<String>,
+}
+
+impl From<CreatePollForm> for voting::UnvalidatedCreatePollSettings {
+    fn from(form: CreatePollForm) -> Self {
+        let mut settings = voting::UnvalidatedCreatePollSettings {
+            title: form.title,
+            options: form.options.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(String::from)
+                .collect(),
+            ..Default::default()
+        };
+        if let Some(count) = form.winner_count.filter(|c| !c.trim().is_empty()) {
+            // anything unparseable is out of range, and reported as such by validation
+            settings.winner_count = count.trim().parse().unwrap_or(0);
+        }
+        settings
+    }
+}
+
+/// Handle an activity posted to the bot's messaging endpoint
+pub async fn messages(
+    authorization: Option<String>, activity: Activity, bot: Arc<Bot>, limiter: Arc<RateLimiter>
+) -> Result<Response, Infallible> {
+    if let Err(err) = bot.auth.authenticate(authorization.as_deref(), &activity).await {
+        return Ok(error::unauthorized(err).into_response());
+    }
+
+    let response = match (activity.kind.as_str(), activity.name.as_deref()) {
+        ("invoke", Some("composeExtension/submitAction")) => submit_action(activity, limiter).await,
+        ("invoke", Some("adaptiveCard/action")) => card_action(activity, limiter).await,
+        // other invokes expect a response, and this tells Teams the bot doesn't support them
+        ("invoke", _) => reply::with_status(reply::reply(), StatusCode::NOT_IMPLEMENTED).into_response(),
+        ("message", _) => {
+            let help = Activity::message(String::from(
+                "Create a poll from the compose box or a message's actions menu, then vote on it right in the card."
+            ));
+            if let Err(err) = bot.connector.reply(&activity, help).await {
+                println!("Failed to reply to message: {err}");
+            }
+            reply::reply().into_response()
+        },
+        _ => reply::reply().into_response(),
+    };
+    Ok(response)
+}
+
+async fn submit_action(activity: Activity, limiter: Arc<RateLimiter>) -> Response {
+    let action: MessagingExtensionAction = match serde_json::from_value(activity.value.clone().unwrap_or_default()) {
+        Err(err) => return error::malformed_body(err).into_response(),
+        Ok(action) => action,
+    };
+    if action.command_id != CREATE_POLL_COMMAND {
+        return reply::with_status(reply::reply(), StatusCode::NOT_IMPLEMENTED).into_response();
+    }
+
+    let result = match serde_json::from_value::<CreatePollForm>(action.data) {
+        Err(_) => MessagingExtensionResult::Message { text: String::from("The poll form is missing its title or options.") },
+        Ok(form) => match owner(&activity) {
+            None => MessagingExtensionResult::Message { text: String::from("Polls can only be created by signed-in users.") },
+            Some(owner) => {
+                if let Err(err) = limiter.check_user(Bucket::PollCreation, &owner.id) {
+                    return err.into_response();
+                }
+                let settings = voting::UnvalidatedCreatePollSettings::from(form);
+                let reference = activity.conversation_reference();
+                match task::spawn_blocking(move || create_poll(owner, settings, reference)).await {
+                    Err(err) => {
+                        println!("Poll creation panicked: {err}");
+                        return reply::with_status(reply::reply(), StatusCode::INTERNAL_SERVER_ERROR).into_response();
+                    },
+                    Ok(Err(err)) => return err.into_response(),
+                    Ok(Ok(result)) => result,
+                }
+            },
+        },
+    };
+
+    reply::json(&MessagingExtensionResponse { compose_extension: result }).into_response()
+}
+
+/// Teams identifies users by their Azure AD object ID, which is what the poll owner is keyed by
+fn owner(activity: &Activity) -> Option<models::User> {
+    let from = activity.from.as_ref()?;
+    let id = Uuid::parse_str(from.aad_object_id.as_ref()?).ok()?;
+    Some(models::User { id, display_name: from.name.clone().unwrap_or_else(|| String::from("Anonymous")) })
+}
+
+/// Create the poll, showing the user what needs fixing if the form isn't valid
+fn create_poll(
+    owner: models::User, settings: voting::UnvalidatedCreatePollSettings, reference: Option<ConversationReference>
+) -> Result<MessagingExtensionResult, error::HttpError> {
+    let settings = match voting::CreatePollSettings::try_from(settings) {
+        Err(errors) => {
+            let text = errors.iter()
+                .map(|err| format!("- {}", err.kind()))
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Ok(MessagingExtensionResult::Message { text: format!("The poll can't be created:\n{text}") });
+        },
+        Ok(s) => s,
+    };
+
+    let connection = &mut establish_connection();
+    let reference = reference.map(|r| serde_json::to_value(r).expect("Conversation references are always serializable"));
+    let poll = poll_api::create(connection, owner, settings, reference)?;
+
+    Ok(MessagingExtensionResult::Result {
+        attachment_layout: "list",
+        attachments: vec![Attachment::adaptive_card(cards::poll_card(&poll))],
+    })
+}
+
+/// Handle an `Action.Execute` from one of the poll cards, answering with the card the user should
+/// see in its place
+async fn card_action(activity: Activity, limiter: Arc<RateLimiter>) -> Response {
+    let invoke: AdaptiveCardInvoke = match serde_json::from_value(activity.value.clone().unwrap_or_default()) {
+        Err(err) => return error::malformed_body(err).into_response(),
+        Ok(invoke) => invoke,
+    };
+    let poll_id = invoke.action.data.get("poll_id")
+        .and_then(|id| id.as_str())
+        .and_then(|id| Uuid::parse_str(id).ok());
+    let Some(poll_id) = poll_id else {
+        let response = AdaptiveCardInvokeResponse::error(400, "BadRequest", String::from("The card has no poll"));
+        return reply::json(&response).into_response();
+    };
+    let Some(user) = owner(&activity) else {
+        let response = AdaptiveCardInvokeResponse::error(401, "Unauthorized", String::from("Only signed-in users can vote"));
+        return reply::json(&response).into_response();
+    };
+    let teams_user_id = activity.from.map(|from| from.id).unwrap_or_default();
+
+    let result = task::spawn_blocking(move || {
+        let connection = &mut establish_connection();
+        run_card_action(connection, &limiter, &poll_id, user, &teams_user_id, invoke.action)
+    }).await;
+
+    let response = match result {
+        Err(err) => {
+            println!("Card action panicked: {err}");
+            AdaptiveCardInvokeResponse::error(500, "InternalServerError", String::from("Something went wrong"))
+        },
+        Ok(Err(err)) => {
+            if err.status.is_server_error() {
+                println!("{err}");
+            }
+            let code = err.status.canonical_reason().unwrap_or("Error").replace(' ', "");
+            AdaptiveCardInvokeResponse::error(err.status.as_u16(), &code, err.message())
+        },
+        Ok(Ok(card)) => AdaptiveCardInvokeResponse::card(card),
+    };
+    reply::json(&response).into_response()
+}
+
+fn run_card_action(
+    connection: &mut PgConnection,
+    limiter: &RateLimiter,
+    poll_id: &Uuid,
+    user: models::User,
+    teams_user_id: &str,
+    action: AdaptiveCardAction,
+) -> Result<serde_json::Value, error::HttpError> {
+    match action.verb.as_str() {
+        cards::VERB_SHOW_RESULT => {
+            limiter.check_user(Bucket::ResultRead, &user.id)?;
+            card_api::render(connection, poll_id, Some(&user.id), CardView::Result)
+        },
+        cards::VERB_SHOW_BALLOT => {
+            let card = card_api::render(connection, poll_id, Some(&user.id), CardView::Ballot)?;
+            let poll = get_poll(connection, poll_id)?;
+            Ok(cards::with_refresh(card, cards::VERB_SHOW_BALLOT, &poll, &[teams_user_id.to_string()]))
+        },
+        cards::VERB_VOTE => {
+            limiter.check_user(Bucket::BallotSubmission, &user.id)?;
+            let (poll, notice) = vote(connection, poll_id, &user, &action.data)?;
+            let ballot = ballot_api::find(connection, poll_id, &user.id)?;
+            let card = cards::ballot_card(&poll, ballot.as_ref());
+            let card = cards::with_refresh(card, cards::VERB_SHOW_BALLOT, &poll, &[teams_user_id.to_string()]);
+            Ok(match notice {
+                Err(text) => cards::with_notice(card, &text, true),
+                Ok(text) => cards::with_notice(card, text, false),
+            })
+        },
+        _ => Err(error::malformed_body(format!("unknown card action '{}'", action.verb))),
+    }
+}
+
+/// Cast or replace the user's ballot from the rank inputs of a ballot card, returning the poll and
+/// a notice for the user about how it went
+fn vote(
+    connection: &mut PgConnection, poll_id: &Uuid, user: &models::User, data: &serde_json::Map<String, serde_json::Value>
+) -> Result<(voting::Poll, Result<&'static str, String>), error::HttpError> {
+    let poll = get_poll(connection, poll_id)?;
+    if poll.closed_at.is_some() {
+        return Ok((poll, Err(String::from("This poll closed before your vote could be counted."))));
+    }
+
+    let ballot = voting::UnvalidatedCreateBallot { ranked_preferences: rank_preferences(data) };
+    let ballot = match ballot.validate(poll.clone()) {
+        Err(errors) => {
+            let text = errors.iter()
+                .map(|err| format!("- {}", err.kind()))
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Ok((poll, Err(format!("Your vote wasn't counted:\n{text}"))));
+        },
+        Ok(b) => b,
+    };
+
+    let user_id = user.id;
+    ballot_api::add_user(connection, user)?;
+    if ballot_api::find(connection, poll_id, &user_id)?.is_some() {
+        if !poll.ballot_changes_allowed {
+            return Ok((poll, Err(String::from("Your vote was already counted, and votes can't be changed in this poll."))));
+        }
+        ballot_api::replace(connection, poll_id, &user_id, &ballot, None)?;
+        Ok((poll, Ok("Your vote has been updated.")))
+    }
+    else {
+        ballot_api::insert(connection, poll_id, &user_id, &ballot)?;
+        Ok((poll, Ok("Your vote has been counted.")))
+    }
+}
+
+/// The chosen option of every rank input, in rank order. Ranks left without a preference are skipped.
+fn rank_preferences(data: &serde_json::Map<String, serde_json::Value>) -> Vec<voting::WeakId> {
+    let mut ranks: Vec<(usize, &str)> = data.iter()
+        .filter_map(|(key, value)| {
+            let index = key.strip_prefix("rank_")?.parse().ok()?;
+            let value = value.as_str().filter(|v| !v.is_empty())?;
+            Some((index, value))
+        })
+        .collect();
+    ranks.sort();
+
+    ranks.into_iter()
+        // an unparseable choice is reported as an option that isn't in the poll
+        .map(|(_, value)| voting::WeakId(value.parse().unwrap_or(u32::MAX)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    use super::*;
+    use super::super::testing::TestPoll;
+
+    fn test_bot() -> Arc<Bot> {
+        Arc::new(Bot::new(None, String::new(), String::new(), None).allow_unauthenticated())
+    }
+
+    fn unlimited() -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(HashMap::new(), 1, 0, 1))
+    }
+
+    fn invoke(data: serde_json::Value) -> Activity {
+        serde_json::from_value(json!({
+            "type": "invoke",
+            "name": "composeExtension/submitAction",
+            "from": { "id": "29:1", "name": "Test User", "aadObjectId": Uuid::new_v4() },
+            "value": { "commandId": CREATE_POLL_COMMAND, "data": data },
+        })).unwrap()
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        let bytes = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn message_replies_through_connector() {
+        // stand in for the Bot Connector service, capturing what the bot sends it
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let mock_connector = warp::path!("v3" / "conversations" / String / "activities" / String)
+            .and(warp::body::json::<serde_json::Value>())
+            .map(move |conversation, reply_to, body| {
+                sender.send((conversation, reply_to, body)).unwrap();
+                warp::reply::json(&json!({ "id": "reply" }))
+            });
+        let (address, server) = warp::serve(mock_connector).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let activity = serde_json::from_value(json!({
+            "type": "message",
+            "id": "message-1",
+            "serviceUrl": format!("http://{address}/"),
+            "from": { "id": "29:1" },
+            "recipient": { "id": "28:bot" },
+            "conversation": { "id": "conversation-1" },
+            "text": "hello",
+        })).unwrap();
+        let response = messages(None, activity, test_bot(), unlimited()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (conversation, reply_to, body) = received.recv().await.unwrap();
+        assert_eq!(conversation, "conversation-1");
+        assert_eq!(reply_to, "message-1");
+        assert_eq!(body["type"], "message");
+        assert_eq!(body["recipient"]["id"], "29:1");
+    }
+
+    #[tokio::test]
+    async fn unconfigured_bots_refuse_activities() {
+        let bot = Arc::new(Bot::new(None, String::new(), String::new(), None));
+        let activity = invoke(json!({ "title": "Lunch", "options": "Pizza\nTacos\n" }));
+        let response = messages(None, activity, bot, unlimited()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn submit_action_reports_invalid_polls() {
+        let activity = invoke(json!({ "title": "no", "options": "only one\n\n", "winner_count": "many" }));
+        let response = messages(None, activity, test_bot(), unlimited()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body(response).await;
+        assert_eq!(body["composeExtension"]["type"], "message");
+        let text = body["composeExtension"]["text"].as_str().unwrap();
+        assert_eq!(text.lines().count(), 4, "Every problem should be listed: {text}");
+    }
+
+    #[tokio::test]
+    async fn submit_action_creates_poll() {
+        let activity = invoke(json!({ "title": "Lunch", "options": "Pizza\nTacos\nSushi\n", "winner_count": "1" }));
+        let owner_id = Uuid::parse_str(activity.from.as_ref().unwrap().aad_object_id.as_ref().unwrap()).unwrap();
+        let response = messages(None, activity, test_bot(), unlimited()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body(response).await;
+        let result = &body["composeExtension"];
+        assert_eq!(result["type"], "result");
+        assert_eq!(result["attachments"][0]["contentType"], activity::ADAPTIVE_CARD_CONTENT_TYPE);
+        assert_eq!(result["attachments"][0]["content"]["body"][0]["text"], "Lunch");
+
+        // clean up
+        task::spawn_blocking(move || {
+            use diesel::prelude::*;
+            use super::super::db::schema;
+
+            let connection = &mut establish_connection();
+            diesel::delete(schema::polls::table.filter(schema::polls::owner_id.eq(owner_id)))
+                .execute(connection).unwrap();
+            diesel::delete(schema::users::table.find(owner_id)).execute(connection).unwrap();
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn card_action_casts_and_updates_vote() {
+        let (test_poll, voter_id) = task::spawn_blocking(move || {
+            let settings = voting::CreatePollSettings::try_from(voting::UnvalidatedCreatePollSettings {
+                title: String::from("Lunch"),
+                options: vec![String::from("Pizza"), String::from("Tacos"), String::from("Sushi")],
+                ..Default::default()
+            }).unwrap();
+            let mut test_poll = TestPoll::create(settings);
+            let voter_id = test_poll.add_voter(&mut establish_connection());
+            (test_poll, voter_id)
+        }).await.unwrap();
+        let poll = test_poll.poll.clone();
+
+        let action = |data: serde_json::Value| serde_json::from_value::<Activity>(json!({
+            "type": "invoke",
+            "name": "adaptiveCard/action",
+            "from": { "id": "29:voter", "name": "Voter", "aadObjectId": voter_id },
+            "value": { "action": { "type": "Action.Execute", "verb": cards::VERB_VOTE, "data": data } },
+        })).unwrap();
+
+        // ranks left empty are skipped
+        let data = json!({ "poll_id": poll.id, "rank_0": "2", "rank_1": "", "rank_2": "0" });
+        let reply = body(messages(None, action(data), test_bot(), unlimited()).await.unwrap()).await;
+        assert_eq!(reply["statusCode"], 200);
+        assert_eq!(reply["type"], activity::ADAPTIVE_CARD_CONTENT_TYPE);
+        let card = &reply["value"];
+        assert_eq!(card["body"][1]["text"], "Your vote has been counted.");
+        assert_eq!(card["refresh"]["userIds"], json!(["29:voter"]));
+        assert_eq!(card["actions"][0]["title"], "Update vote");
+
+        let data = json!({ "poll_id": poll.id, "rank_0": "1", "rank_1": "1" });
+        let reply = body(messages(None, action(data), test_bot(), unlimited()).await.unwrap()).await;
+        assert!(reply["value"]["body"][1]["text"].as_str().unwrap().starts_with("Your vote wasn't counted"));
+
+        let data = json!({ "poll_id": poll.id, "rank_0": "1" });
+        let reply = body(messages(None, action(data), test_bot(), unlimited()).await.unwrap()).await;
+        assert_eq!(reply["value"]["body"][1]["text"], "Your vote has been updated.");
+
+        let ballot = task::spawn_blocking(move || {
+            let ballot = ballot_api::find(&mut establish_connection(), &poll.id.0, &voter_id).unwrap().unwrap();
+            drop(test_poll);
+            ballot
+        }).await.unwrap();
+        assert_eq!(ballot.ranked_preferences, [voting::WeakId(1)]);
+    }
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+
+pub const ADAPTIVE_CARD_CONTENT_TYPE: &str = "application/vnd.microsoft.card.adaptive";
+pub const ERROR_CONTENT_TYPE: &str = "application/vnd.microsoft.error";
+
+/// The parts of a Bot Framework activity that the bot uses. Unknown fields are ignored.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Activity {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<ChannelAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<ChannelAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<ConversationAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entities: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+}
+
+impl Activity {
+    pub fn message(text: String) -> Self {
+        Self { kind: String::from("message"), text: Some(text), ..Default::default() }
+    }
+
+    pub fn card(card: serde_json::Value) -> Self {
+        Self { kind: String::from("message"), attachments: vec![Attachment::adaptive_card(card)], ..Default::default() }
+    }
+
+    /// A message sent back to the conversation this activity came from
+    pub fn reply(&self, mut reply: Activity) -> Activity {
+        reply.from = self.recipient.clone();
+        reply.recipient = self.from.clone();
+        reply.conversation = self.conversation.clone();
+        reply.reply_to_id = self.id.clone();
+        reply
+    }
+
+    /// Where the bot can message the conversation this activity came from later on
+    pub fn conversation_reference(&self) -> Option<ConversationReference> {
+        Some(ConversationReference {
+            service_url: self.service_url.clone()?,
+            channel_id: self.channel_id.clone(),
+            conversation: self.conversation.clone()?,
+            bot: self.recipient.clone(),
+        })
+    }
+}
+
+/// Everything needed to send a proactive message to a conversation
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationReference {
+    pub service_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    pub conversation: ConversationAccount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bot: Option<ChannelAccount>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelAccount {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aad_object_id: Option<String>,
+    /// Only known for the members of a Teams conversation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationAccount {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub content_type: String,
+    pub content: serde_json::Value,
+}
+
+impl Attachment {
+    pub fn adaptive_card(card: serde_json::Value) -> Self {
+        Self { content_type: String::from(ADAPTIVE_CARD_CONTENT_TYPE), content: card }
+    }
+}
+
+/// The value of a `composeExtension/submitAction` invoke
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagingExtensionAction {
+    pub command_id: String,
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+/// The body of the response to a messaging extension invoke
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagingExtensionResponse {
+    pub compose_extension: MessagingExtensionResult,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum MessagingExtensionResult {
+    /// Cards to insert into the compose box
+    #[serde(rename_all = "camelCase")]
+    Result { attachment_layout: &'static str, attachments: Vec<Attachment> },
+    /// Text to show the user instead of a result
+    Message { text: String },
+}
+
+/// The value of an `adaptiveCard/action` invoke, sent when a user triggers an `Action.Execute`
+#[derive(Debug, Deserialize)]
+pub struct AdaptiveCardInvoke {
+    pub action: AdaptiveCardAction,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdaptiveCardAction {
+    pub verb: String,
+    /// The action's data, merged with the card's input values
+    #[serde(default)]
+    pub data: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The body of the response to an `adaptiveCard/action` invoke
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdaptiveCardInvokeResponse {
+    pub status_code: u16,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub value: serde_json::Value,
+}
+
+impl AdaptiveCardInvokeResponse {
+    pub fn card(card: serde_json::Value) -> Self {
+        Self { status_code: 200, kind: ADAPTIVE_CARD_CONTENT_TYPE, value: card }
+    }
+
+    pub fn error(status_code: u16, code: &str, message: String) -> Self {
+        Self {
+            status_code,
+            kind: ERROR_CONTENT_TYPE,
+            value: serde_json::json!({ "code": code, "message": message }),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use super::activity::Activity;
+
+const ISSUER: &str = "https://api.botframework.com";
+
+/// Signing keys are rotated regularly, and fetched again early if an unknown key is seen
+const KEYS_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Anyone can send a token with an unknown key ID, so keys are fetched at most this often
+const KEYS_MIN_REFETCH: Duration = Duration::from_secs(60);
+
+/// Checks that activities really come from the Bot Framework, by validating the JWT it attaches
+pub struct Authenticator {
+    client: reqwest::Client,
+    app_id: Option<String>,
+    /// Whether activities are accepted without a token when there is no app ID
+    pub allow_unauthenticated: bool,
+    metadata_url: String,
+    keys: Mutex<Keys>,
+}
+
+#[derive(Default)]
+struct Keys {
+    set: Option<(JwkSet, Instant)>,
+    /// When keys were last fetched, whether or not that worked
+    attempted_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct AuthError(String);
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<reqwest::Error> for AuthError {
+    fn from(value: reqwest::Error) -> Self {
+        AuthError(format!("failed to fetch signing keys: {value}"))
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AuthError {
+    fn from(value: jsonwebtoken::errors::Error) -> Self {
+        AuthError(format!("invalid token: {value}"))
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenIdMetadata {
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    serviceurl: Option<String>,
+}
+
+impl Authenticator {
+    /// Without an app ID no activity is accepted, unless unauthenticated ones are allowed
+    pub fn new(app_id: Option<String>, metadata_url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            app_id,
+            allow_unauthenticated: false,
+            metadata_url,
+            keys: Mutex::new(Keys::default()),
+        }
+    }
+
+    pub async fn authenticate(&self, authorization: Option<&str>, activity: &Activity) -> Result<(), AuthError> {
+        let Some(app_id) = &self.app_id else {
+            return match self.allow_unauthenticated {
+                true => Ok(()),
+                false => Err(AuthError(String::from("bot has no credentials to authenticate activities with"))),
+            };
+        };
+
+        let token = authorization
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or_else(|| AuthError(String::from("missing bearer token")))?;
+        let kid = jsonwebtoken::decode_header(token)?.kid
+            .ok_or_else(|| AuthError(String::from("token has no key ID")))?;
+        let key = self.key(&kid).await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[app_id]);
+        validation.set_issuer(&[ISSUER]);
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)?.claims;
+
+        // a token is only good for the connector it was issued for
+        if claims.serviceurl.is_some() && claims.serviceurl != activity.service_url {
+            return Err(AuthError(String::from("token was issued for a different service URL")));
+        }
+
+        Ok(())
+    }
+
+    async fn key(&self, kid: &str) -> Result<DecodingKey, AuthError> {
+        let mut keys = self.keys.lock().await;
+
+        let stale = match keys.set.as_ref() {
+            None => true,
+            Some((set, fetched_at)) => fetched_at.elapsed() > KEYS_MAX_AGE || set.find(kid).is_none(),
+        };
+        let recently_attempted = keys.attempted_at.is_some_and(|at| at.elapsed() < KEYS_MIN_REFETCH);
+        if stale && !recently_attempted {
+            keys.attempted_at = Some(Instant::now());
+            let metadata: OpenIdMetadata = self.client.get(&self.metadata_url)
+                .send().await?.error_for_status()?
+                .json().await?;
+            let set: JwkSet = self.client.get(&metadata.jwks_uri)
+                .send().await?.error_for_status()?
+                .json().await?;
+            keys.set = Some((set, Instant::now()));
+        }
+
+        let (set, _) = keys.set.as_ref()
+            .ok_or_else(|| AuthError(String::from("signing keys are unavailable")))?;
+        let jwk = set.find(kid)
+            .ok_or_else(|| AuthError(format!("unknown signing key '{kid}'")))?;
+        Ok(DecodingKey::from_jwk(jwk)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use warp::Filter;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unknown_keys_are_refetched_at_most_once_a_minute() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counted = fetches.clone();
+        let metadata = warp::path!("metadata").map(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            warp::reply::json(&serde_json::json!({ "jwks_uri": "" }))
+        });
+        let (addr, server) = warp::serve(metadata).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let authenticator = Authenticator::new(Some(String::from("app")), format!("http://{addr}/metadata"));
+        let activity: Activity = serde_json::from_value(serde_json::json!({ "type": "message" })).unwrap();
+        for i in 0..3 {
+            let header = Header { kid: Some(format!("junk-{i}")), ..Header::default() };
+            let token = jsonwebtoken::encode(&header, &serde_json::json!({}), &EncodingKey::from_secret(b"junk")).unwrap();
+            let authorization = format!("Bearer {token}");
+            assert!(authenticator.authenticate(Some(&authorization), &activity).await.is_err());
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1, "Junk tokens don't fetch keys again");
+    }
+}
@@ -0,0 +1,128 @@
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use super::activity::{Activity, ChannelAccount, ConversationReference};
+
+const TOKEN_SCOPE: &str = "https://api.botframework.com/.default";
+
+/// Tokens are renewed this long before they expire, so one never runs out mid-request
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// The bot's app registration, used to authenticate in both directions
+#[derive(Clone)]
+pub struct Credentials {
+    pub app_id: String,
+    pub password: String,
+}
+
+/// Client for the Bot Connector service at an activity's service URL, which delivers the bot's
+/// messages to the conversation
+pub struct Connector {
+    client: reqwest::Client,
+    credentials: Option<Credentials>,
+    token_url: String,
+    service_url: Option<String>,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl Connector {
+    /// Without credentials requests are sent unauthenticated, as the Bot Framework emulators expect.
+    /// A `service_url` overrides the one of every conversation, to talk to a local mock connector.
+    pub fn new(credentials: Option<Credentials>, token_url: String, service_url: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            credentials,
+            token_url,
+            service_url,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Send a new message to a conversation the bot has been in before
+    pub async fn send(&self, reference: &ConversationReference, mut activity: Activity) -> Result<(), reqwest::Error> {
+        activity.from = reference.bot.clone();
+        activity.conversation = Some(reference.conversation.clone());
+
+        let url = format!("{}/v3/conversations/{}/activities",
+            self.service_url(&reference.service_url), reference.conversation.id);
+        self.post(url, &activity).await
+    }
+
+    /// The users in a conversation
+    pub async fn members(&self, reference: &ConversationReference) -> Result<Vec<ChannelAccount>, reqwest::Error> {
+        let url = format!("{}/v3/conversations/{}/members",
+            self.service_url(&reference.service_url), reference.conversation.id);
+
+        let mut request = self.client.get(url);
+        if let Some(token) = self.token().await? {
+            request = request.bearer_auth(token);
+        }
+        request.send().await?.error_for_status()?.json().await
+    }
+
+    /// Reply to `activity` in its conversation
+    pub async fn reply(&self, activity: &Activity, reply: Activity) -> Result<(), reqwest::Error> {
+        let (Some(service_url), Some(conversation)) = (&activity.service_url, &activity.conversation) else {
+            println!("Not replying to an activity without a conversation");
+            return Ok(());
+        };
+
+        let reply = activity.reply(reply);
+        let mut url = format!("{}/v3/conversations/{}/activities",
+            self.service_url(service_url), conversation.id);
+        if let Some(id) = &reply.reply_to_id {
+            url = format!("{url}/{id}");
+        }
+        self.post(url, &reply).await
+    }
+
+    fn service_url<'a>(&'a self, service_url: &'a str) -> &'a str {
+        self.service_url.as_deref().unwrap_or(service_url).trim_end_matches('/')
+    }
+
+    async fn post(&self, url: String, activity: &Activity) -> Result<(), reqwest::Error> {
+        let mut request = self.client.post(url).json(activity);
+        if let Some(token) = self.token().await? {
+            request = request.bearer_auth(token);
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// An access token for the connector, reusing the previous one until it is about to expire
+    async fn token(&self) -> Result<Option<String>, reqwest::Error> {
+        let Some(credentials) = &self.credentials else {
+            return Ok(None);
+        };
+
+        let mut token = self.token.lock().await;
+        if let Some((value, expires_at)) = token.as_ref() {
+            if Instant::now() + TOKEN_EXPIRY_MARGIN < *expires_at {
+                return Ok(Some(value.clone()));
+            }
+        }
+
+        let response: TokenResponse = self.client.post(&self.token_url)
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", &credentials.app_id),
+                ("client_secret", &credentials.password),
+                ("scope", TOKEN_SCOPE),
+            ])
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+
+        let expires_at = Instant::now() + Duration::from_secs(response.expires_in);
+        *token = Some((response.access_token.clone(), expires_at));
+        Ok(Some(response.access_token))
+    }
+}
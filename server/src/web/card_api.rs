@@ -0,0 +1,63 @@
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use serde::Deserialize;
+use uuid::Uuid;
+use warp::reply::{self, Reply, Response};
+
+use crate::error;
+use crate::voting;
+use super::cards;
+use super::db::establish_connection;
+use super::poll_api::get_internal as get_poll;
+use super::{ballot_api, result_api};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CardView {
+    #[default]
+    Poll,
+    Ballot,
+    Result,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CardQuery {
+    pub view: CardView,
+}
+
+/// Render the poll as an Adaptive Card. The ballot view is filled in with the user's ballot, if any.
+pub fn get_card(poll_id: Uuid, user_id: Option<Uuid>, query: CardQuery) -> Response {
+    let connection = &mut establish_connection();
+    match render(connection, &poll_id, user_id.as_ref(), query.view) {
+        Err(err) => err.into_response(),
+        Ok(card) => reply::json(&card).into_response(),
+    }
+}
+
+pub fn render(
+    connection: &mut PgConnection, poll_id: &Uuid, user_id: Option<&Uuid>, view: CardView
+) -> Result<serde_json::Value, error::HttpError> {
+    let poll = get_poll(connection, poll_id)?;
+
+    match view {
+        CardView::Poll => Ok(cards::poll_card(&poll)),
+        CardView::Ballot => {
+            let ballot = match user_id {
+                None => None,
+                Some(user_id) => ballot_api::find(connection, poll_id, user_id)?,
+            };
+            Ok(cards::ballot_card(&poll, ballot.as_ref()))
+        },
+        CardView::Result => {
+            let result = match result_api::get_visible(connection, &poll)? {
+                None => None,
+                Some(value) => match serde_json::from_value::<voting::PollResult>(value) {
+                    Err(err) => return Err(error::internal(DbError::DeserializationError(Box::new(err)))),
+                    Ok(r) => Some(r),
+                },
+            };
+            Ok(cards::result_card(&poll, result.as_ref()))
+        },
+    }
+}
@@ -0,0 +1,333 @@
+use serde_json::{json, Value};
+
+use crate::voting;
+
+const SCHEMA: &str = "http://adaptivecards.io/schemas/adaptive-card.json";
+const VERSION: &str = "1.5";
+
+/// `Action.Execute` verb that submits the ballot inputs of a ballot card
+pub const VERB_VOTE: &str = "vote";
+/// `Action.Execute` verb that replaces a card with the user's ballot card
+pub const VERB_SHOW_BALLOT: &str = "show_ballot";
+/// `Action.Execute` verb that replaces a card with the result card
+pub const VERB_SHOW_RESULT: &str = "show_result";
+
+/// Input ID of the choice set for the preference at `index`
+pub fn rank_input_id(index: usize) -> String {
+    format!("rank_{index}")
+}
+
+/// An Adaptive Card announcing a poll and listing its options
+pub fn poll_card(poll: &voting::Poll) -> Value {
+    let mut body = vec![title(poll)];
+
+    for (index, option) in options(poll).iter().enumerate() {
+        body.push(json!({ "type": "TextBlock", "text": format!("{}. {}", index + 1, option.description), "wrap": true }));
+    }
+    body.push(summary(poll));
+
+    let mut actions = vec![];
+    if poll.closed_at.is_none() {
+        actions.push(execute("Vote", VERB_SHOW_BALLOT, poll));
+    }
+    actions.push(execute("Results", VERB_SHOW_RESULT, poll));
+
+    // each viewer gets their own ballot card in place of the shared one
+    let mut card = card(body, actions);
+    card["refresh"] = json!({ "action": execute("Show my ballot", VERB_SHOW_BALLOT, poll) });
+    card
+}
+
+/// An Adaptive Card for ranking a poll's options, with one choice per rank so options can be put in
+/// any order. It starts out filled in with the user's current ballot, if they have one.
+pub fn ballot_card(poll: &voting::Poll, ballot: Option<&voting::Ballot>) -> Value {
+    let mut body = vec![title(poll)];
+    let preferences = ballot.map(|b| b.ranked_preferences.as_slice()).unwrap_or_default();
+
+    // a ballot that can't be changed is shown like one in a closed poll
+    let final_ballot = !preferences.is_empty() && !poll.ballot_changes_allowed;
+    if poll.closed_at.is_some() || final_ballot {
+        let notice = if poll.closed_at.is_some() { "This poll is closed." } else { "Your vote is counted, and can't be changed in this poll." };
+        body.push(json!({ "type": "TextBlock", "text": notice, "wrap": true, "weight": "Bolder" }));
+        if !preferences.is_empty() {
+            body.push(json!({ "type": "TextBlock", "text": "Your ballot:", "wrap": true }));
+        }
+        for (index, option_id) in preferences.iter().enumerate() {
+            body.push(json!({
+                "type": "TextBlock",
+                "text": format!("{}. {}", index + 1, description(poll, *option_id)),
+                "wrap": true,
+            }));
+        }
+        return card(body, vec![execute("Results", VERB_SHOW_RESULT, poll)]);
+    }
+
+    if !preferences.is_empty() {
+        let mut ranking = vec![
+            json!({ "type": "TextBlock", "text": "Your ranking", "weight": "Bolder" }),
+        ];
+        for (index, option_id) in preferences.iter().enumerate() {
+            ranking.push(json!({
+                "type": "TextBlock",
+                "text": format!("{}. {}", index + 1, description(poll, *option_id)),
+                "wrap": true,
+                "spacing": "None",
+            }));
+        }
+        body.push(json!({ "type": "Container", "style": "emphasis", "items": ranking }));
+    }
+
+    let choices: Vec<Value> = options(poll).iter()
+        .map(|o| json!({ "title": o.description, "value": o.id.0.to_string() }))
+        .collect();
+    for index in 0..choices.len() {
+        let mut input = json!({
+            "type": "Input.ChoiceSet",
+            "id": rank_input_id(index),
+            "label": format!("{} choice", ordinal(index + 1)),
+            "style": "compact",
+            "placeholder": "No preference",
+            "choices": choices,
+            // only the first preference is needed, the rest are optional
+            "isRequired": index == 0,
+            "errorMessage": "Choose at least your first preference",
+        });
+        if let Some(option_id) = preferences.get(index) {
+            input["value"] = json!(option_id.0.to_string());
+        }
+        body.push(input);
+    }
+    body.push(summary(poll));
+
+    let submit = if preferences.is_empty() { "Submit vote" } else { "Update vote" };
+    let mut vote = execute(submit, VERB_VOTE, poll);
+    vote["style"] = json!("positive");
+    vote["associatedInputs"] = json!("auto");
+
+    card(body, vec![vote, execute("Results", VERB_SHOW_RESULT, poll)])
+}
+
+/// An Adaptive Card with the poll's winners and a bar per option's final vote count, or a
+/// placeholder if there aren't enough votes to show a result yet
+pub fn result_card(poll: &voting::Poll, result: Option<&voting::PollResult>) -> Value {
+    let mut body = vec![title(poll)];
+    let mut actions = vec![];
+    if poll.closed_at.is_none() {
+        actions.push(execute("Vote", VERB_SHOW_BALLOT, poll));
+    }
+
+    let Some(result) = result else {
+        body.push(json!({ "type": "TextBlock", "text": "Not enough votes have been cast to show a result yet.", "wrap": true }));
+        return card(body, actions);
+    };
+
+    // winners banner
+    let winners: Vec<&str> = result.winners.iter().map(|id| description(poll, *id)).collect();
+    let heading = match (poll.closed_at.is_some(), winners.len()) {
+        (true, 1) => "Winner",
+        (true, _) => "Winners",
+        (false, 1) => "Leading",
+        (false, _) => "Leading options",
+    };
+    body.push(json!({
+        "type": "Container",
+        "style": "good",
+        "bleed": true,
+        "items": [
+            { "type": "TextBlock", "text": heading, "size": "Small", "isSubtle": true },
+            { "type": "TextBlock", "text": winners.join(", "), "size": "Large", "weight": "Bolder", "wrap": true },
+        ],
+    }));
+
+    // one bar per option, scaled to the option with the most votes
+    let max_count = result.tally.iter().map(|t| t.vote_count()).max().unwrap_or(0).max(1);
+    for item in result.tally.iter() {
+        let mut label = format!("{} ({})", description(poll, item.option_id()), votes(item.vote_count()));
+        if result.eliminated.contains(&item.option_id()) {
+            label.push_str(", eliminated");
+        }
+        body.push(json!({ "type": "TextBlock", "text": label, "wrap": true, "spacing": "Medium" }));
+        body.push(bar(item.vote_count(), max_count, result.winners.contains(&item.option_id())));
+    }
+
+    body.push(json!({
+        "type": "TextBlock",
+        "text": format!("An option needs {} to win.", votes(result.threshold as u32)),
+        "isSubtle": true,
+        "wrap": true,
+        "spacing": "Medium",
+    }));
+
+    card(body, actions)
+}
+
+/// Show a message at the top of the card, below its title
+pub fn with_notice(mut card: Value, text: &str, is_error: bool) -> Value {
+    let notice = json!({
+        "type": "TextBlock",
+        "text": text,
+        "wrap": true,
+        "color": if is_error { "Attention" } else { "Good" },
+    });
+    if let Some(body) = card["body"].as_array_mut() {
+        body.insert(1.min(body.len()), notice);
+    }
+    card
+}
+
+/// Keep the card up to date for these users, who see their own view of it when it's refreshed
+pub fn with_refresh(mut card: Value, verb: &str, poll: &voting::Poll, user_ids: &[String]) -> Value {
+    card["refresh"] = json!({ "action": execute("Refresh", verb, poll), "userIds": user_ids });
+    card
+}
+
+fn card(body: Vec<Value>, actions: Vec<Value>) -> Value {
+    json!({
+        "type": "AdaptiveCard",
+        "$schema": SCHEMA,
+        "version": VERSION,
+        "body": body,
+        "actions": actions,
+    })
+}
+
+fn title(poll: &voting::Poll) -> Value {
+    json!({ "type": "TextBlock", "text": poll.title, "size": "Large", "weight": "Bolder", "wrap": true })
+}
+
+/// The winner count and closing conditions of the poll
+fn summary(poll: &voting::Poll) -> Value {
+    let mut parts = vec![match poll.winner_count {
+        1 => String::from("1 winner"),
+        n => format!("{n} winners"),
+    }];
+    match (poll.closed_at, poll.close_after_time) {
+        (Some(closed_at), _) => parts.push(format!("closed {}", closed_at.format("%Y-%m-%d %H:%M UTC"))),
+        (None, Some(close_after)) => parts.push(format!("closes {}", close_after.format("%Y-%m-%d %H:%M UTC"))),
+        (None, None) => {},
+    }
+    if let (None, Some(count)) = (poll.closed_at, poll.close_after_votes) {
+        parts.push(format!("closes after {count} ballots"));
+    }
+
+    json!({ "type": "TextBlock", "text": parts.join(" · "), "isSubtle": true, "wrap": true, "spacing": "Medium" })
+}
+
+fn execute(title: &str, verb: &str, poll: &voting::Poll) -> Value {
+    json!({ "type": "Action.Execute", "title": title, "verb": verb, "data": { "poll_id": poll.id } })
+}
+
+/// A horizontal bar whose length is `count` relative to `max`, drawn with weighted columns
+fn bar(count: u32, max: u32, winner: bool) -> Value {
+    let mut columns = vec![];
+    if count > 0 {
+        columns.push(json!({
+            "type": "Column",
+            "width": count,
+            "minHeight": "12px",
+            "style": if winner { "good" } else { "accent" },
+            "items": [],
+        }));
+    }
+    if count < max {
+        columns.push(json!({ "type": "Column", "width": max - count, "items": [] }));
+    }
+    json!({ "type": "ColumnSet", "columns": columns, "spacing": "Small" })
+}
+
+fn options(poll: &voting::Poll) -> &[voting::PollOption] {
+    poll.options.as_deref().unwrap_or_default()
+}
+
+fn description(poll: &voting::Poll, id: voting::WeakId) -> &str {
+    options(poll).iter()
+        .find(|o| o.id == id)
+        .map(|o| o.description.as_str())
+        .unwrap_or("Unknown option")
+}
+
+fn votes(count: u32) -> String {
+    match count {
+        1 => String::from("1 vote"),
+        n => format!("{n} votes"),
+    }
+}
+
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{n}{suffix}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll() -> voting::Poll {
+        let settings = voting::CreatePollSettings::try_from(voting::UnvalidatedCreatePollSettings {
+            title: String::from("Lunch"),
+            options: vec![String::from("Pizza"), String::from("Tacos"), String::from("Sushi")],
+            ..Default::default()
+        }).unwrap();
+        let options = settings.options.iter().enumerate()
+            .map(|(i, o)| voting::PollOption { id: voting::WeakId(i as u32), description: o.clone() })
+            .collect();
+        let owner = voting::User::new(voting::Id::new(), String::from("Owner"));
+        voting::Poll::new(settings, options, owner, vec![0; 32])
+    }
+
+    #[test]
+    fn ballot_card_is_prefilled() {
+        let poll = poll();
+        let ballot = voting::Ballot {
+            ranked_preferences: vec![voting::WeakId(2), voting::WeakId(0)],
+            ..Default::default()
+        };
+        let card = ballot_card(&poll, Some(&ballot));
+
+        let inputs: Vec<&Value> = card["body"].as_array().unwrap().iter()
+            .filter(|e| e["type"] == "Input.ChoiceSet")
+            .collect();
+        assert_eq!(inputs.len(), 3, "There should be a choice for every rank");
+        assert_eq!(inputs[0]["id"], "rank_0");
+        assert_eq!(inputs[0]["label"], "1st choice");
+        assert_eq!(inputs[0]["value"], "2");
+        assert_eq!(inputs[1]["value"], "0");
+        assert!(inputs[2].get("value").is_none());
+        assert_eq!(card["actions"][0]["verb"], VERB_VOTE);
+        assert_eq!(card["actions"][0]["title"], "Update vote");
+
+        let mut poll = poll;
+        poll.ballot_changes_allowed = false;
+        let card = ballot_card(&poll, Some(&ballot));
+        assert!(!card["body"].as_array().unwrap().iter().any(|e| e["type"] == "Input.ChoiceSet"), "Final ballots can't be changed");
+        assert_eq!(card["actions"][0]["verb"], VERB_SHOW_RESULT);
+    }
+
+    #[test]
+    fn result_card_has_winner_and_bars() {
+        let poll = poll();
+        let result = voting::PollResult::evaluate(&poll, &[
+            voting::Ballot { ranked_preferences: vec![voting::WeakId(1)], ..Default::default() },
+            voting::Ballot { ranked_preferences: vec![voting::WeakId(1)], ..Default::default() },
+            voting::Ballot { ranked_preferences: vec![voting::WeakId(0)], ..Default::default() },
+        ], 2, &poll.rng_seed);
+        let card = result_card(&poll, Some(&result));
+
+        let body = card["body"].as_array().unwrap();
+        assert_eq!(body[1]["items"][0]["text"], "Leading");
+        assert_eq!(body[1]["items"][1]["text"], "Tacos");
+        let bars: Vec<&Value> = body.iter().filter(|e| e["type"] == "ColumnSet").collect();
+        assert_eq!(bars.len(), result.tally.len());
+        assert_eq!(bars[0]["columns"][0]["style"], "good");
+
+        assert_eq!(ordinal(2), "2nd");
+        assert_eq!(ordinal(12), "12th");
+        assert_eq!(ordinal(23), "23rd");
+    }
+}
@@ -5,6 +5,10 @@ use std::env;
 
 use dotenvy::dotenv;
 use diesel::{Connection, PgConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+
+/// Every migration in `migrations`, built into the binary so it can set up its own database
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 pub fn establish_connection() -> PgConnection {
     dotenv().ok();
@@ -27,6 +27,13 @@ pub struct Poll {
     pub created_at: NaiveDateTime,
     pub closed_at: Option<NaiveDateTime>,
     pub rng_seed: Vec<u8>,
+    /// Where the poll was shared in Teams, so its result can be announced there
+    pub conversation_reference: Option<serde_json::Value>,
+    pub announced_at: Option<NaiveDateTime>,
+    pub ballot_changes_allowed: bool,
+    pub ballot_withdrawals_allowed: bool,
+    pub seed_commitment: String,
+    pub announcement_claimed_until: Option<NaiveDateTime>,
 }
 
 impl TryInto<voting::Poll> for (Poll, Vec<PollOption>, User) {
@@ -43,6 +50,10 @@ impl TryInto<voting::Poll> for (Poll, Vec<PollOption>, User) {
             created_at,
             closed_at,
             rng_seed,
+            ballot_changes_allowed,
+            ballot_withdrawals_allowed,
+            seed_commitment,
+            ..
         }, options, owner) = self;
 
         let settings = voting::CreatePollSettings {
@@ -51,6 +62,8 @@ impl TryInto<voting::Poll> for (Poll, Vec<PollOption>, User) {
             options: vec![],
             winner_count: winner_count as u8,
             write_ins_allowed,
+            ballot_changes_allowed,
+            ballot_withdrawals_allowed,
             close_after_time: close_after_time.map(|t| t.and_utc()),
             close_after_votes: close_after_votes.map(|v| v as u32),
         };
@@ -64,6 +77,11 @@ impl TryInto<voting::Poll> for (Poll, Vec<PollOption>, User) {
         poll.close_after_time = close_after_time.map(|t| t.and_utc());
         poll.created_at = created_at.and_utc();
         poll.closed_at = closed_at.map(|t| t.and_utc());
+        // the commitment published when the poll was created or last reopened
+        poll.seed_commitment = seed_commitment;
+        if poll.closed_at.is_some() {
+            poll.reveal_seed();
+        }
 
         Ok(poll)
     }
@@ -77,12 +95,16 @@ pub struct CreatePollSettings {
 
     pub winner_count: i32,
     pub write_ins_allowed: bool,
+    pub ballot_changes_allowed: bool,
+    pub ballot_withdrawals_allowed: bool,
     pub close_after_time: Option<NaiveDateTime>,
     pub close_after_votes: Option<i32>,
 
     pub owner_id: Uuid,
 
     pub rng_seed: Vec<u8>,
+    pub seed_commitment: String,
+    pub conversation_reference: Option<serde_json::Value>,
 }
 
 impl CreatePollSettings {
@@ -92,31 +114,46 @@ impl CreatePollSettings {
         options,
         winner_count,
         write_ins_allowed,
+        ballot_changes_allowed,
+        ballot_withdrawals_allowed,
         close_after_time,
         close_after_votes,
     }: voting::CreatePollSettings) -> (Self, Vec<String>) {
-        let mut poll_settings = Self {
+        let (rng_seed, seed_commitment) = new_seed();
+        let poll_settings = Self {
             id: None, // discard any ID provided as input, force random ID from DB
             title,
             winner_count: winner_count as i32,
             write_ins_allowed,
+            ballot_changes_allowed,
+            ballot_withdrawals_allowed,
             close_after_time: close_after_time.map(|t| t.naive_utc()),
             close_after_votes: close_after_votes.map(|v| v as i32),
             owner_id: owner_id.clone(),
-            rng_seed: vec![0; 32],
+            rng_seed,
+            seed_commitment,
+            conversation_reference: None,
         };
-        rand::thread_rng().fill_bytes(&mut poll_settings.rng_seed);
 
         (poll_settings, options)
     }
 }
 
+/// A new random seed for shuffling ballots and its commitment
+pub fn new_seed() -> (Vec<u8>, String) {
+    let mut rng_seed = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut rng_seed);
+    (rng_seed.to_vec(), voting::commit_seed(&rng_seed))
+}
+
 #[derive(Debug, AsChangeset)]
 #[diesel(table_name = schema::polls)]
 pub struct UpdatePollSettings {
     pub title: Option<String>,
     pub winner_count: Option<i32>,
     pub write_ins_allowed: Option<bool>,
+    pub ballot_changes_allowed: Option<bool>,
+    pub ballot_withdrawals_allowed: Option<bool>,
     pub close_after_time: Option<Option<NaiveDateTime>>,
     pub close_after_votes: Option<Option<i32>>,
 }
@@ -126,6 +163,8 @@ impl From<voting::UpdatePollSettings> for UpdatePollSettings {
         title,
         winner_count,
         write_ins_allowed,
+        ballot_changes_allowed,
+        ballot_withdrawals_allowed,
         close_after_time,
         close_after_votes,
     }: voting::UpdatePollSettings) -> Self {
@@ -133,6 +172,8 @@ impl From<voting::UpdatePollSettings> for UpdatePollSettings {
             title,
             winner_count: winner_count.map(|x| x as i32),
             write_ins_allowed,
+            ballot_changes_allowed,
+            ballot_withdrawals_allowed,
             close_after_time: close_after_time.map(|odt| {
                 odt.map(|dt| {
                     dt.naive_utc()
@@ -191,11 +232,14 @@ pub struct Ballot {
     pub poll_id: Uuid,
     pub user_id: Uuid,
     pub created_at: NaiveDateTime,
+    pub nonce: Option<Vec<u8>>,
+    pub receipt: Option<String>,
+    pub revision: i32,
 }
 
 impl TryInto<voting::Ballot> for (Ballot, Vec<Vote>, User, voting::Poll) {
-    type Error = error::ValidationError;
-    fn try_into(self) -> Result<voting::Ballot, error::ValidationError> {
+    type Error = error::ValidationErrors;
+    fn try_into(self) -> Result<voting::Ballot, error::ValidationErrors> {
         let (db_ballot, db_votes, db_voter, poll) = self;
 
         let mut ballot = voting::UnvalidatedCreateBallot::new();
@@ -206,7 +250,9 @@ impl TryInto<voting::Ballot> for (Ballot, Vec<Vote>, User, voting::Poll) {
             }
             else {
                 return Err(error::ballot_incomplete_selection(i)
-                    .with_context("ballot", error::ContextId::I32(db_ballot.id)));
+                    .at(format!("ranked_preferences[{i}]"))
+                    .with_context("ballot", error::ContextId::I32(db_ballot.id))
+                    .into());
             }
         }
 
@@ -217,7 +263,9 @@ impl TryInto<voting::Ballot> for (Ballot, Vec<Vote>, User, voting::Poll) {
             Ok(b) => b,
         };
 
-        Ok(voting::Ballot::new(db_voter.into(), ballot))
+        let mut ballot = voting::Ballot::new(db_voter.into(), ballot);
+        ballot.revision = db_ballot.revision as u32;
+        Ok(ballot)
     }
 }
 
@@ -227,11 +275,24 @@ impl TryInto<voting::Ballot> for (Ballot, Vec<Vote>, User, voting::Poll) {
 pub struct CreateBallot {
     poll_id: Uuid,
     user_id: Uuid,
+    nonce: Option<Vec<u8>>,
+    receipt: Option<String>,
+    revision: i32,
 }
 
 impl CreateBallot {
     pub fn new(poll_id: Uuid, user_id: Uuid) -> Self {
-        Self { poll_id, user_id }
+        Self { poll_id, user_id, nonce: None, receipt: None, revision: 1 }
+    }
+
+    pub fn with_receipt(poll_id: Uuid, user_id: Uuid, revision: i32, receipt: &voting::Receipt) -> Self {
+        Self {
+            poll_id,
+            user_id,
+            revision,
+            nonce: hex::decode(&receipt.nonce).ok(),
+            receipt: Some(receipt.receipt.clone()),
+        }
     }
 }
 
@@ -244,3 +305,152 @@ pub struct Vote {
     pub preference: i32,
     pub option: i32,
 }
+
+/// One of the rankings a voter has given in a poll, kept when the ballot is changed or withdrawn
+#[derive(Queryable, Selectable, Serialize)]
+#[diesel(table_name = schema::ballotrevisions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct BallotRevision {
+    pub revision: i32,
+    /// Option ids in order of preference, or none if the ballot was withdrawn
+    pub ranked_preferences: Option<Vec<i32>>,
+    pub receipt: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::ballotrevisions)]
+pub struct CreateBallotRevision {
+    pub poll_id: Uuid,
+    pub user_id: Uuid,
+    pub revision: i32,
+    pub ranked_preferences: Option<Vec<i32>>,
+    pub receipt: Option<String>,
+}
+
+#[derive(Associations, Queryable, Selectable, Identifiable)]
+#[diesel(table_name = schema::pollresults)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(poll_id))]
+#[diesel(belongs_to(Poll, foreign_key = poll_id))]
+pub struct PollResult {
+    pub poll_id: Uuid,
+    pub result: serde_json::Value,
+    pub finalized_at: NaiveDateTime,
+    /// The root of the Merkle tree over the poll's ballot receipts
+    pub merkle_root: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::pollresults)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreatePollResult {
+    poll_id: Uuid,
+    result: serde_json::Value,
+    merkle_root: Option<String>,
+}
+
+impl CreatePollResult {
+    pub fn new(poll_id: Uuid, result: serde_json::Value, merkle_root: String) -> Self {
+        Self { poll_id, result, merkle_root: Some(merkle_root) }
+    }
+}
+
+#[derive(Associations, Queryable, Selectable, Identifiable, Serialize)]
+#[diesel(table_name = schema::webhooks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(User, foreign_key = owner_id))]
+pub struct Webhook {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub poll_id: Option<Uuid>,
+    pub tenant_id: Option<String>,
+    pub url: String,
+    /// Only shown when the webhook is created
+    #[serde(skip)]
+    pub secret: String,
+    pub events: Vec<Option<String>>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::webhooks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateWebhook {
+    pub owner_id: Uuid,
+    pub poll_id: Option<Uuid>,
+    pub tenant_id: Option<String>,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<Option<String>>,
+}
+
+#[derive(Associations, Queryable, Selectable, Identifiable, Serialize)]
+#[diesel(table_name = schema::webhookdeliveries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(Webhook, foreign_key = webhook_id))]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: Uuid,
+    pub event: String,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub next_attempt_at: Option<NaiveDateTime>,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::webhookdeliveries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateWebhookDelivery {
+    pub webhook_id: Uuid,
+    pub event: String,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Serialize)]
+#[diesel(table_name = schema::auditevents)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuditEvent {
+    pub id: i64,
+    pub poll_id: Uuid,
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub details: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::auditevents)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateAuditEvent {
+    pub poll_id: Uuid,
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub details: serde_json::Value,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::idempotencykeys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct IdempotencyKey {
+    pub request: String,
+    pub request_hash: String,
+    pub status: Option<i32>,
+    pub body: Option<serde_json::Value>,
+    pub headers: serde_json::Value,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::idempotencykeys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateIdempotencyKey<'a> {
+    pub user_id: Uuid,
+    pub key: &'a str,
+    pub request: &'a str,
+    pub request_hash: &'a str,
+    pub expires_at: NaiveDateTime,
+}
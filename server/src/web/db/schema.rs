@@ -1,11 +1,56 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    auditevents (id) {
+        id -> Int8,
+        poll_id -> Uuid,
+        actor_id -> Nullable<Uuid>,
+        #[max_length = 50]
+        action -> Varchar,
+        details -> Jsonb,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    ballotrevisions (poll_id, user_id, revision) {
+        poll_id -> Uuid,
+        user_id -> Uuid,
+        revision -> Int4,
+        ranked_preferences -> Nullable<Array<Int4>>,
+        #[max_length = 64]
+        receipt -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     ballots (id) {
         id -> Int4,
         poll_id -> Uuid,
         user_id -> Uuid,
         created_at -> Timestamp,
+        nonce -> Nullable<Bytea>,
+        #[max_length = 64]
+        receipt -> Nullable<Varchar>,
+        revision -> Int4,
+    }
+}
+
+diesel::table! {
+    idempotencykeys (user_id, key) {
+        user_id -> Uuid,
+        #[max_length = 255]
+        key -> Varchar,
+        #[max_length = 200]
+        request -> Varchar,
+        #[max_length = 64]
+        request_hash -> Varchar,
+        status -> Nullable<Int4>,
+        body -> Nullable<Jsonb>,
+        headers -> Jsonb,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
     }
 }
 
@@ -18,6 +63,24 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    pollreminders (poll_id, hours_before) {
+        poll_id -> Uuid,
+        hours_before -> Int4,
+        sent_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    pollresults (poll_id) {
+        poll_id -> Uuid,
+        result -> Jsonb,
+        finalized_at -> Timestamp,
+        #[max_length = 64]
+        merkle_root -> Nullable<Varchar>,
+    }
+}
+
 diesel::table! {
     polls (id) {
         id -> Uuid,
@@ -31,6 +94,13 @@ diesel::table! {
         created_at -> Timestamp,
         closed_at -> Nullable<Timestamp>,
         rng_seed -> Bytea,
+        conversation_reference -> Nullable<Jsonb>,
+        announced_at -> Nullable<Timestamp>,
+        ballot_changes_allowed -> Bool,
+        ballot_withdrawals_allowed -> Bool,
+        #[max_length = 64]
+        seed_commitment -> Varchar,
+        announcement_claimed_until -> Nullable<Timestamp>,
     }
 }
 
@@ -50,16 +120,62 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    webhookdeliveries (id) {
+        id -> Int8,
+        webhook_id -> Uuid,
+        #[max_length = 50]
+        event -> Varchar,
+        payload -> Jsonb,
+        attempts -> Int4,
+        next_attempt_at -> Nullable<Timestamp>,
+        last_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        delivered_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Uuid,
+        owner_id -> Uuid,
+        poll_id -> Nullable<Uuid>,
+        #[max_length = 100]
+        tenant_id -> Nullable<Varchar>,
+        #[max_length = 2000]
+        url -> Varchar,
+        #[max_length = 100]
+        secret -> Varchar,
+        events -> Array<Nullable<Text>>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(ballotrevisions -> polls (poll_id));
+diesel::joinable!(ballotrevisions -> users (user_id));
 diesel::joinable!(ballots -> polls (poll_id));
 diesel::joinable!(ballots -> users (user_id));
 diesel::joinable!(polloptions -> polls (poll_id));
+diesel::joinable!(pollreminders -> polls (poll_id));
+diesel::joinable!(pollresults -> polls (poll_id));
 diesel::joinable!(polls -> users (owner_id));
 diesel::joinable!(votes -> ballots (ballot_id));
+diesel::joinable!(webhookdeliveries -> webhooks (webhook_id));
+diesel::joinable!(webhooks -> polls (poll_id));
+diesel::joinable!(webhooks -> users (owner_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    auditevents,
+    ballotrevisions,
     ballots,
+    idempotencykeys,
     polloptions,
+    pollreminders,
+    pollresults,
     polls,
     users,
     votes,
+    webhookdeliveries,
+    webhooks,
 );
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use dotenvy::dotenv;
+use futures_util::{future, stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::{task, time};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_postgres::config::SslMode;
+use tokio_postgres::{AsyncMessage, Client, Config, Connection, NoTls};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use uuid::Uuid;
+use warp::reply::{Reply, Response};
+use warp::sse;
+
+use crate::error;
+use super::db::{establish_connection, schema};
+use super::poll_api::get_internal as get_poll;
+use super::result_api;
+
+/// Postgres channel that every server instance listens on for poll changes
+const CHANNEL: &str = "poll_events";
+
+/// How many events a slow subscriber may fall behind before it starts missing them
+const SUBSCRIBER_CAPACITY: usize = 16;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// What changed about a poll, as sent between server instances
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Ballot,
+    PollUpdated,
+    PollClosed,
+    PollDeleted,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Notification {
+    poll_id: Uuid,
+    change: Change,
+}
+
+/// A server-sent event for the subscribers of a poll
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    name: &'static str,
+    data: serde_json::Value,
+}
+
+impl Event {
+    fn to_sse(&self) -> sse::Event {
+        sse::Event::default()
+            .event(self.name)
+            .json_data(&self.data)
+            .expect("Event data is always serializable")
+    }
+}
+
+/// Fans events out to the clients subscribed to each poll on this server instance
+#[derive(Default)]
+pub struct Hub {
+    channels: Mutex<HashMap<Uuid, broadcast::Sender<Arc<Event>>>>,
+}
+
+impl Hub {
+    pub fn subscribe(&self, poll_id: Uuid) -> broadcast::Receiver<Arc<Event>> {
+        let mut channels = self.channels.lock().unwrap();
+        channels.entry(poll_id)
+            .or_insert_with(|| broadcast::channel(SUBSCRIBER_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Whether anyone is listening to the poll, forgetting channels whose subscribers have all left
+    pub fn has_subscribers(&self, poll_id: &Uuid) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels.contains_key(poll_id)
+    }
+
+    pub fn publish(&self, poll_id: &Uuid, event: Event) {
+        let channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(poll_id) {
+            let _ = sender.send(Arc::new(event));
+        }
+    }
+
+    /// End every subscription to the poll once they have received the events already published
+    pub fn close(&self, poll_id: &Uuid) {
+        self.channels.lock().unwrap().remove(poll_id);
+    }
+}
+
+/// Tell every server instance that a poll has changed. Notifications sent inside a transaction are
+/// only delivered once it commits.
+pub fn notify(connection: &mut PgConnection, poll_id: &Uuid, change: Change) {
+    let payload = serde_json::to_string(&Notification { poll_id: *poll_id, change })
+        .expect("Notifications are always serializable");
+    let result = diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(CHANNEL)
+        .bind::<Text, _>(payload)
+        .execute(connection);
+
+    if let Err(err) = result {
+        println!("Failed to notify {change:?} of poll {poll_id}: {err}");
+    }
+}
+
+/// Stream the poll's events to the client, starting with its current state
+pub fn subscribe(poll_id: Uuid, hub: Arc<Hub>, shutdown: watch::Receiver<bool>) -> Response {
+    // subscribe before reading the current state so no change can slip in between
+    let receiver = hub.subscribe(poll_id);
+
+    let connection = &mut establish_connection();
+    let snapshot = match current_state(connection, &poll_id) {
+        Err(err) => return err.into_response(),
+        Ok(events) => events,
+    };
+
+    let updates = stream::unfold((receiver, shutdown), |(mut receiver, mut shutdown)| async move {
+        loop {
+            tokio::select! {
+                // open streams would otherwise hold up graceful shutdown forever
+                _ = shutdown.changed() => return None,
+                event = receiver.recv() => match event {
+                    Ok(event) => return Some((event, (receiver, shutdown))),
+                    // every event carries the latest state, so skipped ones don't need replaying
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                },
+            }
+        }
+    });
+
+    let events = stream::iter(snapshot.into_iter().map(Arc::new))
+        .chain(updates)
+        .map(|event| Ok::<_, Infallible>(event.to_sse()));
+
+    sse::reply(sse::keep_alive().stream(events)).into_response()
+}
+
+/// Relay poll change notifications from the database to this instance's subscribers until shutdown,
+/// reconnecting whenever the connection is lost
+pub async fn listen(hub: Arc<Hub>, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => return,
+            result = listen_once(&hub) => if let Err(err) = result {
+                println!("Lost poll event notifications: {err}");
+            },
+        }
+
+        tokio::select! {
+            _ = shutdown.changed() => return,
+            _ = time::sleep(RECONNECT_DELAY) => {},
+        }
+    }
+}
+
+async fn listen_once(hub: &Hub) -> Result<(), tokio_postgres::Error> {
+    dotenv().ok();
+    let db_url = env::var("DATABASE_URL")
+        .expect("Environment variable 'DATABASE_URL' must be set");
+    let config: Config = db_url.parse()?;
+
+    // the other connections go through libpq, which encrypts them as `sslmode` says, so this one
+    // has to as well. Where TLS is only preferred it goes without, which libpq allows too.
+    match config.get_ssl_mode() {
+        SslMode::Require => {
+            let (client, connection) = config.connect(tls()).await?;
+            relay(hub, client, connection).await
+        },
+        _ => {
+            let (client, connection) = config.connect(NoTls).await?;
+            relay(hub, client, connection).await
+        },
+    }
+}
+
+/// Connections to the database over TLS, trusting the usual web certificate authorities
+fn tls() -> MakeRustlsConnect {
+    let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("The default TLS versions are supported")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    MakeRustlsConnect::new(config)
+}
+
+/// Pass the notifications received on the connection on to the hub, until the connection is lost
+async fn relay<S, T>(hub: &Hub, client: Client, mut connection: Connection<S, T>) -> Result<(), tokio_postgres::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // the connection has to be driven for the client to make progress, and it is the only way to
+    // receive notifications. It stops once the client is dropped.
+    let (sender, mut notifications) = mpsc::unbounded_channel();
+    let driver = task::spawn(
+        stream::poll_fn(move |cx| connection.poll_message(cx))
+            .try_for_each(move |message| {
+                if let AsyncMessage::Notification(notification) = message {
+                    let _ = sender.send(notification);
+                }
+                future::ready(Ok(()))
+            })
+    );
+
+    client.batch_execute(&format!("LISTEN {CHANNEL}")).await?;
+    println!("Listening for poll events");
+
+    while let Some(notification) = notifications.recv().await {
+        let notification: Notification = match serde_json::from_str(notification.payload()) {
+            Err(err) => {
+                println!("Ignoring malformed poll event '{}': {err}", notification.payload());
+                continue;
+            },
+            Ok(n) => n,
+        };
+        if !hub.has_subscribers(&notification.poll_id) {
+            continue;
+        }
+
+        let poll_id = notification.poll_id;
+        let events = task::spawn_blocking(move || {
+            let connection = &mut establish_connection();
+            changed_state(connection, &poll_id, notification.change)
+        }).await;
+
+        match events {
+            Err(err) => println!("Failed to load events of poll {poll_id}: {err}"),
+            Ok(Err(err)) => println!("Failed to load events of poll {poll_id}: {err}"),
+            Ok(Ok(events)) => for event in events {
+                hub.publish(&poll_id, event);
+            },
+        }
+
+        if notification.change == Change::PollDeleted {
+            hub.close(&poll_id);
+        }
+    }
+
+    // the driver dropped the sender, so it has finished
+    match driver.await {
+        Err(err) => {
+            println!("Poll event connection panicked: {err}");
+            Ok(())
+        },
+        Ok(result) => result,
+    }
+}
+
+/// Everything a new subscriber needs to know about the poll
+fn current_state(connection: &mut PgConnection, poll_id: &Uuid) -> Result<Vec<Event>, error::HttpError> {
+    let poll = get_poll(connection, poll_id)?;
+    let mut events = vec![
+        Event { name: "poll", data: to_value(&poll) },
+        ballot_count(connection, poll_id)?,
+    ];
+    if let Some(result) = result_api::get_visible(connection, &poll)? {
+        events.push(Event { name: "result", data: result });
+    }
+    Ok(events)
+}
+
+/// The events that let subscribers catch up with a change to the poll
+fn changed_state(connection: &mut PgConnection, poll_id: &Uuid, change: Change) -> Result<Vec<Event>, error::HttpError> {
+    if change == Change::PollDeleted {
+        return Ok(vec![Event { name: "poll_deleted", data: serde_json::json!({ "id": poll_id }) }]);
+    }
+
+    let poll = get_poll(connection, poll_id)?;
+    let mut events = match change {
+        Change::Ballot => vec![ballot_count(connection, poll_id)?],
+        Change::PollUpdated => vec![Event { name: "poll_updated", data: to_value(&poll) }],
+        Change::PollClosed => vec![Event { name: "poll_closed", data: to_value(&poll) }],
+        Change::PollDeleted => unreachable!(),
+    };
+    if let Some(result) = result_api::get_visible(connection, &poll)? {
+        events.push(Event { name: "result", data: result });
+    }
+    Ok(events)
+}
+
+fn ballot_count(connection: &mut PgConnection, poll_id: &Uuid) -> Result<Event, error::HttpError> {
+    let result = schema::ballots::table
+        .filter(schema::ballots::poll_id.eq(poll_id))
+        .count()
+        .get_result::<i64>(connection);
+
+    match result {
+        Err(err) => Err(error::internal(err)),
+        Ok(count) => Ok(Event { name: "ballot_count", data: serde_json::json!({ "count": count }) }),
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).expect("Event data is always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hub_fans_out_per_poll() {
+        let hub = Hub::default();
+        let poll_id = Uuid::new_v4();
+        let other_id = Uuid::new_v4();
+
+        let mut first = hub.subscribe(poll_id);
+        let mut second = hub.subscribe(poll_id);
+        let other = hub.subscribe(other_id);
+        assert!(hub.has_subscribers(&poll_id));
+
+        hub.publish(&poll_id, Event { name: "ballot_count", data: serde_json::json!({ "count": 1 }) });
+        assert_eq!(first.recv().await.unwrap().data, serde_json::json!({ "count": 1 }));
+        assert_eq!(second.recv().await.unwrap().name, "ballot_count");
+
+        hub.close(&poll_id);
+        assert!(matches!(first.recv().await, Err(RecvError::Closed)));
+
+        drop(other);
+        assert!(!hub.has_subscribers(&other_id), "Channels without subscribers should be forgotten");
+    }
+}
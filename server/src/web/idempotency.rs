@@ -0,0 +1,249 @@
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use diesel::upsert::excluded;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::StatusCode;
+use warp::reply::{self, Reply, Response};
+
+use crate::error;
+use super::db::{models, schema};
+
+/// How long a response is kept for retries of its request
+pub const KEY_LIFETIME: Duration = Duration::hours(24);
+
+const KEY_LENGTH_BOUNDS: RangeInclusive<usize> = 1 ..= 255;
+
+/// The value of an `Idempotency-Key` header, chosen by the client so it can retry a request safely.
+/// Keys are printable ASCII and belong to the user sending them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdempotencyKey(String);
+
+impl FromStr for IdempotencyKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = s.trim();
+        if !KEY_LENGTH_BOUNDS.contains(&key.len()) || !key.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+            return Err("idempotency keys must be 1 to 255 printable ASCII characters");
+        }
+        Ok(Self(key.to_string()))
+    }
+}
+
+/// What a key was first used for: the method and path of the request, and the hash of its body
+pub struct Request {
+    name: String,
+    hash: String,
+}
+
+impl Request {
+    pub fn new(name: String, body: &impl Serialize) -> Self {
+        let body = serde_json::to_vec(body).expect("request bodies serialize");
+        Self { name, hash: hex::encode(Sha256::digest(body)) }
+    }
+}
+
+/// A successful response, kept so retries of its request get it again
+pub struct Replayable {
+    status: StatusCode,
+    body: serde_json::Value,
+    headers: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Replayable {
+    pub fn new(status: StatusCode, body: &impl Serialize) -> Self {
+        Self {
+            status,
+            body: serde_json::to_value(body).expect("response bodies serialize"),
+            headers: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: String) -> Self {
+        self.headers.insert(name.to_string(), serde_json::Value::String(value));
+        self
+    }
+
+    pub fn into_response(self) -> Response {
+        let mut response = reply::with_status(reply::json(&self.body), self.status).into_response();
+        for (name, value) in self.headers {
+            let (Ok(name), Some(Ok(value))) = (HeaderName::from_bytes(name.as_bytes()), value.as_str().map(HeaderValue::from_str)) else {
+                continue;
+            };
+            response.headers_mut().insert(name, value);
+        }
+        response
+    }
+}
+
+/// Handle a request once per idempotency key. Retries with the same key get the first response
+/// again until the key expires, without handling the request again. The key is claimed, the request
+/// handled and its response kept in one transaction, so a request that fails, or a server that stops
+/// part way through one, leaves the key free to retry with. A retry that arrives while the request is
+/// still being handled waits for it. Requests without a key are always handled.
+pub fn once<F>(
+    connection: &mut PgConnection,
+    user_id: &Uuid,
+    key: Option<IdempotencyKey>,
+    request: Request,
+    handle: F,
+) -> Response
+where
+    F: FnOnce(&mut PgConnection) -> Result<Replayable, error::HttpError>,
+{
+    let Some(IdempotencyKey(key)) = key else {
+        return match handle(connection) {
+            Err(err) => err.into_response(),
+            Ok(reply) => reply.into_response(),
+        };
+    };
+
+    let result = connection.transaction::<Response, Rollback, _>(|connection| {
+        if let Some(claimed) = claim(connection, user_id, &key, &request)? {
+            return Ok(replay(claimed, &request));
+        }
+
+        let reply = handle(connection).map_err(Rollback::Failed)?;
+        diesel::update(schema::idempotencykeys::table.find((user_id, &key)))
+            .set((
+                schema::idempotencykeys::status.eq(reply.status.as_u16() as i32),
+                schema::idempotencykeys::body.eq(&reply.body),
+                schema::idempotencykeys::headers.eq(serde_json::Value::Object(reply.headers.clone())),
+            ))
+            .execute(connection)?;
+        Ok(reply.into_response())
+    });
+
+    match result {
+        Err(Rollback::Failed(err)) => err.into_response(),
+        Err(Rollback::Db(err)) => error::internal(err).into_response(),
+        Ok(response) => response,
+    }
+}
+
+/// Why the transaction of a request with a key was rolled back
+enum Rollback {
+    Failed(error::HttpError),
+    Db(DbError),
+}
+
+impl From<DbError> for Rollback {
+    fn from(err: DbError) -> Self {
+        Rollback::Db(err)
+    }
+}
+
+/// Delete the keys that have expired, returning how many there were
+pub fn delete_expired(connection: &mut PgConnection) -> Result<usize, DbError> {
+    diesel::delete(schema::idempotencykeys::table.filter(schema::idempotencykeys::expires_at.le(Utc::now().naive_utc())))
+        .execute(connection)
+}
+
+/// Claim the user's key for the request. If the key was claimed before and hasn't expired, nothing
+/// changes and that claim is returned instead. Claims without a response are taken over too, since
+/// claims used to be kept while their request was being handled.
+fn claim(
+    connection: &mut PgConnection, user_id: &Uuid, key: &str, request: &Request
+) -> Result<Option<models::IdempotencyKey>, DbError> {
+    let now = Utc::now().naive_utc();
+    let claim = diesel::insert_into(schema::idempotencykeys::table)
+        .values(models::CreateIdempotencyKey {
+            user_id: *user_id,
+            key,
+            request: &request.name,
+            request_hash: &request.hash,
+            expires_at: now + KEY_LIFETIME,
+        })
+        .on_conflict((schema::idempotencykeys::user_id, schema::idempotencykeys::key))
+        .do_update()
+        .set((
+            schema::idempotencykeys::request.eq(excluded(schema::idempotencykeys::request)),
+            schema::idempotencykeys::request_hash.eq(excluded(schema::idempotencykeys::request_hash)),
+            schema::idempotencykeys::status.eq(None::<i32>),
+            schema::idempotencykeys::body.eq(None::<serde_json::Value>),
+            schema::idempotencykeys::headers.eq(serde_json::json!({})),
+            schema::idempotencykeys::created_at.eq(now),
+            schema::idempotencykeys::expires_at.eq(excluded(schema::idempotencykeys::expires_at)),
+        ));
+    // only an expired or abandoned claim is taken over, through the WHERE clause of ON CONFLICT DO UPDATE
+    let takeover = schema::idempotencykeys::expires_at.le(now).or(schema::idempotencykeys::status.is_null());
+    let claimed = diesel::query_dsl::methods::FilterDsl::filter(claim, takeover).execute(connection)?;
+    if claimed > 0 {
+        return Ok(None);
+    }
+
+    schema::idempotencykeys::table
+        .find((user_id, key))
+        .select(models::IdempotencyKey::as_select())
+        .first(connection)
+        .map(Some)
+}
+
+/// The response to a request whose key was claimed before
+fn replay(claimed: models::IdempotencyKey, request: &Request) -> Response {
+    if claimed.request != request.name || claimed.request_hash != request.hash {
+        return error::idempotency_key_reused().into_response();
+    }
+    let (Some(status), Some(body)) = (claimed.status, claimed.body) else {
+        return error::stored_data_invalid("claimed idempotency key has no response").into_response();
+    };
+
+    let reply = Replayable {
+        status: StatusCode::from_u16(status as u16).unwrap_or(StatusCode::OK),
+        body,
+        headers: match claimed.headers {
+            serde_json::Value::Object(headers) => headers,
+            _ => serde_json::Map::new(),
+        },
+    };
+    reply.with_header("idempotent-replayed", String::from("true")).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::db::establish_connection;
+
+    #[test]
+    fn responses_are_replayed() {
+        assert!("".parse::<IdempotencyKey>().is_err());
+        assert!("naïve".parse::<IdempotencyKey>().is_err());
+        assert!("x".repeat(256).parse::<IdempotencyKey>().is_err());
+
+        let connection = &mut establish_connection();
+        let user_id = Uuid::new_v4();
+        let key: IdempotencyKey = "retry-me".parse().unwrap();
+        let mut handled = 0;
+        let mut handle = |body: &str| {
+            let request = Request::new(String::from("POST /test"), &body);
+            once(connection, &user_id, Some(key.clone()), request, |_| {
+                handled += 1;
+                if handled == 1 {
+                    return Err(error::forbidden("test"));
+                }
+                Ok(Replayable::new(StatusCode::CREATED, &handled).with_header("etag", String::from("\"1\"")))
+            })
+        };
+
+        assert_eq!(handle("a").status(), StatusCode::FORBIDDEN);
+        let first = handle("a");
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let retry = handle("a");
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()["etag"], "\"1\"", "Headers are replayed");
+        assert_eq!(retry.headers()["idempotent-replayed"], "true");
+        assert_eq!(handle("b").status(), StatusCode::UNPROCESSABLE_ENTITY, "Keys are for one request");
+        assert_eq!(handled, 2, "Failed requests can be retried with their key");
+
+        diesel::delete(schema::idempotencykeys::table.filter(schema::idempotencykeys::user_id.eq(user_id)))
+            .execute(connection)
+            .unwrap();
+    }
+}
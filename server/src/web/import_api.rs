@@ -0,0 +1,195 @@
+use std::str;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::reply::{self, Reply, Response};
+
+use crate::error;
+use crate::voting;
+use super::audit;
+use super::db::{establish_connection, models, schema};
+use super::poll_api::{self, get_internal as get_poll};
+use super::result_api;
+use super::webhooks;
+
+/// Imports are read whole, so their size is limited
+pub const IMPORT_BODY_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Rows inserted per statement, to stay under the database's limit on bind parameters
+const INSERT_CHUNK: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Blt,
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    pub format: ImportFormat,
+    /// Replaces the title of a BLT or JSON file, and names a CSV import
+    pub title: Option<String>,
+    /// Replaces the seat count of a BLT or JSON file, CSV imports have one winner otherwise
+    pub winner_count: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct Imported {
+    poll: voting::Poll,
+    ballot_count: usize,
+    result: Option<serde_json::Value>,
+}
+
+/// Create a closed poll from an election counted elsewhere, like on paper, and tally it like any
+/// other. Each imported ballot gets an anonymous voter of its own.
+pub fn import(user_id: Uuid, query: ImportQuery, body: Bytes) -> Response {
+    let text = match str::from_utf8(&body) {
+        Err(err) => return error::malformed_body(err).into_response(),
+        Ok(t) => t,
+    };
+
+    let parsed = match query.format {
+        ImportFormat::Blt => voting::parse_blt(text),
+        ImportFormat::Csv => voting::parse_ranked_csv(text),
+        ImportFormat::Json => voting::parse_json_import(text),
+    };
+    let mut parsed = match parsed {
+        Err(err) => return error::HttpError::from(err).into_response(),
+        Ok(p) => p,
+    };
+    if let Some(title) = query.title {
+        parsed.settings.title = title;
+    }
+    if let Some(winner_count) = query.winner_count {
+        parsed.settings.winner_count = winner_count;
+    }
+    let (poll, ballots) = match parsed.validate() {
+        Err(err) => return error::HttpError::from(err).into_response(),
+        Ok(p) => p,
+    };
+
+    let connection = &mut establish_connection();
+    let owner = models::User { id: user_id, display_name: String::from("Anonymous") };
+    let poll_id = match store(connection, owner, poll, &ballots) {
+        Err(err) => return err.into_response(),
+        Ok(id) => id,
+    };
+    println!("Imported {} ballots into poll: {poll_id}", ballots.len());
+
+    webhooks::enqueue(connection, &poll_id, webhooks::Event::PollClosed);
+    if let Err(err) = result_api::finalize(connection, &poll_id) {
+        return err.into_response();
+    }
+
+    let poll = match get_poll(connection, &poll_id) {
+        Err(err) => return err.into_response(),
+        Ok(p) => p,
+    };
+    let result = match result_api::get_visible(connection, &poll) {
+        Err(err) => return err.into_response(),
+        Ok(r) => r,
+    };
+    let imported = Imported { poll, ballot_count: ballots.len(), result };
+    reply::with_status(reply::json(&imported), StatusCode::CREATED).into_response()
+}
+
+/// Store the poll with its ballots, closed, in one transaction so a failed import leaves nothing behind
+fn store(
+    connection: &mut PgConnection, owner: models::User, poll: voting::Poll, ballots: &[voting::Ballot]
+) -> Result<Uuid, error::HttpError> {
+    let settings = voting::CreatePollSettings {
+        title: poll.title,
+        options: poll.options.unwrap_or_default().into_iter().map(|o| o.description).collect(),
+        winner_count: poll.winner_count,
+        ..voting::CreatePollSettings::default()
+    };
+    let owner_id = owner.id;
+
+    let result = connection.transaction::<Result<Uuid, error::HttpError>, DbError, _>(|connection| {
+        let poll_id = match poll_api::create(connection, owner, settings, None) {
+            Err(err) => return Ok(Err(err)),
+            Ok(poll) => poll.id.0,
+        };
+
+        let voters: Vec<models::User> = (0..ballots.len())
+            .map(|i| models::User { id: Uuid::new_v4(), display_name: format!("Imported ballot {}", i + 1) })
+            .collect();
+        for chunk in voters.chunks(INSERT_CHUNK) {
+            diesel::insert_into(schema::users::table).values(chunk).execute(connection)?;
+        }
+
+        let mut votes = vec![];
+        for (ballot_chunk, voter_chunk) in ballots.chunks(INSERT_CHUNK).zip(voters.chunks(INSERT_CHUNK)) {
+            let ballot_ids: Vec<i32> = diesel::insert_into(schema::ballots::table)
+                .values(voter_chunk.iter().map(|voter| models::CreateBallot::new(poll_id, voter.id)).collect::<Vec<_>>())
+                .returning(schema::ballots::id)
+                .get_results(connection)?;
+
+            for (ballot_id, ballot) in ballot_ids.into_iter().zip(ballot_chunk) {
+                votes.extend(ballot.ranked_preferences.iter().enumerate().map(|(preference, option)| {
+                    models::Vote { ballot_id, preference: preference as i32, option: option.0 as i32 }
+                }));
+            }
+        }
+        for chunk in votes.chunks(INSERT_CHUNK) {
+            diesel::insert_into(schema::votes::table).values(chunk).execute(connection)?;
+        }
+
+        diesel::update(schema::polls::table.find(poll_id))
+            .set(schema::polls::closed_at.eq(Utc::now().naive_utc()))
+            .execute(connection)?;
+        let details = serde_json::json!({ "reason": "import", "ballot_count": ballots.len() });
+        audit::record(connection, &poll_id, Some(&owner_id), audit::Action::PollClosed, details)?;
+        Ok(Ok(poll_id))
+    });
+
+    match result {
+        Err(err) => Err(error::db_insert(err, "ballot")),
+        Ok(r) => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::hyper::body;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn imported_elections_are_tallied() {
+        let owner_id = Uuid::new_v4();
+        let blt = "3 1\n4 1 2 0\n2 2 0\n1 3 2 0\n0\n\"Apple\"\n\"Banana\"\n\"Cherry\"\n\"Fruit\"\n";
+        let query = ImportQuery { format: ImportFormat::Blt, title: Some(String::from("Paper fruit")), winner_count: None };
+
+        let res = import(owner_id, query, Bytes::from(blt));
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let imported: serde_json::Value = serde_json::from_slice(&body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+
+        assert_eq!(imported["poll"]["title"], "Paper fruit");
+        assert!(imported["poll"]["closed_at"].is_string(), "Imported polls are closed");
+        assert_eq!(imported["ballot_count"], 7);
+        let result: voting::PollResult = serde_json::from_value(imported["result"].clone()).unwrap();
+        assert_eq!(result.winners.iter().map(|w| w.0).collect::<Vec<_>>(), [0]);
+
+        let poll_id: Uuid = serde_json::from_value(imported["poll"]["id"].clone()).unwrap();
+        let connection = &mut establish_connection();
+        let voters: Vec<Uuid> = schema::ballots::table
+            .filter(schema::ballots::poll_id.eq(poll_id))
+            .select(schema::ballots::user_id)
+            .load(connection).unwrap();
+        diesel::delete(schema::polls::table.find(poll_id)).execute(connection).unwrap();
+        diesel::delete(schema::users::table.filter(schema::users::id.eq_any(voters).or(schema::users::id.eq(owner_id))))
+            .execute(connection).unwrap();
+
+        let query = ImportQuery { format: ImportFormat::Csv, title: None, winner_count: None };
+        let res = import(owner_id, query, Bytes::from("Apple,Banana\n1,1\n"));
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,134 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, MissedTickBehavior};
+
+use super::db::establish_connection;
+use super::{idempotency, poll_api, result_api};
+
+pub const CLOSE_POLLS_PERIOD: Duration = Duration::from_secs(30);
+pub const EXPIRE_IDEMPOTENCY_KEYS_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Work that runs alongside the web server until it is shut down
+pub struct Jobs {
+    shutdown: watch::Receiver<bool>,
+    tasks: JoinSet<()>,
+}
+
+impl Jobs {
+    /// Jobs stop once `true` is sent on the channel behind `shutdown`
+    pub fn new(shutdown: watch::Receiver<bool>) -> Self {
+        Self {
+            shutdown,
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// Run a long-lived task, which must return promptly once its shutdown receiver changes
+    pub fn run<F, Fut>(&mut self, name: &'static str, task: F)
+    where
+        F: FnOnce(watch::Receiver<bool>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let task = task(self.shutdown.clone());
+        self.tasks.spawn(async move {
+            task.await;
+            println!("Background job {name} stopped");
+        });
+    }
+
+    /// Run `job` on the blocking thread pool every `period`, starting immediately. A run that is
+    /// in progress when shutdown is requested is allowed to finish.
+    pub fn every<F>(&mut self, name: &'static str, period: Duration, job: F)
+    where F: Fn() + Send + Sync + 'static {
+        let mut shutdown = self.shutdown.clone();
+        let job = Arc::new(job);
+
+        self.tasks.spawn(async move {
+            let mut interval = time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                tokio::select! {
+                    _ = shutdown.changed() => break,
+                    _ = interval.tick() => {
+                        let job = job.clone();
+                        if let Err(err) = task::spawn_blocking(move || job()).await {
+                            println!("Background job {name} failed: {err}");
+                        }
+                    },
+                }
+            }
+
+            println!("Background job {name} stopped");
+        });
+    }
+
+    /// Wait for every job to stop after shutdown has been signalled
+    pub async fn join(mut self) {
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+/// Close polls that have reached their time or vote limits, then tally any closed polls
+pub fn close_polls() {
+    let connection = &mut establish_connection();
+
+    match poll_api::close_expired(connection) {
+        Err(err) => println!("Failed to close expired polls: {err}"),
+        Ok(ids) => for id in ids {
+            println!("Closed poll: {id}");
+        },
+    }
+
+    match result_api::finalize_closed(connection) {
+        Err(err) => println!("Failed to finalize poll results: {err}"),
+        Ok(ids) => for id in ids {
+            println!("Finalized result of poll: {id}");
+        },
+    }
+}
+
+/// Forget the responses kept for retries once their keys expire
+pub fn expire_idempotency_keys() {
+    let connection = &mut establish_connection();
+
+    match idempotency::delete_expired(connection) {
+        Err(err) => println!("Failed to delete expired idempotency keys: {err}"),
+        Ok(0) => {},
+        Ok(count) => println!("Deleted {count} expired idempotency keys"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_stops_jobs() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let mut jobs = Jobs::new(shutdown_rx);
+
+        let job_runs = runs.clone();
+        jobs.every("counter", Duration::from_millis(10), move || {
+            job_runs.fetch_add(1, Ordering::SeqCst);
+        });
+
+        time::sleep(Duration::from_millis(35)).await;
+        shutdown.send(true).unwrap();
+        time::timeout(Duration::from_secs(1), jobs.join()).await
+            .expect("Jobs should stop promptly on shutdown");
+
+        let stopped_at = runs.load(Ordering::SeqCst);
+        assert!(stopped_at >= 1, "Job should run immediately");
+
+        time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), stopped_at, "Job should not run after shutdown");
+    }
+}
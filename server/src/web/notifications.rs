@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use reqwest::StatusCode;
+use tokio::sync::watch;
+use tokio::{task, time};
+use uuid::Uuid;
+
+use crate::error;
+use super::bot::{Bot, ConversationReference};
+use super::card_api::{self, CardView};
+use super::db::{establish_connection, schema};
+
+pub const ANNOUNCE_PERIOD: Duration = Duration::from_secs(30);
+
+/// How long an announcement is held by the server instance that claimed it, before others may try
+const ANNOUNCE_LEASE: Duration = Duration::from_secs(120);
+
+/// A closed poll's result card, and the conversation it should be posted to
+struct Announcement {
+    poll_id: Uuid,
+    reference: ConversationReference,
+    card: serde_json::Value,
+}
+
+/// Post the result of every closed poll to the conversation it was created in, until shutdown
+pub async fn announce_results(bot: Arc<Bot>, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = time::interval(ANNOUNCE_PERIOD);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => return,
+            _ = interval.tick() => announce_pending(&bot).await,
+        }
+    }
+}
+
+/// Announce the results that haven't been yet. Announcements that fail for a reason that may pass
+/// are tried again once their claim runs out.
+async fn announce_pending(bot: &Bot) {
+    let pending = match task::spawn_blocking(|| pending(&mut establish_connection())).await {
+        Err(err) => {
+            println!("Failed to load result announcements: {err}");
+            return;
+        },
+        Ok(Err(err)) => {
+            println!("Failed to load result announcements: {err}");
+            return;
+        },
+        Ok(Ok(pending)) => pending,
+    };
+
+    for Announcement { poll_id, reference, card } in pending {
+        match bot.send_card(&reference, card).await {
+            Ok(()) => println!("Announced result of poll: {poll_id}"),
+            // the bot was removed from the conversation or it no longer exists, it won't get better
+            Err(err) if err.status().is_some_and(|s| s.is_client_error() && s != StatusCode::TOO_MANY_REQUESTS) => {
+                println!("Gave up announcing result of poll {poll_id}: {err}");
+            },
+            Err(err) => {
+                println!("Failed to announce result of poll {poll_id}, will retry: {err}");
+                continue;
+            },
+        }
+
+        match task::spawn_blocking(move || mark_announced(&mut establish_connection(), &poll_id)).await {
+            Err(err) => println!("Failed to mark result of poll {poll_id} as announced: {err}"),
+            Ok(Err(err)) => println!("Failed to mark result of poll {poll_id} as announced: {err}"),
+            Ok(Ok(())) => {},
+        }
+    }
+}
+
+/// Closed polls with a finalized result and a conversation to announce it in
+fn pending(connection: &mut PgConnection) -> Result<Vec<Announcement>, error::HttpError> {
+    let rows = match claim(connection, Utc::now().naive_utc()) {
+        Err(err) => return Err(error::internal(err)),
+        Ok(r) => r,
+    };
+
+    let mut announcements = vec![];
+    for (poll_id, reference) in rows {
+        let reference = match serde_json::from_value(reference.unwrap_or_default()) {
+            Err(err) => {
+                println!("Gave up announcing result of poll {poll_id}, its conversation reference is invalid: {err}");
+                if let Err(err) = mark_announced(connection, &poll_id) {
+                    return Err(error::internal(err));
+                }
+                continue;
+            },
+            Ok(r) => r,
+        };
+        let card = card_api::render(connection, &poll_id, None, CardView::Result)?;
+        announcements.push(Announcement { poll_id, reference, card });
+    }
+    Ok(announcements)
+}
+
+/// Take the announcements that are due, holding them for long enough to be posted so that other
+/// server instances don't post them too
+fn claim(connection: &mut PgConnection, now: NaiveDateTime) -> Result<Vec<(Uuid, Option<serde_json::Value>)>, DbError> {
+    connection.transaction(|connection| {
+        let rows: Vec<(Uuid, Option<serde_json::Value>)> = schema::polls::table
+            .filter(
+                schema::polls::conversation_reference.is_not_null()
+                .and(schema::polls::announced_at.is_null())
+                .and(schema::polls::announcement_claimed_until.is_null().or(schema::polls::announcement_claimed_until.le(now)))
+                .and(exists(schema::pollresults::table.filter(schema::pollresults::poll_id.eq(schema::polls::id))))
+            )
+            .select((schema::polls::id, schema::polls::conversation_reference))
+            .for_update()
+            .skip_locked()
+            .load(connection)?;
+        if rows.is_empty() {
+            return Ok(rows);
+        }
+
+        let lease = TimeDelta::from_std(ANNOUNCE_LEASE).expect("Lease fits in a TimeDelta");
+        let ids: Vec<Uuid> = rows.iter().map(|(id, _)| *id).collect();
+        diesel::update(schema::polls::table.filter(schema::polls::id.eq_any(&ids)))
+            .set(schema::polls::announcement_claimed_until.eq(now + lease))
+            .execute(connection)?;
+        Ok(rows)
+    })
+}
+
+fn mark_announced(connection: &mut PgConnection, poll_id: &Uuid) -> Result<(), DbError> {
+    diesel::update(schema::polls::table.find(poll_id))
+        .set(schema::polls::announced_at.eq(Utc::now().naive_utc()))
+        .execute(connection)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    use crate::voting;
+    use super::*;
+    use super::super::result_api;
+    use super::super::testing::TestPoll;
+
+    #[tokio::test]
+    async fn closed_polls_are_announced_once() {
+        // stand in for the Bot Connector service, capturing what the bot sends it
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let mock_connector = warp::path!("v3" / "conversations" / String / "activities")
+            .and(warp::body::json::<serde_json::Value>())
+            .map(move |conversation, body| {
+                sender.send((conversation, body)).unwrap();
+                warp::reply::json(&json!({ "id": "announcement" }))
+            });
+        let (address, server) = warp::serve(mock_connector).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let test_poll = task::spawn_blocking(move || {
+            let settings = voting::CreatePollSettings::try_from(voting::UnvalidatedCreatePollSettings {
+                title: String::from("Lunch"),
+                options: vec![String::from("Pizza"), String::from("Tacos")],
+                ..Default::default()
+            }).unwrap();
+            let reference = json!({
+                "serviceUrl": "https://smba.invalid/",
+                "conversation": { "id": "conversation-1" },
+                "bot": { "id": "28:bot" },
+            });
+            let test_poll = TestPoll::create_in(settings, reference);
+
+            let connection = &mut establish_connection();
+            diesel::update(schema::polls::table.find(test_poll.id()))
+                .set(schema::polls::closed_at.eq(Utc::now().naive_utc()))
+                .execute(connection).unwrap();
+            result_api::finalize_closed(connection).unwrap();
+            test_poll
+        }).await.unwrap();
+
+        let bot = Bot::new(None, String::new(), String::new(), Some(format!("http://{address}")));
+        announce_pending(&bot).await;
+
+        let (conversation, body) = received.recv().await.unwrap();
+        assert_eq!(conversation, "conversation-1");
+        assert_eq!(body["from"]["id"], "28:bot");
+        assert_eq!(body["attachments"][0]["content"]["body"][0]["text"], "Lunch");
+
+        announce_pending(&bot).await;
+        assert!(received.try_recv().is_err(), "Results should only be announced once");
+
+        task::spawn_blocking(move || drop(test_poll)).await.unwrap();
+    }
+}
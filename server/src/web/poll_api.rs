@@ -1,19 +1,44 @@
+use std::ops::RangeInclusive;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::dsl::{count, exists};
 use diesel::prelude::*;
 use diesel::result::Error as DbError;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use warp::http::StatusCode;
 use warp::reply::{self, Reply, Response};
 
 use crate::voting;
 use super::db::{establish_connection, models, schema};
+use super::audit;
+use super::events;
+use super::idempotency::{self, IdempotencyKey, Replayable};
+use super::webhooks;
 use crate::error;
 
-pub fn new(user_id: Uuid, settings: voting::CreatePollSettings) -> Response {
+pub fn new(user_id: Uuid, idempotency_key: Option<IdempotencyKey>, settings: voting::CreatePollSettings) -> Response {
     let connection = &mut establish_connection();
 
-    // todo: get owner = session user
-    let owner = models::User { id: user_id, display_name: String::from("Anonymous") };
-    let (settings, options) = models::CreatePollSettings::from(&owner.id, settings);
+    let request = idempotency::Request::new(String::from("POST /api/poll"), &settings);
+    idempotency::once(connection, &user_id, idempotency_key, request, |connection| {
+        // todo: get owner = session user
+        let owner = models::User { id: user_id, display_name: String::from("Anonymous") };
+        let poll = create(connection, owner, settings, None)?;
+        Ok(Replayable::new(StatusCode::CREATED, &poll))
+    })
+}
+
+/// Store a new poll owned by `owner`, adding the owner as a user if they aren't known yet. Polls
+/// created in a Teams conversation keep a reference to it, to announce their result there.
+pub fn create(
+    connection: &mut PgConnection,
+    owner: models::User,
+    settings: voting::CreatePollSettings,
+    conversation_reference: Option<serde_json::Value>,
+) -> Result<voting::Poll, error::HttpError> {
+    let (mut settings, options) = models::CreatePollSettings::from(&owner.id, settings);
+    settings.conversation_reference = conversation_reference;
     let mut options: Vec<models::PollOption> = options.into_iter().enumerate().map(|(index, label)| {
         models::PollOption {
             id: index as i32,
@@ -39,25 +64,26 @@ pub fn new(user_id: Uuid, settings: voting::CreatePollSettings) -> Response {
 
         diesel::insert_into(schema::polloptions::table).values(&options).execute(connection)?;
 
+        let details = serde_json::json!({
+            "title": poll.title,
+            "options": options.iter().map(|o| &o.description).collect::<Vec<_>>(),
+            "winner_count": poll.winner_count,
+            "seed_commitment": poll.seed_commitment,
+        });
+        audit::record(connection, &poll.id, Some(&owner.id), audit::Action::PollCreated, details)?;
+
         Ok(poll)
     });
 
     let poll = match result {
         Err(err) => {
-            return error::db_insert(err, "poll").into_response();
+            return Err(error::db_insert(err, "poll"));
         },
         Ok(p) => p,
     };
 
-    match get_internal(connection, &poll.id) {
-        Err(err) => {
-            reply::with_status(
-                format!("Failed to fetch poll with id {} after creating: {err:?}", &poll.id),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ).into_response()
-        },
-        Ok(poll) => reply::with_status(reply::json(&poll), StatusCode::CREATED).into_response(),
-    }
+    webhooks::enqueue(connection, &poll.id, webhooks::Event::PollCreated);
+    get_internal(connection, &poll.id)
 }
 
 pub fn get(id: Uuid) -> Response {
@@ -68,41 +94,229 @@ pub fn get(id: Uuid) -> Response {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PollStatus {
+    Open,
+    Closed,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ListQuery {
+    /// Only polls owned by the requesting user
+    pub owned: bool,
+    /// Only polls the requesting user has cast a ballot in
+    pub voted: bool,
+    pub status: Option<PollStatus>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    /// Case-insensitive search of poll titles
+    pub q: Option<String>,
+    /// Where the previous page left off, from its `next_cursor`
+    pub cursor: Option<String>,
+    pub limit: i64,
+}
+
+impl Default for ListQuery {
+    fn default() -> Self {
+        Self {
+            owned: false,
+            voted: false,
+            status: None,
+            created_after: None,
+            created_before: None,
+            q: None,
+            cursor: None,
+            limit: 20,
+        }
+    }
+}
+
+const LIST_LIMIT_BOUNDS: RangeInclusive<i64> = 1 ..= 100;
+
+#[derive(Serialize)]
+pub struct PollList {
+    pub polls: Vec<voting::Poll>,
+    pub next_cursor: Option<String>,
+}
+
+/// Position in the list of polls, ordered newest first
+struct Cursor {
+    created_at: NaiveDateTime,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn parse(cursor: &str) -> Option<Self> {
+        let (micros, id) = cursor.split_once('_')?;
+        Some(Self {
+            created_at: DateTime::from_timestamp_micros(micros.parse().ok()?)?.naive_utc(),
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!("{}_{}", self.created_at.and_utc().timestamp_micros(), self.id.simple())
+    }
+}
+
+pub fn list(user_id: Option<Uuid>, query: ListQuery) -> Response {
+    let mut errors = error::ValidationErrors::new();
+    if !LIST_LIMIT_BOUNDS.contains(&query.limit) {
+        errors.push(error::list_limit_invalid(LIST_LIMIT_BOUNDS, query.limit).at("limit"));
+    }
+    let cursor = query.cursor.as_deref().map(Cursor::parse);
+    if let Some(None) = cursor {
+        errors.push(error::list_cursor_invalid().at("cursor"));
+    }
+    if !errors.is_empty() {
+        return error::HttpError::from(errors).into_response();
+    }
+
+    if (query.owned || query.voted) && user_id.is_none() {
+        return error::missing_header("user-id").into_response();
+    }
+
+    let mut db_query = schema::polls::table
+        .inner_join(schema::users::table)
+        .select((
+            models::Poll::as_select(),
+            models::User::as_select(),
+        ))
+        .order((schema::polls::created_at.desc(), schema::polls::id.desc()))
+        .limit(query.limit + 1)
+        .into_boxed();
+
+    if let Some(user_id) = user_id {
+        if query.owned {
+            db_query = db_query.filter(schema::polls::owner_id.eq(user_id));
+        }
+        if query.voted {
+            db_query = db_query.filter(schema::polls::id.eq_any(
+                schema::ballots::table
+                    .filter(schema::ballots::user_id.eq(user_id))
+                    .select(schema::ballots::poll_id)
+            ));
+        }
+    }
+    match query.status {
+        Some(PollStatus::Open) => db_query = db_query.filter(schema::polls::closed_at.is_null()),
+        Some(PollStatus::Closed) => db_query = db_query.filter(schema::polls::closed_at.is_not_null()),
+        None => {},
+    }
+    if let Some(after) = query.created_after {
+        db_query = db_query.filter(schema::polls::created_at.ge(after.naive_utc()));
+    }
+    if let Some(before) = query.created_before {
+        db_query = db_query.filter(schema::polls::created_at.lt(before.naive_utc()));
+    }
+    if let Some(q) = &query.q {
+        // match the search text literally, not as a pattern
+        let q = q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        db_query = db_query.filter(schema::polls::title.ilike(format!("%{q}%")));
+    }
+    if let Some(Some(cursor)) = cursor {
+        db_query = db_query.filter(
+            schema::polls::created_at.lt(cursor.created_at)
+            .or(schema::polls::created_at.eq(cursor.created_at).and(schema::polls::id.lt(cursor.id)))
+        );
+    }
+
+    let connection = &mut establish_connection();
+    let result: Result<Vec<(models::Poll, models::User)>, DbError> = db_query.load(connection);
+    let mut rows = match result {
+        Err(err) => return error::internal(err).into_response(),
+        Ok(r) => r,
+    };
+
+    let next_cursor = if rows.len() as i64 > query.limit {
+        rows.truncate(query.limit as usize);
+        rows.last().map(|(poll, _)| Cursor { created_at: poll.created_at, id: poll.id }.encode())
+    }
+    else {
+        None
+    };
+
+    let (db_polls, db_users): (Vec<models::Poll>, Vec<models::User>) = rows.into_iter().unzip();
+    let options_result: Result<Vec<models::PollOption>, DbError> = models::PollOption::belonging_to(&db_polls)
+        .select(models::PollOption::as_select())
+        .order(schema::polloptions::id)
+        .load(connection);
+    let db_options = match options_result {
+        Err(err) => return error::internal(err).into_response(),
+        Ok(o) => o.grouped_by(&db_polls),
+    };
+
+    let mut polls = vec![];
+    for ((db_poll, db_options), db_user) in db_polls.into_iter().zip(db_options).zip(db_users) {
+        match (db_poll, db_options, db_user).try_into() {
+            Err(err) => return error::stored_data_invalid(err).into_response(),
+            Ok(poll) => polls.push(poll),
+        }
+    }
+
+    reply::json(&PollList { polls, next_cursor }).into_response()
+}
+
 pub fn update(poll_id: Uuid, user_id: Uuid, settings: voting::UpdatePollSettings) -> Response {
+    let details = serde_json::to_value(&settings).expect("poll settings serialize");
     let settings = models::UpdatePollSettings::from(settings);
 
     let connection = &mut establish_connection();
-    let update = diesel::update(
-        schema::polls::table.filter(
-            schema::polls::id.eq(poll_id)
-            .and(schema::polls::owner_id.eq(user_id))
-        )
-    ).set(settings).execute(connection);
+    let update = connection.transaction::<Result<usize, error::HttpError>, DbError, _>(|connection| {
+        // voters cast their ballots under the policy they were shown, so it stays as it was once
+        // there are any, and the poll is locked so that none are cast while it changes
+        if settings.ballot_changes_allowed.is_some() || settings.ballot_withdrawals_allowed.is_some() {
+            let policy: Option<(bool, bool)> = schema::polls::table
+                .filter(schema::polls::id.eq(poll_id).and(schema::polls::owner_id.eq(user_id)))
+                .select((schema::polls::ballot_changes_allowed, schema::polls::ballot_withdrawals_allowed))
+                .for_update()
+                .first(connection)
+                .optional()?;
+            if let Some((changes_allowed, withdrawals_allowed)) = policy {
+                let changed = settings.ballot_changes_allowed.is_some_and(|allowed| allowed != changes_allowed)
+                    || settings.ballot_withdrawals_allowed.is_some_and(|allowed| allowed != withdrawals_allowed);
+                let has_ballots: bool = diesel::select(exists(
+                    schema::ballots::table.filter(schema::ballots::poll_id.eq(poll_id))
+                )).get_result(connection)?;
+                if changed && has_ballots {
+                    return Ok(Err(error::poll_ballot_policy_locked()));
+                }
+            }
+        }
+
+        let updated = diesel::update(
+            schema::polls::table.filter(
+                schema::polls::id.eq(poll_id)
+                .and(schema::polls::owner_id.eq(user_id))
+            )
+        ).set(settings).execute(connection)?;
+        if updated > 0 {
+            audit::record(connection, &poll_id, Some(&user_id), audit::Action::PollUpdated, details)?;
+        }
+        Ok(Ok(updated))
+    });
 
     match update {
         Err(DbError::QueryBuilderError(_)) => {
-            reply::with_status(
-                format!("Cannot update poll {poll_id} without new values"),
-                StatusCode::BAD_REQUEST,
-            ).into_response()
+            error::HttpError::from(error::poll_no_changes()).into_response()
         },
         Err(err) => {
-            reply::with_status(
-                format!("Failed to update poll with id {poll_id}: {err}"),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ).into_response()
+            error::internal(err).into_response()
         },
-        Ok(0) => {
-            reply::with_status(reply::reply(), StatusCode::FORBIDDEN).into_response()
+        Ok(Err(err)) => {
+            err.into_response()
         },
-        Ok(_) => match get_internal(connection, &poll_id) {
+        Ok(Ok(0)) => {
+            error::forbidden("poll").into_response()
+        },
+        Ok(Ok(_)) => match get_internal(connection, &poll_id) {
             Err(err) => {
-                reply::with_status(
-                    format!("Update successful, but failed to retrieve result: {err:?}"),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                ).into_response()
+                err.into_response()
             },
             Ok(poll) => {
+                events::notify(connection, &poll_id, events::Change::PollUpdated);
                 reply::with_status(
                     reply::json(&poll),
                     StatusCode::OK,
@@ -114,30 +328,96 @@ pub fn update(poll_id: Uuid, user_id: Uuid, settings: voting::UpdatePollSettings
 
 pub fn delete(poll_id: Uuid, user_id: Uuid) -> Response {
     let connection = &mut establish_connection();
-    let delete = diesel::delete(
-        schema::polls::table.filter(
-            schema::polls::id.eq(poll_id)
-            .and(schema::polls::owner_id.eq(user_id))
-        ),
-    ).execute(connection);
+    let delete = connection.transaction::<usize, DbError, _>(|connection| {
+        let deleted = diesel::delete(
+            schema::polls::table.filter(
+                schema::polls::id.eq(poll_id)
+                .and(schema::polls::owner_id.eq(user_id))
+            ),
+        ).execute(connection)?;
+        if deleted > 0 {
+            audit::record(connection, &poll_id, Some(&user_id), audit::Action::PollDeleted, serde_json::json!({}))?;
+        }
+        Ok(deleted)
+    });
 
     match delete {
         Err(err) => {
-            reply::with_status(
-                format!("Failed to delete poll with id {poll_id}: {err}"),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ).into_response()
+            error::internal(err).into_response()
         },
         Ok(0) => {
-            reply::with_status(reply::reply(), StatusCode::NOT_FOUND).into_response()
+            error::not_found("poll").into_response()
         },
         Ok(_) => {
+            events::notify(connection, &poll_id, events::Change::PollDeleted);
             reply::with_status(reply::reply(), StatusCode::NO_CONTENT).into_response()
         },
     }
 }
 
-pub fn get_internal(connection: &mut PgConnection, id: &Uuid) -> Result<voting::Poll, error::HttpGetError> {
+/// Close every open poll that has passed its closing time or received its closing number of ballots,
+/// returning the IDs of the polls that were closed
+pub fn close_expired(connection: &mut PgConnection) -> Result<Vec<Uuid>, DbError> {
+    let now = Utc::now().naive_utc();
+
+    let closed = connection.transaction::<Vec<Uuid>, DbError, _>(|connection| {
+        let mut closed: Vec<Uuid> = diesel::update(
+            schema::polls::table.filter(
+                schema::polls::closed_at.is_null()
+                .and(schema::polls::close_after_time.le(now))
+            )
+        )
+            .set(schema::polls::closed_at.eq(now))
+            .returning(schema::polls::id)
+            .get_results(connection)?;
+        for poll_id in closed.iter() {
+            audit::record(connection, poll_id, None, audit::Action::PollClosed, serde_json::json!({ "reason": "time" }))?;
+        }
+
+        let ballot_counts: Vec<(Uuid, Option<i32>, i64)> = schema::polls::table
+            .left_join(schema::ballots::table)
+            .filter(
+                schema::polls::closed_at.is_null()
+                .and(schema::polls::close_after_votes.is_not_null())
+            )
+            .group_by(schema::polls::id)
+            .select((
+                schema::polls::id,
+                schema::polls::close_after_votes,
+                count(schema::ballots::id.nullable()),
+            ))
+            .load(connection)?;
+
+        let full: Vec<Uuid> = ballot_counts.into_iter()
+            .filter(|(_, limit, count)| limit.is_some_and(|limit| *count >= limit as i64))
+            .map(|(id, ..)| id)
+            .collect();
+        if !full.is_empty() {
+            let mut full = diesel::update(
+                schema::polls::table.filter(
+                    schema::polls::id.eq_any(full)
+                    .and(schema::polls::closed_at.is_null())
+                )
+            )
+                .set(schema::polls::closed_at.eq(now))
+                .returning(schema::polls::id)
+                .get_results(connection)?;
+            for poll_id in full.iter() {
+                audit::record(connection, poll_id, None, audit::Action::PollClosed, serde_json::json!({ "reason": "votes" }))?;
+            }
+            closed.append(&mut full);
+        }
+
+        Ok(closed)
+    })?;
+
+    for poll_id in closed.iter() {
+        webhooks::enqueue(connection, poll_id, webhooks::Event::PollClosed);
+    }
+    Ok(closed)
+}
+
+pub fn get_internal(connection: &mut PgConnection, id: &Uuid) -> Result<voting::Poll, error::HttpError> {
     // fetch poll from db
     let poll_result: Result<(models::Poll, models::User), DbError> = schema::polls::table.find(id)
         .inner_join(schema::users::table)
@@ -148,11 +428,8 @@ pub fn get_internal(connection: &mut PgConnection, id: &Uuid) -> Result<voting::
         .first(connection);
 
     let (db_poll, db_user) = match poll_result {
-        Err(err @ DbError::NotFound) => {
-            return Err(error::db_get(err, StatusCode::NOT_FOUND, "poll/owner", None));
-        }
         Err(err) => {
-            return Err(error::db_get(err, StatusCode::INTERNAL_SERVER_ERROR, "poll/owner", None));
+            return Err(error::db_get(err, "poll"));
         },
         Ok(r) => r,
     };
@@ -163,13 +440,13 @@ pub fn get_internal(connection: &mut PgConnection, id: &Uuid) -> Result<voting::
 
     let db_options = match options_result {
         Err(err) => {
-            return Err(error::db_get(err, StatusCode::INTERNAL_SERVER_ERROR, "option", Some("poll")));
+            return Err(error::db_get(err, "poll option"));
         },
         Ok(o) => o,
     };
 
     let poll: voting::Poll = match (db_poll, db_options, db_user).try_into() {
-        Err(err) => return Err(error::HttpGetError::from(err)),
+        Err(err) => return Err(error::stored_data_invalid(err)),
         Ok(p) => p,
     };
 
@@ -181,10 +458,11 @@ mod tests {
     use std::error::Error as StdError;
 
     use super::*;
+    use super::super::testing::TestPoll;
     use warp::hyper::body;
 
     async fn setup(settings: &voting::CreatePollSettings) -> Result<voting::Poll, Box<dyn StdError>> {
-        let res = new(Uuid::nil(), voting::CreatePollSettings::from(settings.clone()));
+        let res = new(Uuid::nil(), None, settings.clone());
         let res_bytes = body::to_bytes(res.into_body()).await?;
         let res_poll: voting::Poll = serde_json::from_reader(res_bytes.as_ref())?;
 
@@ -218,4 +496,41 @@ mod tests {
         teardown(poll).await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn list_owned_pages() -> Result<(), Box<dyn StdError>> {
+        let owner_id = Uuid::new_v4();
+        let _polls: Vec<TestPoll> = ["List test one", "List test two", "List test three"].into_iter()
+            .map(|title| TestPoll::create_owned(owner_id, voting::CreatePollSettings {
+                title: String::from(title),
+                ..voting::CreatePollSettings::default()
+            }))
+            .collect();
+
+        let mut titles = vec![];
+        let mut cursor = None;
+        loop {
+            let query = ListQuery { owned: true, limit: 2, cursor, ..ListQuery::default() };
+            let res = list(Some(owner_id), query);
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let page: serde_json::Value = serde_json::from_slice(&body::to_bytes(res.into_body()).await?)?;
+            for poll in page["polls"].as_array().unwrap() {
+                titles.push(poll["title"].as_str().unwrap().to_string());
+            }
+            match page["next_cursor"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => break,
+            }
+        }
+        assert_eq!(titles, ["List test three", "List test two", "List test one"], "Newest first, across pages");
+
+        let query = ListQuery { owned: true, q: Some(String::from("TWO")), ..ListQuery::default() };
+        let page: serde_json::Value = serde_json::from_slice(&body::to_bytes(list(Some(owner_id), query).into_body()).await?)?;
+        assert_eq!(page["polls"].as_array().unwrap().len(), 1, "Title search");
+
+        let res = list(None, ListQuery { owned: true, ..ListQuery::default() });
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "Owned polls need a user");
+        Ok(())
+    }
 }
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+use warp::{Filter, Rejection};
+
+use crate::error;
+
+pub const PRUNE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// How many more requests an address can make than a user, since many users can share an address
+/// behind a proxy or NAT
+const DEFAULT_IP_FACTOR: u32 = 10;
+
+/// How many buckets are kept in memory at most
+const DEFAULT_MAX_CLIENTS: usize = 100_000;
+
+/// The kinds of requests that are limited separately
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Bucket {
+    PollCreation,
+    BallotSubmission,
+    ResultRead,
+}
+
+impl Bucket {
+    fn env_var(&self) -> &'static str {
+        match self {
+            Bucket::PollCreation => "RATE_LIMIT_POLL_CREATION",
+            Bucket::BallotSubmission => "RATE_LIMIT_BALLOT_SUBMISSION",
+            Bucket::ResultRead => "RATE_LIMIT_RESULT_READ",
+        }
+    }
+
+    fn default_limit(&self) -> Limit {
+        match self {
+            Bucket::PollCreation => Limit { requests: 20, period: Duration::from_secs(60 * 60) },
+            Bucket::BallotSubmission => Limit { requests: 60, period: Duration::from_secs(60) },
+            Bucket::ResultRead => Limit { requests: 120, period: Duration::from_secs(60) },
+        }
+    }
+}
+
+/// At most `requests` requests in a burst, with the allowance refilling evenly over `period`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    fn scaled(self, factor: u32) -> Self {
+        Limit { requests: self.requests.saturating_mul(factor), ..self }
+    }
+
+    fn per_second(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+/// Parses limits written as `<requests>/<seconds>`, such as `20/3600` for 20 requests an hour
+impl FromStr for Limit {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (requests, seconds) = s.trim().split_once('/').ok_or("limits must be <requests>/<seconds>")?;
+        let requests = requests.trim().parse().map_err(|_| "limit requests must be a whole number")?;
+        let seconds = seconds.trim().parse().map_err(|_| "limit seconds must be a whole number")?;
+        if requests == 0 || seconds == 0 {
+            return Err("limits must allow at least one request over at least one second");
+        }
+        Ok(Limit { requests, period: Duration::from_secs(seconds) })
+    }
+}
+
+/// Who a request is counted against
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    User(Uuid),
+    /// IPv6 addresses are counted by their /64 network, since one host can have all of it
+    Ip(IpAddr),
+}
+
+impl Client {
+    fn ip(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V6(v6) if v6.to_ipv4_mapped().is_none() => {
+                let network = u128::from(v6) & !(u64::MAX as u128);
+                Client::Ip(IpAddr::V6(network.into()))
+            },
+            _ => Client::Ip(ip.to_canonical()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct State {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl State {
+    /// The state of a bucket at `now`, which is full if it wasn't used before
+    fn refilled(state: Option<&State>, limit: &Limit, now: Instant) -> Self {
+        let Some(state) = state else {
+            return State { tokens: limit.requests as f64, updated: now };
+        };
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        State {
+            tokens: (state.tokens + elapsed * limit.per_second()).min(limit.requests as f64),
+            updated: now,
+        }
+    }
+}
+
+/// Token buckets for each kind of request, kept in memory for each user and each address
+pub struct RateLimiter {
+    limits: HashMap<Bucket, Limit>,
+    ip_factor: u32,
+    trusted_proxies: usize,
+    max_clients: usize,
+    states: Mutex<HashMap<(Bucket, Client), State>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<Bucket, Limit>, ip_factor: u32, trusted_proxies: usize, max_clients: usize) -> Self {
+        Self { limits, ip_factor, trusted_proxies, max_clients, states: Mutex::new(HashMap::new()) }
+    }
+
+    /// Read the limits from `RATE_LIMIT_POLL_CREATION`, `RATE_LIMIT_BALLOT_SUBMISSION` and
+    /// `RATE_LIMIT_RESULT_READ`, any of which can be `off`. Addresses get `RATE_LIMIT_IP_FACTOR`
+    /// times the allowance of a user, and are taken from `X-Forwarded-For` only when
+    /// `RATE_LIMIT_TRUST_FORWARDED_FOR` is `true` for one proxy in front of the server, or the number
+    /// of proxies in front of it, since clients can set it to anything. At most
+    /// `RATE_LIMIT_MAX_CLIENTS` buckets are kept.
+    pub fn from_env() -> Self {
+        let mut limits = HashMap::new();
+        for bucket in [Bucket::PollCreation, Bucket::BallotSubmission, Bucket::ResultRead] {
+            let limit = match env::var(bucket.env_var()) {
+                Err(_) => Some(bucket.default_limit()),
+                Ok(value) if value.trim().eq_ignore_ascii_case("off") => None,
+                Ok(value) => match value.parse() {
+                    Ok(limit) => Some(limit),
+                    Err(err) => {
+                        println!("Invalid {}, using the default: {err}", bucket.env_var());
+                        Some(bucket.default_limit())
+                    },
+                },
+            };
+            if let Some(limit) = limit {
+                limits.insert(bucket, limit);
+            }
+        }
+
+        let ip_factor = env::var("RATE_LIMIT_IP_FACTOR").ok()
+            .and_then(|f| f.parse().ok())
+            .filter(|&f| f > 0)
+            .unwrap_or(DEFAULT_IP_FACTOR);
+        let trusted_proxies = match env::var("RATE_LIMIT_TRUST_FORWARDED_FOR") {
+            Ok(t) if t == "true" => 1,
+            Ok(t) => t.parse().unwrap_or(0),
+            Err(_) => 0,
+        };
+        let max_clients = env::var("RATE_LIMIT_MAX_CLIENTS").ok()
+            .and_then(|m| m.parse().ok())
+            .filter(|&m| m > 0)
+            .unwrap_or(DEFAULT_MAX_CLIENTS);
+        Self::new(limits, ip_factor, trusted_proxies, max_clients)
+    }
+
+    /// Take a request from the address's bucket and the user's bucket, or if either is empty, take
+    /// nothing and return how many seconds to wait before retrying. User IDs are chosen by clients,
+    /// so the address is checked first, and a rejected request never adds a bucket.
+    fn check(&self, bucket: Bucket, user_id: Option<Uuid>, ip: Option<IpAddr>, now: Instant) -> Result<(), u64> {
+        let Some(&limit) = self.limits.get(&bucket) else {
+            return Ok(());
+        };
+        let clients = [
+            ip.map(|ip| (Client::ip(ip), limit.scaled(self.ip_factor))),
+            user_id.map(|id| (Client::User(id), limit)),
+        ];
+
+        let mut states = self.states.lock().unwrap();
+        let mut refilled = Vec::with_capacity(clients.len());
+        for (client, limit) in clients.iter().flatten() {
+            let state = State::refilled(states.get(&(bucket, *client)), limit, now);
+            if state.tokens < 1.0 {
+                return Err(((1.0 - state.tokens) / limit.per_second()).ceil() as u64);
+            }
+            refilled.push(((bucket, *client), state));
+        }
+
+        for (key, mut state) in refilled {
+            state.tokens -= 1.0;
+            if !states.contains_key(&key) {
+                self.make_room(&mut states, now);
+            }
+            states.insert(key, state);
+        }
+        Ok(())
+    }
+
+    /// Take a request from the bucket of a user who was authenticated some other way, such as the
+    /// Teams bot's users, whose requests all come from the addresses of the Bot Framework
+    pub fn check_user(&self, bucket: Bucket, user_id: &Uuid) -> Result<(), error::HttpError> {
+        self.check(bucket, Some(*user_id), None, Instant::now()).map_err(error::rate_limited)
+    }
+
+    /// Forget the buckets that would have refilled by now, so idle clients don't take up memory
+    pub fn prune(&self) {
+        let mut states = self.states.lock().unwrap();
+        self.forget_refilled(&mut states, Instant::now());
+    }
+
+    fn forget_refilled(&self, states: &mut HashMap<(Bucket, Client), State>, now: Instant) {
+        states.retain(|(bucket, _), state| {
+            self.limits.get(bucket).is_some_and(|limit| now.saturating_duration_since(state.updated) < limit.period)
+        });
+    }
+
+    /// Keep the number of buckets under the maximum, first by forgetting the ones that have
+    /// refilled, then if that isn't enough, by forgetting the half that were used least recently
+    fn make_room(&self, states: &mut HashMap<(Bucket, Client), State>, now: Instant) {
+        if states.len() < self.max_clients {
+            return;
+        }
+        self.forget_refilled(states, now);
+        if states.len() < self.max_clients {
+            return;
+        }
+
+        let mut updated: Vec<Instant> = states.values().map(|s| s.updated).collect();
+        let middle = updated.len() / 2;
+        let (_, &mut cutoff, _) = updated.select_nth_unstable(middle);
+        states.retain(|_, state| state.updated > cutoff);
+    }
+
+    /// The address a request came from. Each proxy appends the address it was connected from to
+    /// `X-Forwarded-For`, so the client's address is as many entries from the right as there are
+    /// trusted proxies, and anything left of it was written by the client.
+    fn client_ip(&self, remote: Option<SocketAddr>, forwarded_for: Option<String>) -> Option<IpAddr> {
+        let forwarded = forwarded_for
+            .filter(|_| self.trusted_proxies > 0)
+            .and_then(|f| f.rsplit(',').nth(self.trusted_proxies - 1).and_then(|ip| ip.trim().parse().ok()));
+        forwarded.or(remote.map(|addr| addr.ip()))
+    }
+}
+
+/// Reject requests over the limit for `bucket` with a 429, counting them against the `user-id` of
+/// the request if it has one, and against the address it came from
+pub fn limit(limiter: Arc<RateLimiter>, bucket: Bucket) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("user-id"))
+        .and_then(move |remote: Option<SocketAddr>, forwarded_for: Option<String>, user_id: Option<String>| {
+            let limiter = limiter.clone();
+            async move {
+                // an invalid user ID is rejected by the route itself, so it's only counted by address
+                let user_id = user_id.and_then(|id| id.parse().ok());
+                let ip = limiter.client_ip(remote, forwarded_for);
+                limiter.check(bucket, user_id, ip, Instant::now())
+                    .map_err(|retry_after| warp::reject::custom(error::rate_limited(retry_after)))
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_empty_and_refill() {
+        assert_eq!("20/3600".parse(), Ok(Limit { requests: 20, period: Duration::from_secs(3600) }));
+        assert!("0/60".parse::<Limit>().is_err());
+        assert!("20".parse::<Limit>().is_err());
+
+        let limit = Limit { requests: 2, period: Duration::from_secs(60) };
+        let limiter = RateLimiter::new(HashMap::from([(Bucket::BallotSubmission, limit)]), 2, 0, 100);
+        let (alice, bob) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let ip = Some(IpAddr::from([10, 0, 0, 1]));
+        let start = Instant::now();
+
+        assert_eq!(limiter.check(Bucket::BallotSubmission, alice, ip, start), Ok(()));
+        assert_eq!(limiter.check(Bucket::BallotSubmission, alice, ip, start), Ok(()));
+        assert_eq!(limiter.check(Bucket::BallotSubmission, alice, ip, start), Err(30), "User bucket is empty");
+        assert_eq!(limiter.check(Bucket::ResultRead, alice, ip, start), Ok(()), "Buckets are separate");
+
+        assert_eq!(limiter.check(Bucket::BallotSubmission, bob, ip, start), Ok(()));
+        assert_eq!(limiter.check(Bucket::BallotSubmission, bob, ip, start), Ok(()));
+        assert_eq!(limiter.check(Bucket::BallotSubmission, None, ip, start), Err(15), "Address bucket is empty");
+
+        let later = start + Duration::from_secs(30);
+        assert_eq!(limiter.check(Bucket::BallotSubmission, alice, ip, later), Ok(()), "Buckets refill");
+        assert_eq!(limiter.check(Bucket::BallotSubmission, alice, ip, later), Err(30));
+    }
+
+    #[test]
+    fn buckets_are_bounded() {
+        let limit = Limit { requests: 1, period: Duration::from_secs(60) };
+        let limiter = RateLimiter::new(HashMap::from([(Bucket::PollCreation, limit)]), 1, 0, 4);
+        let ip = Some(IpAddr::from([203, 0, 113, 7]));
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(Bucket::PollCreation, Some(Uuid::new_v4()), ip, now), Ok(()));
+        for _ in 0..10 {
+            assert!(limiter.check(Bucket::PollCreation, Some(Uuid::new_v4()), ip, now).is_err(), "New user IDs don't help");
+        }
+        assert_eq!(limiter.states.lock().unwrap().len(), 2, "Rejected requests add no buckets");
+
+        let network: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(limiter.check(Bucket::PollCreation, None, Some(network), now), Ok(()));
+        let same_network: IpAddr = "2001:db8::2".parse().unwrap();
+        assert!(limiter.check(Bucket::PollCreation, None, Some(same_network), now).is_err(), "IPv6 networks share a bucket");
+
+        for i in 0..10 {
+            let later = now + Duration::from_secs(i);
+            let ip = Some(IpAddr::from([198, 51, 100, i as u8]));
+            assert_eq!(limiter.check(Bucket::PollCreation, None, ip, later), Ok(()));
+            assert!(limiter.states.lock().unwrap().len() <= 4);
+        }
+    }
+
+    #[test]
+    fn forwarded_for_is_read_from_the_right() {
+        let remote = Some(SocketAddr::from(([10, 0, 0, 2], 443)));
+        let forwarded_for = || Some("203.0.113.9, 198.51.100.4, 10.0.0.3".to_string());
+
+        let untrusted = RateLimiter::new(HashMap::new(), 1, 0, 100);
+        assert_eq!(untrusted.client_ip(remote, forwarded_for()), Some(IpAddr::from([10, 0, 0, 2])));
+
+        let one_proxy = RateLimiter::new(HashMap::new(), 1, 1, 100);
+        assert_eq!(one_proxy.client_ip(remote, forwarded_for()), Some(IpAddr::from([10, 0, 0, 3])), "Client entries are ignored");
+
+        let two_proxies = RateLimiter::new(HashMap::new(), 1, 2, 100);
+        assert_eq!(two_proxies.client_ip(remote, forwarded_for()), Some(IpAddr::from([198, 51, 100, 4])));
+
+        let too_many = RateLimiter::new(HashMap::new(), 1, 4, 100);
+        assert_eq!(too_many.client_ip(remote, forwarded_for()), Some(IpAddr::from([10, 0, 0, 2])), "Short headers fall back to the peer");
+    }
+}
@@ -0,0 +1,283 @@
+mod notifiers;
+
+use std::collections::HashSet;
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::{task, time};
+use uuid::Uuid;
+
+use crate::error;
+use crate::voting;
+use super::bot::{Bot, ConversationReference};
+use super::db::{establish_connection, schema};
+use super::poll_api::get_internal as get_poll;
+pub use notifiers::{EmailNotifier, TeamsNotifier, WebhookNotifier};
+
+pub const REMINDER_PERIOD: Duration = Duration::from_secs(60);
+
+/// How many hours before a poll closes a reminder is sent, latest first
+pub const REMINDER_HOURS: [i32; 2] = [24, 1];
+
+/// A member of the poll's conversation who hasn't voted yet
+#[derive(Clone, Debug, Serialize)]
+pub struct Recipient {
+    pub user_id: Uuid,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    #[serde(skip)]
+    pub teams_id: String,
+}
+
+/// A reminder to vote on a poll before it closes
+#[derive(Serialize)]
+pub struct Reminder {
+    pub poll: voting::Poll,
+    pub hours_before: i32,
+    pub recipients: Vec<Recipient>,
+    #[serde(skip)]
+    pub conversation: ConversationReference,
+}
+
+impl Reminder {
+    /// How long until the poll closes, in words
+    pub fn closes_in(&self) -> String {
+        match self.hours_before {
+            1 => String::from("1 hour"),
+            n => format!("{n} hours"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NotifyError(String);
+
+impl NotifyError {
+    pub fn new(message: impl Display) -> Self {
+        NotifyError(message.to_string())
+    }
+}
+
+impl Display for NotifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A way of reaching the recipients of a reminder
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn notify<'a>(&'a self, reminder: &'a Reminder) -> BoxFuture<'a, Result<(), NotifyError>>;
+}
+
+/// Reminds the members of a poll's conversation to vote before it closes. Only polls created in a
+/// Teams conversation have reminders, because the conversation is what decides who may vote.
+pub struct Reminders {
+    bot: Arc<Bot>,
+    notifiers: Vec<Box<dyn Notifier>>,
+}
+
+/// A poll with a reminder due, as loaded from the database
+struct Due {
+    poll: voting::Poll,
+    conversation: ConversationReference,
+    hours: Vec<i32>,
+    voted: HashSet<Uuid>,
+}
+
+impl Reminders {
+    pub fn new(bot: Arc<Bot>, notifiers: Vec<Box<dyn Notifier>>) -> Self {
+        Self { bot, notifiers }
+    }
+
+    /// Remind through Teams, plus email if `SMTP_HOST` and `SMTP_FROM` are set, and a webhook if
+    /// `REMINDER_WEBHOOK_URL` is set
+    pub fn from_env(bot: Arc<Bot>) -> Self {
+        let mut notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(TeamsNotifier::new(bot.clone()))];
+
+        if let (Ok(host), Ok(from)) = (env::var("SMTP_HOST"), env::var("SMTP_FROM")) {
+            let port = env::var("SMTP_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(25);
+            match EmailNotifier::new(&host, port, &from) {
+                Err(err) => println!("Email reminders are disabled: {err}"),
+                Ok(notifier) => notifiers.push(Box::new(notifier)),
+            }
+        }
+        if let Ok(url) = env::var("REMINDER_WEBHOOK_URL") {
+            notifiers.push(Box::new(WebhookNotifier::new(url)));
+        }
+
+        Self::new(bot, notifiers)
+    }
+
+    /// Send reminders as they come due, until shutdown
+    pub async fn run(self, mut shutdown: watch::Receiver<bool>) {
+        let mut interval = time::interval(REMINDER_PERIOD);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => self.send_due(Utc::now()).await,
+            }
+        }
+    }
+
+    async fn send_due(&self, now: DateTime<Utc>) {
+        let due = match task::spawn_blocking(move || due(&mut establish_connection(), now)).await {
+            Err(err) => {
+                println!("Failed to load due reminders: {err}");
+                return;
+            },
+            Ok(Err(err)) => {
+                println!("Failed to load due reminders: {err}");
+                return;
+            },
+            Ok(Ok(due)) => due,
+        };
+
+        for Due { poll, conversation, hours, voted } in due {
+            let poll_id = poll.id.0;
+
+            // membership can change while the poll is open, so it is looked up every time
+            let members = match self.bot.conversation_members(&conversation).await {
+                Err(err) => {
+                    println!("Failed to look up voters of poll {poll_id}, will retry: {err}");
+                    continue;
+                },
+                Ok(m) => m,
+            };
+            let recipients: Vec<Recipient> = members.into_iter()
+                .filter_map(|member| {
+                    let user_id = Uuid::parse_str(member.aad_object_id.as_deref()?).ok()?;
+                    Some(Recipient { user_id, name: member.name, email: member.email, teams_id: member.id })
+                })
+                .filter(|recipient| !voted.contains(&recipient.user_id))
+                .collect();
+
+            // when several reminders are due at once only the latest is worth sending
+            let hours_before = *hours.iter().min().expect("Due polls have at least one reminder due");
+            let reminder = Reminder { poll, hours_before, recipients, conversation };
+            if !reminder.recipients.is_empty() {
+                // one way of reminding voters is enough, but if every one failed it is tried again
+                let mut reminded = self.notifiers.is_empty();
+                for notifier in self.notifiers.iter() {
+                    match notifier.notify(&reminder).await {
+                        Err(err) => println!("Failed to send {} reminder for poll {poll_id}: {err}", notifier.name()),
+                        Ok(()) => reminded = true,
+                    }
+                }
+                if !reminded {
+                    println!("Failed to remind voters of poll {poll_id}, will retry");
+                    continue;
+                }
+                println!("Reminded {} voters of poll: {poll_id}", reminder.recipients.len());
+            }
+
+            match task::spawn_blocking(move || mark_sent(&mut establish_connection(), &poll_id, &hours)).await {
+                Err(err) => println!("Failed to record reminders of poll {poll_id}: {err}"),
+                Ok(Err(err)) => println!("Failed to record reminders of poll {poll_id}: {err}"),
+                Ok(Ok(())) => {},
+            }
+        }
+    }
+}
+
+/// The reminders of a poll closing at `closes_at` that are due by `now` and haven't been sent
+fn due_hours(closes_at: DateTime<Utc>, now: DateTime<Utc>, sent: &[i32]) -> Vec<i32> {
+    if closes_at <= now {
+        return vec![];
+    }
+
+    REMINDER_HOURS.into_iter()
+        .filter(|hours| closes_at - TimeDelta::hours(*hours as i64) <= now)
+        .filter(|hours| !sent.contains(hours))
+        .collect()
+}
+
+/// Open polls in a Teams conversation with a reminder due
+fn due(connection: &mut PgConnection, now: DateTime<Utc>) -> Result<Vec<Due>, error::HttpError> {
+    let horizon = now + TimeDelta::hours(REMINDER_HOURS.into_iter().max().unwrap_or(0) as i64);
+    let result: Result<Vec<(Uuid, Option<serde_json::Value>)>, DbError> = schema::polls::table
+        .filter(
+            schema::polls::closed_at.is_null()
+            .and(schema::polls::conversation_reference.is_not_null())
+            .and(schema::polls::close_after_time.gt(now.naive_utc()))
+            .and(schema::polls::close_after_time.le(horizon.naive_utc()))
+        )
+        .select((schema::polls::id, schema::polls::conversation_reference))
+        .load(connection);
+    let rows = match result {
+        Err(err) => return Err(error::internal(err)),
+        Ok(r) => r,
+    };
+
+    let mut due = vec![];
+    for (poll_id, conversation) in rows {
+        let Some(conversation) = conversation.and_then(|c| serde_json::from_value(c).ok()) else {
+            continue;
+        };
+        let poll = get_poll(connection, &poll_id)?;
+
+        let result = schema::pollreminders::table
+            .filter(schema::pollreminders::poll_id.eq(poll_id))
+            .select(schema::pollreminders::hours_before)
+            .load::<i32>(connection);
+        let sent = match result {
+            Err(err) => return Err(error::internal(err)),
+            Ok(s) => s,
+        };
+        let closes_at = poll.close_after_time.expect("Only polls with a closing time are selected");
+        let hours = due_hours(closes_at, now, &sent);
+        if hours.is_empty() {
+            continue;
+        }
+
+        let result = schema::ballots::table
+            .filter(schema::ballots::poll_id.eq(poll_id))
+            .select(schema::ballots::user_id)
+            .load::<Uuid>(connection);
+        let voted = match result {
+            Err(err) => return Err(error::internal(err)),
+            Ok(v) => v.into_iter().collect(),
+        };
+
+        due.push(Due { poll, conversation, hours, voted });
+    }
+    Ok(due)
+}
+
+fn mark_sent(connection: &mut PgConnection, poll_id: &Uuid, hours: &[i32]) -> Result<(), DbError> {
+    let rows: Vec<_> = hours.iter()
+        .map(|h| (schema::pollreminders::poll_id.eq(poll_id), schema::pollreminders::hours_before.eq(h)))
+        .collect();
+    diesel::insert_into(schema::pollreminders::table)
+        .values(rows)
+        .on_conflict_do_nothing()
+        .execute(connection)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reminders_come_due_once() {
+        let now = Utc::now();
+
+        assert_eq!(due_hours(now + TimeDelta::hours(30), now, &[]), Vec::<i32>::new());
+        assert_eq!(due_hours(now + TimeDelta::hours(20), now, &[]), [24]);
+        assert_eq!(due_hours(now + TimeDelta::hours(20), now, &[24]), Vec::<i32>::new());
+        assert_eq!(due_hours(now + TimeDelta::minutes(30), now, &[24]), [1]);
+        assert_eq!(due_hours(now + TimeDelta::minutes(30), now, &[]), [24, 1], "A short poll has every reminder due at once");
+        assert_eq!(due_hours(now - TimeDelta::minutes(1), now, &[]), Vec::<i32>::new(), "Closed polls need no reminders");
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde_json::json;
+
+use super::super::bot::{Activity, Bot};
+use super::super::cards;
+use super::{Notifier, NotifyError, Reminder};
+
+/// Mentions the recipients in the conversation the poll was shared in, along with the poll's card
+pub struct TeamsNotifier {
+    bot: Arc<Bot>,
+}
+
+impl TeamsNotifier {
+    pub fn new(bot: Arc<Bot>) -> Self {
+        Self { bot }
+    }
+}
+
+impl Notifier for TeamsNotifier {
+    fn name(&self) -> &'static str {
+        "Teams"
+    }
+
+    fn notify<'a>(&'a self, reminder: &'a Reminder) -> BoxFuture<'a, Result<(), NotifyError>> {
+        Box::pin(async move {
+            let mut mentions = vec![];
+            let mut entities = vec![];
+            for recipient in reminder.recipients.iter() {
+                let name = recipient.name.clone().unwrap_or_else(|| String::from("Anonymous"));
+                let mention = format!("<at>{name}</at>");
+                entities.push(json!({
+                    "type": "mention",
+                    "text": mention,
+                    "mentioned": { "id": recipient.teams_id, "name": name },
+                }));
+                mentions.push(mention);
+            }
+
+            let mut activity = Activity::card(cards::poll_card(&reminder.poll));
+            activity.text = Some(format!(
+                "\"{}\" closes in {}. {} still need to vote.",
+                reminder.poll.title, reminder.closes_in(), mentions.join(", "),
+            ));
+            activity.entities = entities;
+
+            self.bot.send(&reminder.conversation, activity).await.map_err(NotifyError::new)
+        })
+    }
+}
+
+/// Emails every recipient whose address is known, through an SMTP server. Mail is sent unencrypted,
+/// so the server should be a local relay or a stand-in like MailHog.
+pub struct EmailNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl EmailNotifier {
+    pub fn new(host: &str, port: u16, from: &str) -> Result<Self, NotifyError> {
+        Ok(Self {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host).port(port).build(),
+            from: from.parse().map_err(NotifyError::new)?,
+        })
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn notify<'a>(&'a self, reminder: &'a Reminder) -> BoxFuture<'a, Result<(), NotifyError>> {
+        Box::pin(async move {
+            let mut failed = 0;
+            for recipient in reminder.recipients.iter() {
+                let Some(email) = &recipient.email else {
+                    continue;
+                };
+
+                let result = async {
+                    let message = Message::builder()
+                        .from(self.from.clone())
+                        .to(Mailbox::new(recipient.name.clone(), email.parse().map_err(NotifyError::new)?))
+                        .subject(format!("Reminder: vote on \"{}\"", reminder.poll.title))
+                        .body(format!(
+                            "The poll \"{}\" closes in {}, and you haven't voted yet. Vote on it in Teams.",
+                            reminder.poll.title, reminder.closes_in(),
+                        ))
+                        .map_err(NotifyError::new)?;
+                    self.transport.send(message).await.map_err(NotifyError::new)
+                }.await;
+
+                if let Err(err) = result {
+                    println!("Failed to email reminder to {email}: {err}");
+                    failed += 1;
+                }
+            }
+
+            match failed {
+                0 => Ok(()),
+                n => Err(NotifyError::new(format!("{n} of the emails could not be sent"))),
+            }
+        })
+    }
+}
+
+/// Posts the reminder as JSON to a URL, for other systems to deliver
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String) -> Self {
+        Self { client: reqwest::Client::new(), url }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn notify<'a>(&'a self, reminder: &'a Reminder) -> BoxFuture<'a, Result<(), NotifyError>> {
+        Box::pin(async move {
+            self.client.post(&self.url)
+                .json(&json!({ "event": "poll.reminder", "reminder": reminder }))
+                .send().await
+                .and_then(|response| response.error_for_status())
+                .map_err(NotifyError::new)?;
+            Ok(())
+        })
+    }
+}
@@ -1,10 +1,15 @@
 use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use serde::Serialize;
 use uuid::Uuid;
 use warp::reply::{self, Reply, Response};
 use warp::http::StatusCode;
 
+use crate::error;
 use crate::voting;
 use super::db::{establish_connection, schema, models};
+use super::events;
+use super::webhooks;
 use super::poll_api::get_internal as get_poll;
 
 pub fn get_result(poll_id: Uuid) -> Response {
@@ -16,49 +21,293 @@ pub fn get_result(poll_id: Uuid) -> Response {
         Ok(p) => p,
     };
 
+    match get_visible(conn, &poll) {
+        Err(err) => err.into_response(),
+        Ok(Some(result)) => reply::json(&result).into_response(),
+        Ok(None) => reply::with_status("Not yet enough votes to tally", StatusCode::NO_CONTENT).into_response(),
+    }
+}
+
+/// Every ballot of the poll in the BLT format, for recounting with other STV software. Only the
+/// poll's owner may export it while it is open; once it closes its ballots are published anyway.
+pub fn export_blt(poll_id: Uuid, user_id: Uuid) -> Response {
+    let conn = &mut establish_connection();
+
+    let poll = match get_poll(conn, &poll_id) {
+        Err(err) => { return err.into_response(); },
+        Ok(p) => p,
+    };
+    if poll.closed_at.is_none() && poll.owner_id.0 != user_id {
+        return error::forbidden("poll").into_response();
+    }
+
+    let ballots = match get_ballots(conn, &poll_id) {
+        Err(err) => { return err.into_response(); },
+        Ok(b) => b,
+    };
+
+    let blt = voting::to_blt(&poll, &ballots);
+    let reply = reply::with_header(blt, "content-type", "text/plain; charset=utf-8");
+    reply::with_header(reply, "content-disposition", format!("attachment; filename=\"{poll_id}.blt\"")).into_response()
+}
+
+/// Everything needed to recount a closed poll: its ballots in the order they are counted and the
+/// revealed seed, which is the JSON `stv` reads. Only ballots' preferences are published, not voters.
+#[derive(Serialize)]
+struct Verification {
+    poll_id: Uuid,
+    title: String,
+    /// Option descriptions, numbered from 0 in order
+    options: Vec<String>,
+    winner_count: u8,
+    ballots: Vec<Vec<voting::WeakId>>,
+    seed: String,
+    seed_commitment: String,
+    /// Whether the stored result has the same winners, eliminations and tally, or none if the
+    /// result isn't stored yet
+    result_matches: Option<bool>,
+    result: voting::PollResult,
+}
+
+/// Count a closed poll again from its published ballots and revealed seed, and compare it with the
+/// result that was stored when it closed
+pub fn verify(poll_id: Uuid) -> Response {
+    let conn = &mut establish_connection();
+
+    let poll = match get_poll(conn, &poll_id) {
+        Err(err) => { return err.into_response(); },
+        Ok(p) => p,
+    };
+    let Some(seed) = poll.revealed_seed.clone() else {
+        return error::poll_not_closed().into_response();
+    };
+
+    let ballots = match get_ballots(conn, &poll_id) {
+        Err(err) => { return err.into_response(); },
+        Ok(b) => b,
+    };
+    let stored = match get_finalized(conn, &poll_id) {
+        Err(err) => { return err.into_response(); },
+        Ok(r) => r,
+    };
+
+    let result = evaluate(&poll, &ballots);
+    let result_matches = stored.map(|stored| {
+        match serde_json::from_value::<voting::PollResult>(stored.result) {
+            Err(_) => false,
+            Ok(stored) => stored.winners == result.winners
+                && stored.eliminated == result.eliminated
+                && stored.tally == result.tally,
+        }
+    });
+
+    let options = poll.options.as_deref().unwrap_or_default();
+    let verification = Verification {
+        poll_id,
+        options: poll.option_ids.iter()
+            .map(|id| options.iter().find(|o| o.id == *id).map_or_else(String::new, |o| o.description.clone()))
+            .collect(),
+        title: poll.title,
+        winner_count: poll.winner_count,
+        ballots: ballots.into_iter().map(|b| b.ranked_preferences).collect(),
+        seed,
+        seed_commitment: poll.seed_commitment,
+        result_matches,
+        result,
+    };
+    reply::json(&verification).into_response()
+}
+
+/// Every ballot of a closed poll as counted, with its receipt but not its voter, and the Merkle root
+/// over them published when it closed, for voters to check that their ballot was counted as cast
+#[derive(Serialize)]
+struct Receipts {
+    merkle_root: Option<String>,
+    ballots: Vec<voting::CountedBallot>,
+}
+
+pub fn receipts(poll_id: Uuid) -> Response {
+    let conn = &mut establish_connection();
+
+    let poll = match get_poll(conn, &poll_id) {
+        Err(err) => { return err.into_response(); },
+        Ok(p) => p,
+    };
+    if poll.closed_at.is_none() {
+        return error::poll_not_closed().into_response();
+    }
+
+    let stored = match get_finalized(conn, &poll_id) {
+        Err(err) => { return err.into_response(); },
+        Ok(r) => r,
+    };
+    let ballots = match get_counted(conn, &poll_id) {
+        Err(err) => { return err.into_response(); },
+        Ok(b) => b,
+    };
+
+    let merkle_root = stored.and_then(|r| r.merkle_root);
+    reply::json(&Receipts { merkle_root, ballots }).into_response()
+}
+
+/// The result with every option described and the count of each round, for reading rather than
+/// for clients
+pub fn export_json(poll_id: Uuid) -> Response {
+    match get_report(&poll_id) {
+        Err(err) => err.into_response(),
+        Ok(Some(report)) => reply::json(&report).into_response(),
+        Ok(None) => reply::with_status("Not yet enough votes to tally", StatusCode::NO_CONTENT).into_response(),
+    }
+}
+
+/// The count of each round as CSV, to paste into spreadsheets and reports
+pub fn export_csv(poll_id: Uuid) -> Response {
+    match get_report(&poll_id) {
+        Err(err) => err.into_response(),
+        Ok(Some(report)) => {
+            let reply = reply::with_header(report.to_csv(), "content-type", "text/csv; charset=utf-8");
+            reply::with_header(reply, "content-disposition", format!("attachment; filename=\"{poll_id}-result.csv\"")).into_response()
+        },
+        Ok(None) => reply::with_status("Not yet enough votes to tally", StatusCode::NO_CONTENT).into_response(),
+    }
+}
+
+fn get_report(poll_id: &Uuid) -> Result<Option<voting::ResultReport>, error::HttpError> {
+    let conn = &mut establish_connection();
+    let poll = get_poll(conn, poll_id)?;
+    let Some(result) = get_visible(conn, &poll)? else {
+        return Ok(None);
+    };
+    let result: voting::PollResult = serde_json::from_value(result)
+        .expect("Poll results are always deserializable");
+    let ballots = get_ballots(conn, poll_id)?;
+
+    Ok(Some(voting::ResultReport::new(&poll, &result, ballots.len())))
+}
+
+/// The result of the poll as it may be shown to voters, or none if there aren't enough votes to tally yet
+pub fn get_visible(conn: &mut PgConnection, poll: &voting::Poll) -> Result<Option<serde_json::Value>, error::HttpError> {
+    // closed polls have their results stored once, don't recount them
+    if let Some(result) = get_finalized(conn, &poll.id.0)? {
+        return Ok(Some(result.result));
+    }
+
+    let ballots = get_ballots(conn, &poll.id.0)?;
+
+    let vote_count: usize = ballots.iter().map(|b| b.ranked_preferences.len()).sum();
+    if vote_count < 3 {
+        return Ok(None);
+    }
+
+    let result = serde_json::to_value(evaluate(poll, &ballots))
+        .expect("Poll results are always serializable");
+    Ok(Some(result))
+}
+
+/// Tally and store the results of every closed poll that doesn't have a stored result yet
+pub fn finalize_closed(conn: &mut PgConnection) -> Result<Vec<Uuid>, error::HttpError> {
+    let result = schema::polls::table
+        .left_join(schema::pollresults::table)
+        .filter(schema::polls::closed_at.is_not_null().and(schema::pollresults::poll_id.is_null()))
+        .select(schema::polls::id)
+        .load(conn);
+    let poll_ids: Vec<Uuid> = match result {
+        Err(err) => {
+            return Err(error::internal(err));
+        },
+        Ok(ids) => ids,
+    };
+
+    let mut finalized = vec![];
+    for poll_id in poll_ids {
+        if finalize(conn, &poll_id)? {
+            finalized.push(poll_id);
+        }
+    }
+
+    Ok(finalized)
+}
+
+/// Tally and store the result of a closed poll, returning whether this call stored it
+pub fn finalize(conn: &mut PgConnection, poll_id: &Uuid) -> Result<bool, error::HttpError> {
+    let poll = get_poll(conn, poll_id)?;
+    let counted = get_counted(conn, poll_id)?;
+    let merkle_root = voting::merkle_root(&counted);
+    let ballots: Vec<voting::Ballot> = counted.into_iter()
+        .map(|b| voting::Ballot { ranked_preferences: b.ranked_preferences, ..Default::default() })
+        .collect();
+    let result = serde_json::to_value(evaluate(&poll, &ballots))
+        .expect("Poll results are always serializable");
+
+    // another server instance may have finalized this poll in the meantime, the first result wins
+    let inserted = diesel::insert_into(schema::pollresults::table)
+        .values(models::CreatePollResult::new(*poll_id, result, merkle_root))
+        .on_conflict_do_nothing()
+        .execute(conn);
+    match inserted {
+        Err(err) => Err(error::db_insert(err, "poll result")),
+        Ok(0) => Ok(false),
+        Ok(_) => {
+            events::notify(conn, poll_id, events::Change::PollClosed);
+            webhooks::enqueue(conn, poll_id, webhooks::Event::ResultFinalized);
+            Ok(true)
+        },
+    }
+}
+
+fn get_finalized(conn: &mut PgConnection, poll_id: &Uuid) -> Result<Option<models::PollResult>, error::HttpError> {
+    let result: Result<Option<models::PollResult>, DbError> = schema::pollresults::table
+        .find(poll_id)
+        .select(models::PollResult::as_select())
+        .first(conn)
+        .optional();
+
+    match result {
+        Err(err) => Err(error::internal(err)),
+        Ok(r) => Ok(r),
+    }
+}
+
+/// Every ballot of the poll as counted, ordered by receipt so that neither counting nor publishing
+/// them says anything about when they were cast
+pub fn get_counted(conn: &mut PgConnection, poll_id: &Uuid) -> Result<Vec<voting::CountedBallot>, error::HttpError> {
     let result = schema::ballots::table
         .inner_join(schema::votes::table)
         .filter(schema::ballots::poll_id.eq(poll_id))
-        .order((schema::ballots::id, schema::votes::preference))
-        .select(models::Vote::as_select())
-        .load(conn);
-    let votes: Vec<models::Vote> = match result {
+        .order((schema::ballots::receipt, schema::ballots::id, schema::votes::preference))
+        .select((schema::ballots::id, schema::ballots::receipt, schema::votes::option))
+        .load::<(i32, Option<String>, i32)>(conn);
+    let votes = match result {
         Err(err) => {
-            return reply::with_status(
-                format!("Failed to fetch ballots for poll {poll_id}: {err}"),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ).into_response();
+            return Err(error::internal(err));
         },
         Ok(v) => v,
     };
 
-    if votes.len() < 3 {
-        return reply::with_status("Not yet enough votes to tally", StatusCode::NO_CONTENT).into_response();
-    }
-
     let mut ballots = vec![];
-    let mut ballot = (
-        votes[0].ballot_id,
-        voting::Ballot { ranked_preferences: vec![], ..Default::default() },
-    );
-    for vote in votes {
-        if vote.ballot_id != ballot.0 {
-            ballots.push(ballot.1);
-            ballot = (
-                vote.ballot_id,
-                voting::Ballot { ranked_preferences: vec![], ..Default::default() },
-            );
+    let mut votes = votes.into_iter().peekable();
+    while let Some((ballot_id, receipt, option)) = votes.next() {
+        let mut ranked_preferences = vec![voting::WeakId(option as u32)];
+        while let Some((_, _, next)) = votes.next_if(|v| v.0 == ballot_id) {
+            ranked_preferences.push(voting::WeakId(next as u32));
         }
-
-        ballot.1.ranked_preferences.push(voting::WeakId(vote.option as u32));
+        ballots.push(voting::CountedBallot { receipt, ranked_preferences });
     }
-    ballots.push(ballot.1);
 
-    let result = voting::PollResult::evaluate(
-        &poll,
-        ballots.as_ref(),
-        poll.option_ids.len() as u32 - poll.winner_count as u32,
-        &poll.rng_seed);
+    Ok(ballots)
+}
+
+fn get_ballots(conn: &mut PgConnection, poll_id: &Uuid) -> Result<Vec<voting::Ballot>, error::HttpError> {
+    let counted = get_counted(conn, poll_id)?;
+    Ok(counted.into_iter()
+        .map(|b| voting::Ballot { ranked_preferences: b.ranked_preferences, ..Default::default() })
+        .collect())
+}
 
-    reply::json(&result).into_response()
+fn evaluate(poll: &voting::Poll, ballots: &[voting::Ballot]) -> voting::PollResult {
+    voting::PollResult::evaluate(
+        poll,
+        ballots,
+        (poll.option_ids.len() as u32).saturating_sub(poll.winner_count as u32),
+        &poll.rng_seed)
 }
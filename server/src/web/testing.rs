@@ -0,0 +1,65 @@
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::voting;
+use super::db::{establish_connection, models, schema};
+use super::poll_api;
+
+/// A poll stored for a test. Its owner and the voters added through it are deleted when it is
+/// dropped, and the owner's polls with them, even if the test fails.
+pub struct TestPoll {
+    pub poll: voting::Poll,
+    pub owner_id: Uuid,
+    voters: Vec<Uuid>,
+}
+
+impl TestPoll {
+    /// Create the poll like any other, with an owner of its own named after it
+    pub fn create(settings: voting::CreatePollSettings) -> Self {
+        Self::create_owned(Uuid::new_v4(), settings)
+    }
+
+    /// Create the poll for an owner, who can own other test polls too
+    pub fn create_owned(owner_id: Uuid, settings: voting::CreatePollSettings) -> Self {
+        Self::store(owner_id, settings, None)
+    }
+
+    /// Create the poll as if it was shared in the Teams conversation
+    pub fn create_in(settings: voting::CreatePollSettings, conversation_reference: serde_json::Value) -> Self {
+        Self::store(Uuid::new_v4(), settings, Some(conversation_reference))
+    }
+
+    fn store(owner_id: Uuid, settings: voting::CreatePollSettings, conversation_reference: Option<serde_json::Value>) -> Self {
+        let connection = &mut establish_connection();
+        let owner = models::User { id: owner_id, display_name: settings.title.clone() };
+        let poll = poll_api::create(connection, owner, settings, conversation_reference)
+            .expect("Test polls are valid");
+        Self { poll, owner_id, voters: vec![] }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.poll.id.0
+    }
+
+    /// Store a new user to vote in the poll
+    pub fn add_voter(&mut self, connection: &mut PgConnection) -> Uuid {
+        let voter_id = Uuid::new_v4();
+        diesel::insert_into(schema::users::table)
+            .values(models::User { id: voter_id, display_name: String::from("Voter") })
+            .execute(connection)
+            .expect("Voters can be stored");
+        self.voters.push(voter_id);
+        voter_id
+    }
+}
+
+impl Drop for TestPoll {
+    fn drop(&mut self) {
+        // everything in the poll, and the poll itself, goes with its owner
+        let users = schema::users::table
+            .filter(schema::users::id.eq(self.owner_id).or(schema::users::id.eq_any(&self.voters)));
+        if let Err(err) = diesel::delete(users).execute(&mut establish_connection()) {
+            println!("Failed to delete test poll {}: {err}", self.id());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::env;
+
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reply::{self, Reply, Response};
+
+use crate::error;
+use super::db::{establish_connection, models, schema};
+use super::webhooks::{self, Destinations, Event};
+
+/// How many of a webhook's deliveries are listed, newest first
+const DELIVERY_LIST_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct UnvalidatedCreateWebhook {
+    pub url: String,
+    /// Every event if left out
+    #[serde(default)]
+    pub events: Vec<Event>,
+}
+
+pub struct CreateWebhook {
+    pub url: reqwest::Url,
+    pub events: Vec<Event>,
+}
+
+impl TryFrom<UnvalidatedCreateWebhook> for CreateWebhook {
+    type Error = error::ValidationErrors;
+
+    fn try_from(UnvalidatedCreateWebhook { url, events }: UnvalidatedCreateWebhook) -> Result<Self, Self::Error> {
+        let mut errors = error::ValidationErrors::new();
+        let url = match Destinations::from_env().check_url(&url) {
+            Err(err) => {
+                errors.push(err.at("url"));
+                None
+            },
+            Ok(url) => Some(url),
+        };
+
+        let mut unique = vec![];
+        for event in events {
+            if !unique.contains(&event) {
+                unique.push(event);
+            }
+        }
+        if unique.is_empty() {
+            unique = Event::ALL.to_vec();
+        }
+
+        match url {
+            Some(url) => errors.into_result(Self { url, events: unique }),
+            None => Err(errors),
+        }
+    }
+}
+
+/// A new webhook, with the secret its deliveries are signed with. The secret isn't shown again.
+#[derive(Serialize)]
+struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: models::Webhook,
+    secret: String,
+}
+
+/// Subscribe to the events of a poll, which only its owner may do
+pub fn new_for_poll(poll_id: Uuid, user_id: Uuid, settings: CreateWebhook) -> Response {
+    let connection = &mut establish_connection();
+
+    let result = schema::polls::table
+        .find(poll_id)
+        .select(schema::polls::owner_id)
+        .first::<Uuid>(connection);
+    match result {
+        Err(err) => return error::db_get(err, "poll").into_response(),
+        Ok(owner_id) if owner_id != user_id => return error::forbidden("poll").into_response(),
+        Ok(_) => {},
+    }
+
+    create(connection, user_id, Some(poll_id), None, settings)
+}
+
+/// Subscribe to the events of every poll created in a Teams tenant. This takes the admin key set in
+/// `WEBHOOK_ADMIN_KEY`, and is disabled if there is none or it's empty.
+pub fn new_for_tenant(tenant_id: String, user_id: Uuid, admin_key: Option<String>, settings: CreateWebhook) -> Response {
+    match (env::var("WEBHOOK_ADMIN_KEY").ok().filter(|k| !k.is_empty()), admin_key) {
+        (None, _) => return error::unauthorized("tenant webhooks are disabled").into_response(),
+        (_, None) => return error::unauthorized("no admin key").into_response(),
+        // compared in constant time so the time taken doesn't reveal how much of the key was right
+        (Some(expected), Some(key)) if !bool::from(expected.as_bytes().ct_eq(key.as_bytes())) => {
+            return error::unauthorized("wrong admin key").into_response();
+        },
+        _ => {},
+    }
+
+    let connection = &mut establish_connection();
+    let owner = models::User { id: user_id, display_name: String::from("Anonymous") };
+    let result = diesel::insert_into(schema::users::table)
+        .values(&owner)
+        .on_conflict_do_nothing()
+        .execute(connection);
+    if let Err(err) = result {
+        return error::db_insert(err, "user").into_response();
+    }
+
+    create(connection, user_id, None, Some(tenant_id), settings)
+}
+
+fn create(
+    connection: &mut PgConnection,
+    owner_id: Uuid,
+    poll_id: Option<Uuid>,
+    tenant_id: Option<String>,
+    CreateWebhook { url, events }: CreateWebhook,
+) -> Response {
+    if let Err(err) = Destinations::from_env().check_host(&url) {
+        return error::HttpError::from(err.at("url")).into_response();
+    }
+
+    let secret = webhooks::new_secret();
+    let result = diesel::insert_into(schema::webhooks::table)
+        .values(models::CreateWebhook {
+            owner_id,
+            poll_id,
+            tenant_id,
+            url: url.to_string(),
+            secret: secret.clone(),
+            events: events.iter().map(|e| Some(e.name().to_string())).collect(),
+        })
+        .returning(models::Webhook::as_returning())
+        .get_result(connection);
+
+    match result {
+        Err(err) => error::db_insert(err, "webhook").into_response(),
+        Ok(webhook) => {
+            println!("New webhook: {}", webhook.id);
+            reply::with_status(reply::json(&CreatedWebhook { webhook, secret }), StatusCode::CREATED).into_response()
+        },
+    }
+}
+
+/// The webhooks subscribed to a poll by its owner
+pub fn list_for_poll(poll_id: Uuid, user_id: Uuid) -> Response {
+    let connection = &mut establish_connection();
+    let result: Result<Vec<models::Webhook>, DbError> = schema::webhooks::table
+        .filter(schema::webhooks::poll_id.eq(poll_id).and(schema::webhooks::owner_id.eq(user_id)))
+        .order(schema::webhooks::created_at)
+        .select(models::Webhook::as_select())
+        .load(connection);
+
+    match result {
+        Err(err) => error::internal(err).into_response(),
+        Ok(webhooks) => reply::json(&webhooks).into_response(),
+    }
+}
+
+/// The latest deliveries to a webhook, for its owner to see what was sent and why it may have failed
+pub fn deliveries(webhook_id: Uuid, user_id: Uuid) -> Response {
+    let connection = &mut establish_connection();
+    if let Err(err) = get_owned(connection, &webhook_id, &user_id) {
+        return err.into_response();
+    }
+
+    let result: Result<Vec<models::WebhookDelivery>, DbError> = schema::webhookdeliveries::table
+        .filter(schema::webhookdeliveries::webhook_id.eq(webhook_id))
+        .order((schema::webhookdeliveries::created_at.desc(), schema::webhookdeliveries::id.desc()))
+        .limit(DELIVERY_LIST_LIMIT)
+        .select(models::WebhookDelivery::as_select())
+        .load(connection);
+
+    match result {
+        Err(err) => error::internal(err).into_response(),
+        Ok(deliveries) => reply::json(&deliveries).into_response(),
+    }
+}
+
+/// Unsubscribe, dropping any deliveries that are still pending
+pub fn delete(webhook_id: Uuid, user_id: Uuid) -> Response {
+    let connection = &mut establish_connection();
+    if let Err(err) = get_owned(connection, &webhook_id, &user_id) {
+        return err.into_response();
+    }
+
+    match diesel::delete(schema::webhooks::table.find(webhook_id)).execute(connection) {
+        Err(err) => error::internal(err).into_response(),
+        Ok(_) => reply::with_status(reply::reply(), StatusCode::NO_CONTENT).into_response(),
+    }
+}
+
+fn get_owned(connection: &mut PgConnection, webhook_id: &Uuid, user_id: &Uuid) -> Result<models::Webhook, error::HttpError> {
+    let result = schema::webhooks::table
+        .find(webhook_id)
+        .select(models::Webhook::as_select())
+        .first(connection);
+
+    match result {
+        Err(err) => Err(error::db_get(err, "webhook")),
+        Ok(webhook) if webhook.owner_id != *user_id => Err(error::forbidden("webhook")),
+        Ok(webhook) => Ok(webhook),
+    }
+}
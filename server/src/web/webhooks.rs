@@ -0,0 +1,595 @@
+use std::env;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use diesel::prelude::*;
+use diesel::result::Error as DbError;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::watch;
+use tokio::{task, time};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use uuid::Uuid;
+
+use crate::error;
+use super::bot::ConversationReference;
+use super::db::{establish_connection, models, schema};
+use super::poll_api::get_internal as get_poll;
+
+pub const DELIVERY_PERIOD: Duration = Duration::from_secs(10);
+
+/// How long a delivery may take before the endpoint is considered down
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many deliveries are claimed at a time
+const DELIVERY_BATCH: i64 = 50;
+
+/// Deliveries are given up on after this many failed attempts, about 14 hours after the event
+const MAX_ATTEMPTS: i32 = 12;
+
+const FIRST_RETRY_DELAY: TimeDelta = TimeDelta::seconds(30);
+const MAX_RETRY_DELAY: TimeDelta = TimeDelta::hours(6);
+
+pub const MAX_URL_LENGTH: usize = 2000;
+
+pub const EVENT_HEADER: &str = "x-webhook-event";
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// Something that happened to a poll, which webhooks can subscribe to
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    #[serde(rename = "poll.created")]
+    PollCreated,
+    #[serde(rename = "ballot.cast")]
+    BallotCast,
+    #[serde(rename = "poll.closed")]
+    PollClosed,
+    #[serde(rename = "result.finalized")]
+    ResultFinalized,
+}
+
+impl Event {
+    pub const ALL: [Event; 4] = [Event::PollCreated, Event::BallotCast, Event::PollClosed, Event::ResultFinalized];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PollCreated => "poll.created",
+            Event::BallotCast => "ballot.cast",
+            Event::PollClosed => "poll.closed",
+            Event::ResultFinalized => "result.finalized",
+        }
+    }
+}
+
+/// Which endpoints webhooks may be registered for and delivered to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Destinations {
+    /// Only https endpoints at public addresses, so that webhooks can't be used to reach the
+    /// server's own host or network
+    Public,
+    /// Any http or https endpoint, for local development
+    Any,
+}
+
+impl Destinations {
+    /// Any endpoint is only allowed when `WEBHOOK_ALLOW_PRIVATE_DESTINATIONS` is `true`
+    pub fn from_env() -> Self {
+        match env::var("WEBHOOK_ALLOW_PRIVATE_DESTINATIONS").as_deref() {
+            Ok("true") => Destinations::Any,
+            _ => Destinations::Public,
+        }
+    }
+
+    /// Parse a webhook URL, checking its scheme and, if its host is an address, the address. Host
+    /// names are checked when they are resolved.
+    pub fn check_url(&self, url: &str) -> Result<reqwest::Url, error::ValidationError> {
+        let parsed = reqwest::Url::parse(url).ok()
+            .filter(|u| u.has_host() && url.len() <= MAX_URL_LENGTH)
+            .filter(|u| match self {
+                Destinations::Public => u.scheme() == "https",
+                Destinations::Any => u.scheme() == "https" || u.scheme() == "http",
+            })
+            .ok_or_else(error::webhook_url_invalid)?;
+
+        match literal_address(&parsed) {
+            Some(ip) if *self == Destinations::Public && !is_public(ip) => Err(error::webhook_url_forbidden()),
+            _ => Ok(parsed),
+        }
+    }
+
+    /// Resolve the host name of a checked URL, failing unless every address it has is allowed. This
+    /// blocks, and the host is resolved again when delivering since its addresses can change.
+    pub fn check_host(&self, url: &reqwest::Url) -> Result<(), error::ValidationError> {
+        if *self == Destinations::Any || literal_address(url).is_some() {
+            return Ok(());
+        }
+        let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+            return Err(error::webhook_url_invalid());
+        };
+
+        let addresses: Vec<SocketAddr> = match (host, port).to_socket_addrs() {
+            Err(_) => return Err(error::webhook_url_invalid()),
+            Ok(addresses) => addresses.collect(),
+        };
+        if addresses.is_empty() || !addresses.iter().all(|a| is_public(a.ip())) {
+            return Err(error::webhook_url_forbidden());
+        }
+        Ok(())
+    }
+
+    /// A client for deliveries, which only connects to allowed addresses and doesn't follow
+    /// redirects, since those could lead anywhere
+    pub fn client(&self) -> reqwest::Client {
+        let builder = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none());
+        let builder = match self {
+            Destinations::Public => builder.dns_resolver(Arc::new(PublicResolver)),
+            Destinations::Any => builder,
+        };
+        builder.build().expect("Webhook client settings are valid")
+    }
+}
+
+/// Resolves host names like the system does, but fails for names with any address that isn't public
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if addresses.is_empty() || !addresses.iter().all(|a| is_public(a.ip())) {
+                return Err(format!("{host} resolves to an address that isn't public").into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The address a URL is for, if its host is an address rather than a name
+fn literal_address(url: &reqwest::Url) -> Option<IpAddr> {
+    url.host_str()?.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Whether an address is on the public internet, rather than the server's own host or network
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+                // "this network" and the shared address space of carrier-grade NAT
+                || a == 0 || (a == 100 && (64..128).contains(&b)))
+        },
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            // NAT64 addresses reach IPv4 hosts
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [_, _, _, _, _, _, high, low] = segments;
+                return is_public(IpAddr::V4(((high as u32) << 16 | low as u32).into()));
+            }
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || ip.is_unique_local() || ip.is_unicast_link_local())
+        },
+    }
+}
+
+/// The body of every webhook request
+#[derive(Serialize)]
+struct Payload {
+    event: Event,
+    poll_id: Uuid,
+    occurred_at: NaiveDateTime,
+    data: serde_json::Value,
+}
+
+/// A new random secret to sign a webhook's deliveries with
+pub fn new_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    hex::encode(secret)
+}
+
+/// The signature header of a delivery: an HMAC-SHA256 of the timestamp and body keyed with the
+/// webhook's secret, so receivers can check who sent it and reject replays of old deliveries
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("t={timestamp},v1={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Queue the event for every webhook subscribed to it, through the poll or the tenant the poll was
+/// created in. Like change notifications, failures are logged rather than failing the request.
+pub fn enqueue(connection: &mut PgConnection, poll_id: &Uuid, event: Event) {
+    if let Err(err) = try_enqueue(connection, poll_id, event) {
+        println!("Failed to queue {} webhooks of poll {poll_id}: {err}", event.name());
+    }
+}
+
+fn try_enqueue(connection: &mut PgConnection, poll_id: &Uuid, event: Event) -> Result<(), error::HttpError> {
+    let result = schema::polls::table
+        .find(poll_id)
+        .select(schema::polls::conversation_reference)
+        .first::<Option<serde_json::Value>>(connection);
+    let tenant_id = match result {
+        Err(err) => return Err(error::db_get(err, "poll")),
+        Ok(reference) => reference
+            .and_then(|r| serde_json::from_value::<ConversationReference>(r).ok())
+            .and_then(|r| r.conversation.tenant_id),
+    };
+
+    let mut query = schema::webhooks::table
+        .filter(schema::webhooks::poll_id.eq(poll_id))
+        .select(models::Webhook::as_select())
+        .into_boxed();
+    if let Some(tenant_id) = tenant_id {
+        query = query.or_filter(schema::webhooks::tenant_id.eq(tenant_id));
+    }
+    let result: Result<Vec<models::Webhook>, DbError> = query.load(connection);
+    let webhooks: Vec<models::Webhook> = match result {
+        Err(err) => return Err(error::internal(err)),
+        Ok(w) => w.into_iter()
+            .filter(|w| w.events.iter().any(|e| e.as_deref() == Some(event.name())))
+            .collect(),
+    };
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let payload = Payload {
+        event,
+        poll_id: *poll_id,
+        occurred_at: Utc::now().naive_utc(),
+        data: data(connection, poll_id, event)?,
+    };
+    let payload = serde_json::to_value(payload).expect("Webhook payloads are always serializable");
+
+    let deliveries: Vec<_> = webhooks.into_iter()
+        .map(|webhook| models::CreateWebhookDelivery {
+            webhook_id: webhook.id,
+            event: event.name().to_string(),
+            payload: payload.clone(),
+        })
+        .collect();
+    let result = diesel::insert_into(schema::webhookdeliveries::table)
+        .values(deliveries)
+        .execute(connection);
+    match result {
+        Err(err) => Err(error::db_insert(err, "webhook delivery")),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// What the event is about. Ballots are secret, so only their number is sent.
+fn data(connection: &mut PgConnection, poll_id: &Uuid, event: Event) -> Result<serde_json::Value, error::HttpError> {
+    match event {
+        Event::PollCreated | Event::PollClosed => {
+            let poll = get_poll(connection, poll_id)?;
+            Ok(serde_json::json!({ "poll": poll }))
+        },
+        Event::BallotCast => {
+            let result = schema::ballots::table
+                .filter(schema::ballots::poll_id.eq(poll_id))
+                .count()
+                .get_result::<i64>(connection);
+            match result {
+                Err(err) => Err(error::internal(err)),
+                Ok(count) => Ok(serde_json::json!({ "ballot_count": count })),
+            }
+        },
+        Event::ResultFinalized => {
+            let result = schema::pollresults::table
+                .find(poll_id)
+                .select(schema::pollresults::result)
+                .first::<serde_json::Value>(connection);
+            match result {
+                Err(err) => Err(error::db_get(err, "poll result")),
+                Ok(result) => Ok(serde_json::json!({ "result": result })),
+            }
+        },
+    }
+}
+
+/// Deliver queued events to their webhooks, until shutdown
+pub async fn deliver(mut shutdown: watch::Receiver<bool>) {
+    let destinations = Destinations::from_env();
+    let client = destinations.client();
+    let mut interval = time::interval(DELIVERY_PERIOD);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => return,
+            _ = interval.tick() => deliver_pending(&client, destinations).await,
+        }
+    }
+}
+
+/// Whether a delivery got through, and if not what went wrong. Owners can read outcomes back, so
+/// errors are only described in general terms that don't reveal anything about the network.
+struct Outcome {
+    status: Option<u16>,
+    error: Option<String>,
+}
+
+async fn deliver_pending(client: &reqwest::Client, destinations: Destinations) {
+    let now = Utc::now().naive_utc();
+    let pending = match task::spawn_blocking(move || claim(&mut establish_connection(), now)).await {
+        Err(err) => {
+            println!("Failed to load webhook deliveries: {err}");
+            return;
+        },
+        Ok(Err(err)) => {
+            println!("Failed to load webhook deliveries: {err}");
+            return;
+        },
+        Ok(Ok(pending)) => pending,
+    };
+
+    for (delivery, webhook) in pending {
+        let outcome = send(client, destinations, &delivery, &webhook).await;
+
+        let result = task::spawn_blocking(move || record(&mut establish_connection(), &delivery, outcome)).await;
+        match result {
+            Err(err) => println!("Failed to record webhook delivery: {err}"),
+            Ok(Err(err)) => println!("Failed to record webhook delivery: {err}"),
+            Ok(Ok(())) => {},
+        }
+    }
+}
+
+async fn send(
+    client: &reqwest::Client, destinations: Destinations, delivery: &models::WebhookDelivery, webhook: &models::Webhook
+) -> Outcome {
+    let attempt = delivery.attempts + 1;
+    // the URL was checked when the webhook was created, but checking again costs nothing
+    if destinations.check_url(&webhook.url).is_err() {
+        println!("Not delivering {} to webhook {} (attempt {attempt}): URL isn't allowed", delivery.event, webhook.id);
+        return Outcome { status: None, error: Some(String::from("destination not allowed")) };
+    }
+
+    let body = serde_json::to_vec(&delivery.payload).expect("Stored payloads are always serializable");
+    let signature = sign(&webhook.secret, Utc::now().timestamp(), &body);
+
+    let result = client.post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, delivery.id)
+        .header(SIGNATURE_HEADER, signature)
+        .body(body)
+        .send().await;
+
+    match result {
+        Err(err) => {
+            println!("Failed to deliver {} to webhook {} (attempt {attempt}): {err}", delivery.event, webhook.id);
+            let class = if err.is_timeout() {
+                "timed out"
+            }
+            else if err.is_connect() {
+                "could not connect"
+            }
+            else {
+                "request failed"
+            };
+            Outcome { status: None, error: Some(String::from(class)) }
+        },
+        Ok(response) if response.status().is_success() => Outcome { status: Some(response.status().as_u16()), error: None },
+        Ok(response) => {
+            println!("Failed to deliver {} to webhook {} (attempt {attempt}): endpoint responded {}",
+                delivery.event, webhook.id, response.status());
+            Outcome { status: Some(response.status().as_u16()), error: Some(String::from("endpoint responded with an error")) }
+        },
+    }
+}
+
+/// Take the deliveries that are due, holding them for long enough to be sent so that other server
+/// instances don't send them too
+fn claim(
+    connection: &mut PgConnection, now: NaiveDateTime
+) -> Result<Vec<(models::WebhookDelivery, models::Webhook)>, DbError> {
+    connection.transaction(|connection| {
+        let ids: Vec<i64> = schema::webhookdeliveries::table
+            .filter(schema::webhookdeliveries::next_attempt_at.le(now))
+            .order(schema::webhookdeliveries::next_attempt_at)
+            .limit(DELIVERY_BATCH)
+            .select(schema::webhookdeliveries::id)
+            .for_update()
+            .skip_locked()
+            .load(connection)?;
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let lease = TimeDelta::from_std(DELIVERY_TIMEOUT * 3).expect("Lease fits in a TimeDelta");
+        diesel::update(schema::webhookdeliveries::table.filter(schema::webhookdeliveries::id.eq_any(&ids)))
+            .set(schema::webhookdeliveries::next_attempt_at.eq(now + lease))
+            .execute(connection)?;
+
+        schema::webhookdeliveries::table
+            .inner_join(schema::webhooks::table)
+            .filter(schema::webhookdeliveries::id.eq_any(&ids))
+            .order(schema::webhookdeliveries::id)
+            .select((models::WebhookDelivery::as_select(), models::Webhook::as_select()))
+            .load(connection)
+    })
+}
+
+fn record(connection: &mut PgConnection, delivery: &models::WebhookDelivery, outcome: Outcome) -> Result<(), DbError> {
+    let now = Utc::now().naive_utc();
+    let attempts = delivery.attempts + 1;
+    let (delivered_at, next_attempt_at) = match outcome.error {
+        None => (Some(now), None),
+        Some(_) if attempts >= MAX_ATTEMPTS => (None, None),
+        Some(_) => (None, Some(now + retry_delay(attempts))),
+    };
+
+    diesel::update(schema::webhookdeliveries::table.find(delivery.id))
+        .set((
+            schema::webhookdeliveries::attempts.eq(attempts),
+            schema::webhookdeliveries::last_status.eq(outcome.status.map(|s| s as i32)),
+            schema::webhookdeliveries::last_error.eq(outcome.error),
+            schema::webhookdeliveries::delivered_at.eq(delivered_at),
+            schema::webhookdeliveries::next_attempt_at.eq(next_attempt_at),
+        ))
+        .execute(connection)?;
+    Ok(())
+}
+
+/// How long to wait after the given number of failed attempts, doubling each time
+fn retry_delay(attempts: i32) -> TimeDelta {
+    let factor = 1 << (attempts - 1).clamp(0, 16);
+    (FIRST_RETRY_DELAY * factor).min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use warp::http::{HeaderMap, StatusCode};
+    use warp::hyper::body::Bytes;
+    use warp::Filter;
+
+    use crate::voting;
+    use super::*;
+    use super::super::ballot_api;
+    use super::super::testing::TestPoll;
+
+    #[test]
+    fn signatures_cover_timestamp_and_body() {
+        let signature = sign("secret", 1700000000, b"{}");
+        assert!(signature.starts_with("t=1700000000,v1="));
+        assert_eq!(signature, sign("secret", 1700000000, b"{}"));
+        assert_ne!(signature, sign("other secret", 1700000000, b"{}"));
+        assert_ne!(signature, sign("secret", 1700000001, b"{}"));
+        assert_ne!(signature, sign("secret", 1700000000, b"{ }"));
+    }
+
+    #[test]
+    fn only_public_https_destinations() {
+        let public = Destinations::Public;
+        assert!(public.check_url("https://example.com/hook").is_ok());
+        assert!(public.check_url("https://93.184.215.14/hook").is_ok());
+        assert!(public.check_url("http://example.com/hook").is_err(), "Deliveries are encrypted");
+        for url in [
+            "https://127.0.0.1:5432/", "https://10.1.2.3/", "https://172.16.0.1/", "https://192.168.1.1/",
+            "https://169.254.169.254/latest/meta-data", "https://0.0.0.0/", "https://100.64.0.1/",
+            "https://[::1]/", "https://[fd00::1]/", "https://[fe80::1]/", "https://[::ffff:127.0.0.1]/",
+            "https://[64:ff9b::a9fe:a9fe]/",
+        ] {
+            assert_eq!(public.check_url(url).unwrap_err().kind(), &error::ErrorKind::WebhookUrlForbidden, "{url}");
+        }
+        let localhost = public.check_url("https://localhost/hook").unwrap();
+        assert!(public.check_host(&localhost).is_err(), "Names are resolved");
+
+        assert!(Destinations::Any.check_url("http://127.0.0.1:3000/hook").is_ok());
+    }
+
+    #[test]
+    fn retries_back_off() {
+        assert_eq!(retry_delay(1), TimeDelta::seconds(30));
+        assert_eq!(retry_delay(2), TimeDelta::seconds(60));
+        assert_eq!(retry_delay(4), TimeDelta::seconds(240));
+        assert_eq!(retry_delay(MAX_ATTEMPTS), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn events_are_signed_and_retried() {
+        // stand in for a subscriber that is down for the first delivery
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let failures = Arc::new(AtomicU32::new(1));
+        let mock_endpoint = warp::path("hook")
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |headers: HeaderMap, body: Bytes| {
+                if failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |f| f.checked_sub(1)).is_ok() {
+                    return warp::reply::with_status("down", StatusCode::SERVICE_UNAVAILABLE);
+                }
+                sender.send((headers, body)).unwrap();
+                warp::reply::with_status("ok", StatusCode::OK)
+            });
+        let (address, server) = warp::serve(mock_endpoint).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let (test_poll, webhook_id, secret) = task::spawn_blocking(move || {
+            let connection = &mut establish_connection();
+            let settings = voting::CreatePollSettings::try_from(voting::UnvalidatedCreatePollSettings {
+                title: String::from("Webhooks"),
+                options: vec![String::from("Yes"), String::from("No")],
+                ..Default::default()
+            }).unwrap();
+            let mut test_poll = TestPoll::create(settings);
+
+            let secret = new_secret();
+            let webhook: models::Webhook = diesel::insert_into(schema::webhooks::table)
+                .values(models::CreateWebhook {
+                    owner_id: test_poll.owner_id,
+                    poll_id: Some(test_poll.id()),
+                    tenant_id: None,
+                    url: format!("http://{address}/hook"),
+                    secret: secret.clone(),
+                    events: vec![Some(Event::BallotCast.name().to_string())],
+                })
+                .returning(models::Webhook::as_returning())
+                .get_result(connection).unwrap();
+
+            let voter_id = test_poll.add_voter(connection);
+            let ballot = voting::UnvalidatedCreateBallot { ranked_preferences: vec![voting::WeakId(1)] }
+                .validate(test_poll.poll.clone()).unwrap();
+            ballot_api::insert(connection, &test_poll.id(), &voter_id, &ballot).unwrap();
+
+            (test_poll, webhook.id, secret)
+        }).await.unwrap();
+
+        let client = Destinations::Any.client();
+        deliver_pending(&client, Destinations::Any).await;
+        assert!(received.try_recv().is_err(), "The first attempt fails");
+
+        // make the retry due now rather than in 30 seconds
+        task::spawn_blocking(move || {
+            diesel::update(schema::webhookdeliveries::table.filter(schema::webhookdeliveries::webhook_id.eq(webhook_id)))
+                .set(schema::webhookdeliveries::next_attempt_at.eq(Utc::now().naive_utc()))
+                .execute(&mut establish_connection()).unwrap();
+        }).await.unwrap();
+        deliver_pending(&client, Destinations::Any).await;
+
+        let (headers, body) = received.recv().await.unwrap();
+        assert_eq!(headers[EVENT_HEADER], "ballot.cast");
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+        let timestamp = signature.trim_start_matches("t=").split(',').next().unwrap().parse().unwrap();
+        assert_eq!(signature, sign(&secret, timestamp, &body));
+
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["event"], "ballot.cast");
+        assert_eq!(payload["poll_id"], json!(test_poll.id()));
+        assert_eq!(payload["data"], json!({ "ballot_count": 1 }));
+
+        let delivery = task::spawn_blocking(move || {
+            let connection = &mut establish_connection();
+            let delivery = schema::webhookdeliveries::table
+                .filter(schema::webhookdeliveries::webhook_id.eq(webhook_id))
+                .select(models::WebhookDelivery::as_select())
+                .first(connection).unwrap();
+
+            drop(test_poll);
+            delivery
+        }).await.unwrap();
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.last_status, Some(200));
+        assert!(delivery.delivered_at.is_some() && delivery.next_attempt_at.is_none());
+    }
+}